@@ -0,0 +1,89 @@
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion};
+use serde_json::json;
+use ycm_core::server::{Options, ServerState};
+use ycm_core::ycmd_types::{EventNotification, SimpleRequest};
+
+const FILEPATH: &str = "/bench/buffer.rs";
+
+fn options(max_num_candidates: usize) -> Options {
+    serde_json::from_value(json!({
+        "hmac_secret": "c2VjcmV0",
+        "max_num_candidates": max_num_candidates,
+        "min_num_of_chars_for_completion": 1,
+        "max_num_candidates_to_detail": 10,
+        "max_diagnostics_to_display": 10,
+        "filepath_blacklist": {},
+        "filepath_completion_use_working_dir": 0,
+        "rust_toolchain_root": "",
+    }))
+    .unwrap()
+}
+
+/// Buffer with `n` distinct identifiers sharing a prefix, typing `fo` on its last line
+fn buffer(n: usize) -> String {
+    let mut contents = (0..n)
+        .map(|i| format!("let foo_bar_{} = baz_{}.qux();\n", i, i))
+        .collect::<String>();
+    contents.push_str("fo");
+    contents
+}
+
+fn file_data(contents: &str) -> serde_json::Value {
+    json!({ FILEPATH: { "filetypes": ["rust"], "contents": contents } })
+}
+
+fn event(contents: &str, line_num: usize) -> EventNotification {
+    serde_json::from_value(json!({
+        "line_num": line_num,
+        "column_num": 1,
+        "filepath": FILEPATH,
+        "file_data": file_data(contents),
+        "event_name": "FileReadyToParse",
+    }))
+    .unwrap()
+}
+
+fn request(contents: &str, line_num: usize) -> SimpleRequest {
+    serde_json::from_value(json!({
+        "line_num": line_num,
+        "column_num": 3,
+        "filepath": FILEPATH,
+        "file_data": file_data(contents),
+    }))
+    .unwrap()
+}
+
+pub fn completions_benchmark(c: &mut Criterion) {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    for n in [100, 1000, 10000] {
+        let contents = buffer(n);
+        let line_num = n + 1;
+        for max_num_candidates in [10, 100] {
+            let state = ServerState::new(options(max_num_candidates));
+            state.event_notification(event(&contents, line_num));
+            c.bench_function(
+                &format!("Parse buffer {} max {}", n, max_num_candidates),
+                |b| {
+                    b.iter_batched(
+                        || event(&contents, line_num),
+                        |event| black_box(state.event_notification(event)),
+                        BatchSize::SmallInput,
+                    )
+                },
+            );
+            c.bench_function(
+                &format!("Completions {} max {}", n, max_num_candidates),
+                |b| {
+                    b.iter_batched(
+                        || request(&contents, line_num),
+                        |request| black_box(runtime.block_on(state.completions(request))),
+                        BatchSize::SmallInput,
+                    )
+                },
+            );
+        }
+    }
+}
+
+criterion_group!(benches, completions_benchmark);
+criterion_main!(benches);
@@ -1,6 +1,8 @@
 use criterion::{black_box, criterion_group, criterion_main, Criterion};
 use ycm_core::core::candidate::*;
+use ycm_core::core::character::Segmentation;
 use ycm_core::core::query::*;
+use ycm_core::core::utils::identifier::start_of_longest_identifier_ending_at_index;
 
 fn generate_candidates_with_common_prefix(prefix: &str, n: usize) -> Vec<String> {
     let mut candidates = Vec::with_capacity(n);
@@ -33,12 +35,126 @@ pub fn criterion_benchmark(c: &mut Criterion) {
                     .map(|s| Candidate::new(&s))
                     .collect::<Vec<_>>();
                 let q = Word::new(q);
-                let results = filter_and_sort_candidates(&candidates, &q, n);
+                let results = filter_and_sort_candidates(
+                    &candidates,
+                    &q,
+                    n,
+                    &ScoringWeights::default(),
+                    None,
+                    EmptyOrder::Lexicographic,
+                );
                 black_box(results);
             })
         });
+        // Same, with candidates parsed once up front as a store of them would
+        let stored = candidates
+            .iter()
+            .map(|s| Candidate::new(s))
+            .collect::<Vec<_>>();
+        c.bench_function(&format!("Stored {}", n), |b| {
+            b.iter(|| {
+                let q = Word::new(q);
+                let results = filter_and_sort_candidates(
+                    &stored,
+                    &q,
+                    n,
+                    &ScoringWeights::default(),
+                    None,
+                    EmptyOrder::Lexicographic,
+                );
+                black_box(results);
+            })
+        });
+    }
+}
+
+pub fn query_parse_benchmark(c: &mut Criterion) {
+    let q = "a_A_a_aAaAaAaA";
+    let requests = 16;
+    c.bench_function(&format!("Parsed query x{}", requests), |b| {
+        b.iter(|| {
+            for _ in 0..requests {
+                black_box(Word::new(black_box(q)));
+            }
+        })
+    });
+    c.bench_function(&format!("Cached query x{}", requests), |b| {
+        b.iter(|| {
+            let cache = WordCache::default();
+            for _ in 0..requests {
+                black_box(cache.get(black_box(q), Segmentation::Grapheme));
+            }
+        })
+    });
+}
+
+pub fn prefix_benchmark(c: &mut Criterion) {
+    let q = Word::new("aA");
+    let n = 4096;
+    for (name, prefix) in [("All prefix", "aA"), ("All subsequence", "a_A_a_")] {
+        let candidates = generate_candidates_with_common_prefix(prefix, n);
+        let candidates = candidates
+            .iter()
+            .map(|s| Candidate::new(s))
+            .collect::<Vec<_>>();
+        c.bench_function(&format!("{} {}", name, n), |b| {
+            b.iter(|| {
+                let results = filter_and_sort_candidates(
+                    &candidates,
+                    &q,
+                    50,
+                    &ScoringWeights::default(),
+                    None,
+                    EmptyOrder::Lexicographic,
+                );
+                black_box(results);
+            })
+        });
+    }
+}
+
+pub fn long_candidates_benchmark(c: &mut Criterion) {
+    // Matches end early in the candidates, the rest of them need not be scanned
+    let q = Word::new("ab");
+    let n = 4096;
+    let candidates = generate_candidates_with_common_prefix(&"a_b_".repeat(64), n);
+    let candidates = candidates
+        .iter()
+        .map(|s| Candidate::new(s))
+        .collect::<Vec<_>>();
+    c.bench_function(&format!("Long candidates {}", n), |b| {
+        b.iter(|| {
+            for candidate in &candidates {
+                black_box(candidate.matches_query(&q));
+            }
+        })
+    });
+}
+
+pub fn identifier_start_benchmark(c: &mut Criterion) {
+    let n = 100_000;
+    for (name, line) in [
+        ("Minified", "a.".repeat(n / 2)),
+        ("Single identifier", "a".repeat(n)),
+    ] {
+        c.bench_function(&format!("Identifier start {} {}", name, n), |b| {
+            b.iter(|| {
+                black_box(start_of_longest_identifier_ending_at_index(
+                    black_box(&line),
+                    line.len(),
+                    None,
+                ))
+            })
+        });
     }
 }
 
-criterion_group!(benches, criterion_benchmark);
+criterion_group!(
+    benches,
+    criterion_benchmark,
+    query_parse_benchmark,
+    prefix_benchmark,
+    long_candidates_benchmark,
+    identifier_start_benchmark
+);
 criterion_main!(benches);
@@ -1,17 +1,21 @@
 use log::debug;
 use regex::Regex;
 use std::{
-    collections::HashSet,
-    path::{Path, PathBuf},
+    collections::{HashMap, HashSet},
+    path::{Component, Path, PathBuf},
 };
 
 use crate::{
-    core::query::filter_and_sort_generic_candidates,
-    core::utils,
+    core::{
+        query::{filter_and_sort_generic_candidates, Word},
+        utils,
+    },
     ycmd_types::{Candidate, SimpleRequest},
 };
 
-use super::{Completer, CompleterInner, CompletionConfig};
+use super::{
+    filter_and_sort_completions, Completer, CompleterError, CompleterInner, CompletionConfig,
+};
 
 use itertools::Itertools;
 
@@ -31,10 +35,29 @@ static ref PATH_SEPARATORS_REGEX: Regex = Regex::new(format!("([{0}][^{0}]*|[{0}
     .unwrap();
 }
 
+/// Filetypes where a lone path separator is usually something else,
+/// e.g. a comment in the C family or a closing tag in HTML
+const LONE_SEPARATOR_BLACKLIST: &[&str] = &["c", "cpp", "cuda", "objc", "objcpp", "html", "xml"];
+
+/// Default value for `filepath_lone_separator_blacklist`: filetype to
+/// the separators that should not complete the root directory on their own
+pub fn default_lone_separator_blacklist() -> HashMap<String, String> {
+    LONE_SEPARATOR_BLACKLIST
+        .iter()
+        .map(|f| (f.to_string(), PATH_SEPARATORS.to_string()))
+        .collect()
+}
+
 pub struct FilenameCompleter {
     config: CompletionConfig,
+    /// Filetypes to not complete in, `*` for all of them.
+    /// `!filetype` keeps completion enabled for a filetype regardless
     blacklist: HashSet<String>,
     use_working_dir: bool,
+    lone_separator_blacklist: HashMap<String, String>,
+    /// Directories of a path that don't exist are replaced by the closest
+    /// existing ones, see `resolve_fuzzy`
+    fuzzy_components: bool,
 }
 
 #[derive(PartialEq)]
@@ -67,13 +90,43 @@ impl FilenameCompleter {
         config: CompletionConfig,
         blacklist: HashSet<String>,
         use_working_dir: bool,
+        lone_separator_blacklist: HashMap<String, String>,
     ) -> Self {
         Self {
             config,
             blacklist,
             use_working_dir,
+            lone_separator_blacklist,
+            fuzzy_components: false,
+        }
+    }
+
+    /// Same completer, matching directories of paths fuzzily if `fuzzy_components`
+    pub fn with_fuzzy_components(self, fuzzy_components: bool) -> Self {
+        Self {
+            fuzzy_components,
+            ..self
+        }
+    }
+}
+
+/// Edits turning `a` into `b`, counting a swap of adjacent characters as one
+fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    let mut rows = vec![(0..=b.len()).collect::<Vec<_>>()];
+    for i in 1..=a.len() {
+        let mut row = vec![i; b.len() + 1];
+        for j in 1..=b.len() {
+            let substitution = rows[i - 1][j - 1] + (a[i - 1] != b[j - 1]) as usize;
+            row[j] = substitution.min(rows[i - 1][j] + 1).min(row[j - 1] + 1);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                row[j] = row[j].min(rows[i - 2][j - 2] + 1);
+            }
         }
+        rows.push(row);
     }
+    rows[a.len()][b.len()]
 }
 
 impl FilenameCompleter {
@@ -87,7 +140,18 @@ impl FilenameCompleter {
     }
 
     fn current_filetype_completion_disabled(&self, filetypes: &[String]) -> bool {
-        self.blacklist.contains("*") || filetypes.iter().any(|f| self.blacklist.contains(f))
+        let forced = filetypes
+            .iter()
+            .any(|f| self.blacklist.contains(&format!("!{}", f)));
+        !forced
+            && (self.blacklist.contains("*")
+                || filetypes.iter().any(|f| self.blacklist.contains(f)))
+    }
+
+    fn lone_separator_disabled(&self, filetypes: &[String], separator: char) -> bool {
+        filetypes.iter().any(
+            |f| matches!(self.lone_separator_blacklist.get(f), Some(s) if s.contains(separator)),
+        )
     }
 
     fn get_dir_head_regex(&self, directory: &str) -> Regex {
@@ -106,6 +170,73 @@ impl FilenameCompleter {
         .unwrap()
     }
 
+    /// Directory in `dir` the user most likely meant by `name`: the best match of
+    /// `name` as a query, or else the closest one by `edit_distance` with at most
+    /// one edit per three characters
+    fn closest_dir_entry(&self, dir: &Path, name: &str) -> Option<String> {
+        let mut entries = utils::list_dir(dir.to_str()?)
+            .filter(|e| dir.join(e).is_dir())
+            .collect::<Vec<_>>();
+        entries.sort();
+        let query = Word::with_segmentation(name, self.config.segmentation);
+        filter_and_sort_generic_candidates(
+            entries.clone(),
+            &query,
+            1,
+            &self.config.scoring_weights,
+            |e| e,
+        )
+        .into_iter()
+        .next()
+        .or_else(|| {
+            let max_edits = (name.chars().count() / 3).max(1);
+            entries
+                .into_iter()
+                .map(|e| (edit_distance(name, &e), e))
+                .filter(|(edits, _)| *edits <= max_edits)
+                .min_by_key(|(edits, _)| *edits)
+                .map(|(_, e)| e)
+        })
+    }
+
+    /// `path` with each directory after the first one that doesn't exist
+    /// replaced by the closest existing one, see `closest_dir_entry`
+    fn resolve_fuzzy(&self, path: &Path) -> Option<PathBuf> {
+        let mut components = path.components();
+        let mut resolved = PathBuf::new();
+        // Anything up to the first directory name has to exist as typed
+        for component in components.by_ref() {
+            resolved.push(component);
+            if let Component::Normal(_) = component {
+                break;
+            }
+        }
+        if !resolved.is_dir() {
+            return None;
+        }
+        for component in components {
+            match component {
+                Component::Normal(name) if !resolved.join(name).is_dir() => {
+                    let name = self.closest_dir_entry(&resolved, name.to_str()?)?;
+                    resolved.push(name);
+                }
+                component => resolved.push(component),
+            }
+        }
+        Some(resolved)
+    }
+
+    /// `path` if it exists, or what it resolves to when matching fuzzily
+    fn existing_path(&self, path: &Path) -> Option<PathBuf> {
+        if path.exists() {
+            Some(path.to_owned())
+        } else if self.fuzzy_components {
+            self.resolve_fuzzy(path)
+        } else {
+            None
+        }
+    }
+
     ///Return the tuple (|path|, |start_column|) where |path| is a path that
     ///could be completed on the current line before the cursor and |start_column|
     ///is the column where the completion should start. (None, None) is returned if
@@ -142,7 +273,7 @@ impl FilenameCompleter {
                     path.to_owned()
                 };
 
-                if path.exists() {
+                if let Some(path) = self.existing_path(&path) {
                     return Some((path, last_match_start + 1));
                 }
             } else {
@@ -159,8 +290,8 @@ impl FilenameCompleter {
                 {
                     let path = utils::expand_vars(path);
                     let path = std::path::Path::new(&*path);
-                    if path.exists() {
-                        return Some((path.to_owned(), last_match_start + 1));
+                    if let Some(path) = self.existing_path(path) {
+                        return Some((path, last_match_start + 1));
                     }
                 }
             }
@@ -168,12 +299,12 @@ impl FilenameCompleter {
             // path could be exactly "/" (or "\" on Windows). Only return the path if
             // there are no other path separators on the line. This prevents always
             // completing the root directory if nothing is matched.
-            // TODO: completion on a single "/" or "\" is not really desirable in
+            // Completion on a single "/" or "\" is not really desirable in
             // languages where such characters are part of special constructs like
-            // comments in C/C++ or closing tags in HTML. This behavior could be improved
-            // by using rules that depend on the filetype.
+            // comments in C/C++ or closing tags in HTML, see `lone_separator_blacklist`.
         }
-        if matches_n == 1 {
+        let separator = current_line[last_match_start..].chars().next().unwrap();
+        if matches_n == 0 && !self.lone_separator_disabled(request.filetypes(), separator) {
             return Some((
                 std::path::PathBuf::from(&String::from(std::path::MAIN_SEPARATOR)),
                 last_match_start + 1,
@@ -182,9 +313,12 @@ impl FilenameCompleter {
         None
     }
 
-    fn generate_path_candidates(&self, dir: PathBuf) -> Vec<Candidate> {
-        match std::fs::read_dir(dir) {
-            Err(_) => vec![],
+    /// Entries of `dir`, none if it doesn't exist as paths being typed often don't.
+    /// Read anew for every request, so there is no listing to drop on BufferUnload
+    fn generate_path_candidates(&self, dir: PathBuf) -> std::io::Result<Vec<Candidate>> {
+        Ok(match std::fs::read_dir(dir) {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => vec![],
+            Err(e) => return Err(e),
             Ok(d) => d
                 .map(|f| f.ok())
                 .flatten()
@@ -210,10 +344,11 @@ impl FilenameCompleter {
                         detailed_info: None,
                         kind: None,
                         extra_data: None,
+                        filter_text: None,
                     }
                 })
                 .collect(),
-        }
+        })
     }
 }
 
@@ -228,6 +363,10 @@ impl CompleterInner for FilenameCompleter {
 }
 
 impl Completer for FilenameCompleter {
+    fn name(&self) -> &str {
+        "filename"
+    }
+
     fn should_use_now(&self, request: &SimpleRequest) -> bool {
         !self.current_filetype_completion_disabled(request.filetypes()) && {
             let s = self.search_path(request);
@@ -236,21 +375,30 @@ impl Completer for FilenameCompleter {
         }
     }
 
-    fn compute_candidates(&self, request: &mut SimpleRequest) -> Vec<Candidate> {
+    fn completion_start_column(&self, request: &SimpleRequest) -> usize {
+        match self.search_path(request) {
+            Some((_, start)) => start,
+            None => request.start_column(),
+        }
+    }
+
+    fn compute_candidates(
+        &self,
+        request: &mut SimpleRequest,
+    ) -> Result<Vec<Candidate>, CompleterError> {
         if !self.should_use_now(request) {
-            vec![]
+            Ok(vec![])
         } else if let Some((dir, start)) = self.search_path(request) {
             request.start_column = Some(start);
-            let candidates = self.generate_path_candidates(dir);
+            let candidates = self.generate_path_candidates(dir)?;
             debug!("Path completion candidates: {:?}", candidates);
-            filter_and_sort_generic_candidates(
+            Ok(filter_and_sort_completions(
                 candidates,
-                request.query(),
-                self.get_settings().max_candidates,
-                |c| &c.insertion_text,
-            )
+                request,
+                self.get_settings(),
+            ))
         } else {
-            vec![]
+            Ok(vec![])
         }
     }
 }
@@ -260,6 +408,7 @@ mod tests {
     use std::fs::File;
     use std::io::Write;
 
+    use crate::completer::tests::{test_config, test_request};
     use crate::ycmd_types::FileData;
 
     use super::*;
@@ -268,15 +417,10 @@ mod tests {
     fn test_search_path_abs() {
         let completer = FilenameCompleter {
             blacklist: HashSet::default(),
-            config: CompletionConfig {
-                min_num_chars: 1,
-                max_diagnostics_to_display: 1,
-                completion_triggers: Default::default(),
-                signature_triggers: Default::default(),
-                max_candidates: 10,
-                max_candidates_to_detail: 1,
-            },
+            config: test_config(),
             use_working_dir: false,
+            lone_separator_blacklist: default_lone_separator_blacklist(),
+            fuzzy_components: false,
         };
         let tmp = tempdir().unwrap();
         let file_path = tmp.path().join("candidate.txt");
@@ -284,26 +428,9 @@ mod tests {
         writeln!(file, "_ was here. Briefly.").unwrap();
         core::mem::drop(file);
 
-        let mut file_data = std::collections::HashMap::default();
         let file_contents = format!("1234{}/ ", tmp.path().display());
         let column_num = file_contents.len() + 1; // on the last space in that line
-        file_data.insert(
-            PathBuf::from("/file"),
-            FileData {
-                filetypes: vec![],
-                contents: file_contents,
-            },
-        );
-        let request = SimpleRequest {
-            line_num: 1,
-            column_num,
-            filepath: PathBuf::from("/file"),
-            file_data,
-            completer_target: None,
-            working_dir: None,
-            extra_conf_data: None,
-            start_column: None,
-        };
+        let request = request_in(PathBuf::from("/file"), file_contents);
         assert_eq!(
             Some((tmp.into_path(), column_num - 2)),
             completer.search_path(&request)
@@ -314,15 +441,10 @@ mod tests {
     fn test_search_path_relative() {
         let completer = FilenameCompleter {
             blacklist: HashSet::default(),
-            config: CompletionConfig {
-                min_num_chars: 1,
-                max_diagnostics_to_display: 1,
-                completion_triggers: Default::default(),
-                signature_triggers: Default::default(),
-                max_candidates: 10,
-                max_candidates_to_detail: 1,
-            },
+            config: test_config(),
             use_working_dir: false,
+            lone_separator_blacklist: default_lone_separator_blacklist(),
+            fuzzy_components: false,
         };
         let tmp = tempdir().unwrap();
         let file_path = tmp.path().join("candidate.txt");
@@ -330,29 +452,12 @@ mod tests {
         writeln!(file, "_ was here. Briefly.").unwrap();
         core::mem::drop(file);
 
-        let mut file_data = std::collections::HashMap::default();
         let file_contents = format!(
             "123 ../{}/ ",
             tmp.path().file_name().unwrap().to_string_lossy()
         );
         let column_num = file_contents.len() + 1; // on the last space in that line
-        file_data.insert(
-            file_path.clone(),
-            FileData {
-                filetypes: vec![],
-                contents: file_contents,
-            },
-        );
-        let request = SimpleRequest {
-            line_num: 1,
-            column_num,
-            filepath: file_path,
-            file_data,
-            completer_target: None,
-            working_dir: None,
-            extra_conf_data: None,
-            start_column: None,
-        };
+        let request = request_in(file_path, file_contents);
         assert_eq!(
             Some((
                 PathBuf::from(format!(
@@ -365,4 +470,144 @@ mod tests {
             completer.search_path(&request)
         );
     }
+
+    /// Request at the end of `contents`, the only line of `filepath` without filetypes
+    fn request_in(filepath: PathBuf, contents: String) -> SimpleRequest {
+        let column_num = contents.len() + 1;
+        let mut file_data = HashMap::default();
+        file_data.insert(
+            filepath.clone(),
+            FileData {
+                filetypes: vec![],
+                contents,
+            },
+        );
+        SimpleRequest {
+            column_num,
+            filepath,
+            file_data,
+            ..test_request("", "")
+        }
+    }
+
+    #[test]
+    fn directory_changes_between_requests() {
+        let completer = FilenameCompleter::new(
+            test_config(),
+            HashSet::default(),
+            false,
+            default_lone_separator_blacklist(),
+        );
+        let tmp = tempdir().unwrap();
+        File::create(tmp.path().join("first.txt")).unwrap();
+        let texts = || {
+            let contents = format!("x = \"{}/", tmp.path().display());
+            let mut request = request_in(PathBuf::from("/file"), contents);
+            let mut texts = completer
+                .compute_candidates(&mut request)
+                .unwrap()
+                .into_iter()
+                .map(|c| c.insertion_text)
+                .collect::<Vec<_>>();
+            texts.sort_unstable();
+            texts
+        };
+
+        assert_eq!(texts(), vec!["first.txt"]);
+        File::create(tmp.path().join("second.txt")).unwrap();
+        std::fs::remove_file(tmp.path().join("first.txt")).unwrap();
+        assert_eq!(texts(), vec!["second.txt"]);
+    }
+
+    fn lone_separator_request(filetype: &str) -> SimpleRequest {
+        test_request("foo /", filetype)
+    }
+
+    #[test]
+    fn test_search_path_lone_separator() {
+        let completer = FilenameCompleter::new(
+            test_config(),
+            HashSet::default(),
+            false,
+            default_lone_separator_blacklist(),
+        );
+        assert_eq!(
+            Some((PathBuf::from(String::from(std::path::MAIN_SEPARATOR)), 5)),
+            completer.search_path(&lone_separator_request("text"))
+        );
+        assert_eq!(None, completer.search_path(&lone_separator_request("cpp")));
+        assert_eq!(None, completer.search_path(&lone_separator_request("html")));
+        assert!(completer
+            .search_path(&lone_separator_request("rust"))
+            .is_some());
+
+        // With another separator earlier on the line, the last one is not alone
+        let mut request = lone_separator_request("text");
+        request
+            .file_data
+            .get_mut(Path::new("/foo.rs"))
+            .unwrap()
+            .contents = String::from("a/b /");
+        assert_eq!(None, completer.search_path(&request));
+    }
+
+    #[test]
+    fn test_blacklist_negation() {
+        let completer = FilenameCompleter::new(
+            test_config(),
+            vec![String::from("*"), String::from("!rust")]
+                .into_iter()
+                .collect(),
+            false,
+            default_lone_separator_blacklist(),
+        );
+        let filetypes = |f: &[&str]| f.iter().map(|f| f.to_string()).collect::<Vec<_>>();
+        assert!(!completer.current_filetype_completion_disabled(&filetypes(&["rust"])));
+        assert!(!completer.current_filetype_completion_disabled(&filetypes(&["c", "rust"])));
+        assert!(completer.current_filetype_completion_disabled(&filetypes(&["python"])));
+        assert!(completer.current_filetype_completion_disabled(&filetypes(&[])));
+    }
+
+    #[test]
+    fn test_search_path_fuzzy_components() {
+        let completer = FilenameCompleter::new(
+            test_config(),
+            HashSet::default(),
+            false,
+            default_lone_separator_blacklist(),
+        );
+        let tmp = tempdir().unwrap();
+        std::fs::create_dir_all(tmp.path().join("src").join("cmp")).unwrap();
+        std::fs::create_dir_all(tmp.path().join("src").join("lib")).unwrap();
+        let request = |path: &str| {
+            let contents = format!("x = \"{}/{}/", tmp.path().display(), path);
+            let mut request = lone_separator_request("text");
+            request.column_num = contents.len() + 1;
+            request
+                .file_data
+                .get_mut(Path::new("/foo.rs"))
+                .unwrap()
+                .contents = contents;
+            request
+        };
+        let cmp = tmp.path().join("src").join("cmp");
+        let start = |path: &str| request(path).column_num - 1;
+
+        assert_eq!(None, completer.search_path(&request("src/cpm")));
+        let completer = completer.with_fuzzy_components(true);
+        // Transposed characters, then a directory matching as a query
+        assert_eq!(
+            Some((cmp.clone(), start("src/cpm"))),
+            completer.search_path(&request("src/cpm"))
+        );
+        assert_eq!(
+            Some((cmp.clone(), start("sr/cmp"))),
+            completer.search_path(&request("sr/cmp"))
+        );
+        assert_eq!(
+            Some((cmp, start("src/cmp"))),
+            completer.search_path(&request("src/cmp"))
+        );
+        assert_eq!(None, completer.search_path(&request("src/xyz")));
+    }
 }
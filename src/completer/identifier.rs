@@ -0,0 +1,143 @@
+use log::error;
+
+use crate::{
+    core::{
+        identifier_database::IdentifierDatabase,
+        utils::identifier::start_of_longest_identifier_ending_at_index,
+    },
+    ycmd_types::{Candidate, Event, EventNotification, SimpleRequest},
+};
+
+use super::{Completer, CompleterError, CompleterInner, CompletionConfig};
+
+/// Completes identifiers seen in buffers of the same filetype
+pub struct IdentifierCompleter {
+    config: CompletionConfig,
+    database: IdentifierDatabase,
+}
+
+impl IdentifierCompleter {
+    /// Completer starting with the identifiers cached in `config.identifier_cache`
+    pub fn new(config: CompletionConfig) -> Self {
+        let database = match &config.identifier_cache {
+            Some(path) => IdentifierDatabase::load_cache(path),
+            None => IdentifierDatabase::default(),
+        };
+        Self { config, database }
+    }
+
+    /// Event's file contents with the identifier being typed at the cursor blanked out,
+    /// so half-typed words don't end up in the database
+    fn contents_without_cursor_identifier(event: &EventNotification, contents: &str) -> String {
+        let mut contents = contents.to_string();
+        let line_start = contents
+            .split_inclusive('\n')
+            .take(event.line_num.saturating_sub(1))
+            .map(str::len)
+            .sum::<usize>();
+        let line = contents[line_start..]
+            .split('\n')
+            .next()
+            .unwrap_or_default();
+        let end = event.column_num.saturating_sub(1);
+        if end <= line.len() && line.is_char_boundary(end) {
+            let start = start_of_longest_identifier_ending_at_index(
+                line,
+                end,
+                event
+                    .file_data
+                    .get(&event.filepath)
+                    .and_then(|f| f.filetypes.first())
+                    .map(String::as_str),
+            );
+            contents.replace_range(
+                line_start + start..line_start + end,
+                &" ".repeat(end - start),
+            );
+        }
+        contents
+    }
+}
+
+impl CompleterInner for IdentifierCompleter {
+    fn get_settings(&self) -> &CompletionConfig {
+        &self.config
+    }
+
+    fn get_settings_mut(&mut self) -> &mut CompletionConfig {
+        &mut self.config
+    }
+}
+
+impl Completer for IdentifierCompleter {
+    fn name(&self) -> &str {
+        "identifier"
+    }
+
+    fn on_event(&mut self, event: &EventNotification) {
+        match event.event_name {
+            Event::FileReadyToParse => {
+                for (filepath, file) in &event.file_data {
+                    let contents = if *filepath == event.filepath {
+                        Self::contents_without_cursor_identifier(event, &file.contents)
+                    } else if self.config.collect_identifiers_from_all_files {
+                        file.contents.clone()
+                    } else {
+                        continue;
+                    };
+                    let filetype = file.filetypes.first().map(String::as_str);
+                    // Only the lines that changed since the last parse are looked at again
+                    self.database.set_contents(
+                        filetype.unwrap_or_default(),
+                        filepath,
+                        contents,
+                        self.config.min_identifier_length,
+                    );
+                }
+            }
+            Event::BufferUnload => self.database.clear_file(&event.filepath),
+            _ => {}
+        }
+    }
+
+    fn shutdown(&mut self) {
+        if let Some(path) = &self.config.identifier_cache {
+            if let Err(e) = self.database.save_cache(path) {
+                error!("Failed to save identifier cache {}: {}", path.display(), e);
+            }
+        }
+    }
+
+    fn should_use_now(&self, request: &SimpleRequest) -> bool {
+        self.query_length_above_min_threshold(
+            request.start_column(),
+            request.completion_column().saturating_sub(1),
+        )
+    }
+
+    fn compute_candidates_inner(
+        &self,
+        request: &SimpleRequest,
+    ) -> Result<Vec<Candidate>, CompleterError> {
+        if !self.should_use_now(request) {
+            return Ok(vec![]);
+        }
+        // Buffers without a filetype store their identifiers under an empty one
+        let identifiers = match request.filetypes() {
+            [] => self.database.identifiers(""),
+            filetypes => self.database.identifiers_for_filetypes(filetypes),
+        };
+        Ok(identifiers
+            .into_iter()
+            .map(|i| Candidate {
+                insertion_text: i.to_string(),
+                extra_menu_info: Some(String::from("[ID]")),
+                menu_text: None,
+                detailed_info: None,
+                kind: None,
+                extra_data: None,
+                filter_text: None,
+            })
+            .collect())
+    }
+}
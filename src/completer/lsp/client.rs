@@ -1,57 +1,237 @@
-use std::{ffi::OsStr, process::Stdio};
+use std::{
+    collections::HashMap,
+    ffi::OsStr,
+    process::Stdio,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
 
+use log::{debug, log, warn, Level};
 use lsp_types;
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
+use tokio::net::TcpStream;
 use tokio::process::Child;
 
+use super::transport::LspTransport;
+use crate::correlation;
+
+/// Delay before retrying to connect to a server's port, doubled on each failure
+const CONNECT_RETRY_DELAY: Duration = Duration::from_millis(10);
+const CONNECT_RETRY_MAX_DELAY: Duration = Duration::from_millis(500);
+/// How long a server gets to start listening on its port
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Connect to `address`, retrying with an exponential backoff as a freshly
+/// spawned server may not be listening yet
+async fn connect_with_backoff(
+    address: &str,
+    timeout: Duration,
+) -> Result<TcpStream, anyhow::Error> {
+    let deadline = Instant::now() + timeout;
+    let mut delay = CONNECT_RETRY_DELAY;
+    loop {
+        match TcpStream::connect(address).await {
+            Ok(stream) => return Ok(stream),
+            Err(e) if Instant::now() + delay < deadline => {
+                debug!(
+                    "Connecting to {} failed, retrying in {:?}: {}",
+                    address, delay, e
+                );
+                tokio::time::sleep(delay).await;
+                delay = (delay * 2).min(CONNECT_RETRY_MAX_DELAY);
+            }
+            Err(e) => {
+                return Err(anyhow::anyhow!(
+                    "Language server didn't accept connections on {} within {:?}: {}",
+                    address,
+                    timeout,
+                    e
+                ))
+            }
+        }
+    }
+}
+
+/// Pass each line of `reader` to `log` until it is closed, as a server's stderr
+/// is once the server exits
+async fn forward_lines<R, F>(reader: R, mut log: F)
+where
+    R: AsyncRead + Unpin,
+    F: FnMut(&str),
+{
+    let mut lines = BufReader::new(reader).split(b'\n');
+    loop {
+        match lines.next_segment().await {
+            Ok(Some(line)) => log(String::from_utf8_lossy(&line).trim_end_matches('\r')),
+            Ok(None) => break,
+            Err(e) => {
+                debug!("Failed to read language server stderr: {}", e);
+                break;
+            }
+        }
+    }
+}
+
+/// Servers log all sorts of things on stderr, only what looks like a failure is a warning
+fn stderr_level(line: &str) -> Level {
+    let line = line.to_lowercase();
+    if line.contains("error") || line.contains("panic") {
+        Level::Warn
+    } else {
+        Level::Debug
+    }
+}
+
 /// Object responsible for spawning an LSP server process
 /// and its lifetime
 pub struct LspClient {
-    transport: super::transport::LspTransport,
-    child: Child,
+    transport: LspTransport,
+    child: Mutex<Option<Child>>,
+    progress: Mutex<Progress>,
+}
+
+/// Requests still waiting for a response, and when the server
+/// last answered (or was first asked something after being idle)
+struct Progress {
+    pending: usize,
+    since: Instant,
+}
+
+impl Default for Progress {
+    fn default() -> Self {
+        Self {
+            pending: 0,
+            since: Instant::now(),
+        }
+    }
+}
+
+/// Marks a request as answered when dropped, so abandoned requests don't count
+struct PendingRequest<'a>(&'a Mutex<Progress>);
+
+impl<'a> PendingRequest<'a> {
+    fn new(progress: &'a Mutex<Progress>) -> Self {
+        let mut p = progress.lock().unwrap();
+        if p.pending == 0 {
+            p.since = Instant::now();
+        }
+        p.pending += 1;
+        Self(progress)
+    }
+}
+
+impl Drop for PendingRequest<'_> {
+    fn drop(&mut self) {
+        let mut p = self.0.lock().unwrap();
+        p.pending -= 1;
+        p.since = Instant::now();
+    }
+}
+
+fn to_params<T: serde::Serialize>(params: T) -> Result<jsonrpc_core::Params, anyhow::Error> {
+    Ok(match serde_json::to_value(params)? {
+        jsonrpc_core::Value::Null => jsonrpc_core::types::Params::None,
+        jsonrpc_core::Value::Array(a) => jsonrpc_core::types::Params::Array(a),
+        jsonrpc_core::Value::Object(m) => jsonrpc_core::types::Params::Map(m),
+        _ => unreachable!(),
+    })
 }
 
 impl LspClient {
-    pub async fn new<P, S, I>(path: P, args: I, port: Option<u32>) -> Result<Self, anyhow::Error>
+    /// Spawn the server with `env` on top of the inherited environment, or instead of
+    /// it with `clear_env`
+    pub async fn new<P, S, I>(
+        path: P,
+        args: I,
+        port: Option<u32>,
+        env: &HashMap<String, String>,
+        clear_env: bool,
+    ) -> Result<Self, anyhow::Error>
     where
         I: IntoIterator<Item = S>,
         S: AsRef<OsStr>,
         P: AsRef<OsStr>,
     {
+        let name = path.as_ref().to_string_lossy().into_owned();
         let mut command = tokio::process::Command::new(path);
-        command.args(args);
+        if clear_env {
+            command.env_clear();
+        }
+        command.args(args).envs(env).stderr(Stdio::piped());
         if port.is_none() {
-            command.stdin(Stdio::piped()).stdin(Stdio::piped());
+            command.stdin(Stdio::piped()).stdout(Stdio::piped());
         }
         let mut child = command.spawn()?;
+        if let Some(stderr) = child.stderr.take() {
+            tokio::spawn(forward_lines(stderr, move |line| {
+                log!(stderr_level(line), "{} stderr: {}", name, line)
+            }));
+        }
 
         let transport = match port {
-            None => super::transport::LspTransport::new(
-                child.stdout.take().unwrap(),
-                child.stdin.take().unwrap(),
-            ),
+            None => LspTransport::new(child.stdout.take().unwrap(), child.stdin.take().unwrap()),
             Some(p) => {
-                let stream = tokio::net::TcpStream::connect(format!("127.0.0.1:{}", p)).await?;
+                let address = format!("127.0.0.1:{}", p);
+                let stream = connect_with_backoff(&address, CONNECT_TIMEOUT).await?;
                 let (r, w) = tokio::io::split(stream);
-                super::transport::LspTransport::new(r, w)
+                LspTransport::new(r, w)
             }
         };
 
-        Ok(Self { child, transport })
+        Ok(Self {
+            child: Mutex::new(Some(child)),
+            transport,
+            progress: Mutex::default(),
+        })
+    }
+
+    /// Client for a server we don't own the process of
+    pub fn from_transport(transport: LspTransport) -> Self {
+        Self {
+            transport,
+            child: Mutex::default(),
+            progress: Mutex::default(),
+        }
+    }
+
+    /// See `LspTransport::take_server_requests`
+    pub fn take_server_requests(
+        &mut self,
+    ) -> Option<tokio::sync::mpsc::Receiver<jsonrpc_core::types::Call>> {
+        self.transport.take_server_requests()
     }
 
     pub async fn request<T: lsp_types::request::Request>(
         &self,
         params: T::Params,
     ) -> Result<T::Result, anyhow::Error> {
-        let params = match serde_json::to_value(params)? {
-            jsonrpc_core::Value::Null => jsonrpc_core::types::Params::None,
-            jsonrpc_core::Value::Array(a) => jsonrpc_core::types::Params::Array(a),
-            jsonrpc_core::Value::Object(m) => jsonrpc_core::types::Params::Map(m),
-            _ => unreachable!(),
-        };
-        match self.transport.call(T::METHOD.to_string(), params).await {
+        self.request_with_id::<T>(params, |_| {}).await
+    }
+
+    /// Like [`LspClient::request`], handing the request id to `on_id`, see
+    /// [`LspTransport::call_with_id`]
+    pub async fn request_with_id<T: lsp_types::request::Request>(
+        &self,
+        params: T::Params,
+        on_id: impl FnOnce(usize),
+    ) -> Result<T::Result, anyhow::Error> {
+        let params = to_params(params)?;
+        let _pending = PendingRequest::new(&self.progress);
+        let output = self
+            .transport
+            .call_with_id(T::METHOD.to_string(), params, on_id)
+            .await;
+        match output {
             jsonrpc_core::Output::Success(r) => Ok(serde_json::from_value(r.result)?),
-            jsonrpc_core::Output::Failure(e) => Err(e.error.into()),
+            jsonrpc_core::Output::Failure(e) => {
+                debug!(
+                    "[{}] {} failed: {}",
+                    correlation::current(),
+                    T::METHOD,
+                    e.error.message
+                );
+                Err(e.error.into())
+            }
         }
     }
 
@@ -59,19 +239,214 @@ impl LspClient {
         &self,
         params: T::Params,
     ) -> Result<(), anyhow::Error> {
-        let params = match serde_json::to_value(params)? {
-            jsonrpc_core::Value::Null => jsonrpc_core::types::Params::None,
-            jsonrpc_core::Value::Array(a) => jsonrpc_core::types::Params::Array(a),
-            jsonrpc_core::Value::Object(m) => jsonrpc_core::types::Params::Map(m),
-            _ => unreachable!(),
-        };
+        let params = to_params(params)?;
         self.transport.notify(T::METHOD.to_string(), params).await;
         Ok(())
     }
 
-    pub async fn shutdown(&mut self) -> Result<(), anyhow::Error> {
-        self.request::<lsp_types::request::Shutdown>(()).await?;
-        self.child.wait().await?;
+    /// Queue a notification without waiting, see [`LspTransport::try_notify`]
+    pub fn notification_nowait<T: lsp_types::notification::Notification>(
+        &self,
+        params: T::Params,
+    ) -> Result<(), anyhow::Error> {
+        let params = to_params(params)?;
+        self.transport.try_notify(T::METHOD.to_string(), params)
+    }
+
+    /// Cancel request `id`, see [`LspTransport::cancel`]
+    pub fn cancel(&self, id: usize) {
+        self.transport.cancel(id);
+    }
+
+    /// No request has been waiting for an answer longer than `grace`
+    pub fn is_responsive(&self, grace: Duration) -> bool {
+        let progress = self.progress.lock().unwrap();
+        progress.pending == 0 || progress.since.elapsed() < grace
+    }
+
+    /// Server process is still running and responsive
+    pub fn is_healthy(&self, grace: Duration) -> bool {
+        let running = match &mut *self.child.lock().unwrap() {
+            Some(child) => matches!(child.try_wait(), Ok(None)),
+            None => true,
+        };
+        running && self.is_responsive(grace)
+    }
+
+    /// Ask the server to shut down and exit, giving it `grace` for each step.
+    /// A server process still running after that is killed
+    pub async fn shutdown(&self, grace: Duration) -> Result<(), anyhow::Error> {
+        match tokio::time::timeout(grace, self.request::<lsp_types::request::Shutdown>(())).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => debug!("Shutdown request failed: {}", e),
+            Err(_) => debug!("Shutdown request unanswered"),
+        }
+        if tokio::time::timeout(
+            grace,
+            self.notification::<lsp_types::notification::Exit>(()),
+        )
+        .await
+        .is_err()
+        {
+            debug!("Exit notification not sent");
+        }
+        let child = self.child.lock().unwrap().take();
+        if let Some(mut child) = child {
+            if tokio::time::timeout(grace, child.wait()).await.is_err() {
+                warn!("Language server didn't exit, killing it");
+                child.kill().await?;
+            }
+        }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn connect_retries_until_listening() {
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let address = format!("127.0.0.1:{}", port);
+        let listen_address = address.clone();
+        let listener = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            let listener = tokio::net::TcpListener::bind(listen_address).await.unwrap();
+            listener.accept().await.unwrap();
+        });
+
+        connect_with_backoff(&address, Duration::from_secs(10))
+            .await
+            .unwrap();
+        listener.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn connect_gives_up_after_timeout() {
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let address = format!("127.0.0.1:{}", port);
+        let error = connect_with_backoff(&address, Duration::from_millis(50))
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains(&address));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn stdio_server_reads_and_writes() {
+        // Echoes every message back, as if the server sent it
+        let mut client = LspClient::new("cat", &[] as &[&str], None, &HashMap::default(), false)
+            .await
+            .unwrap();
+        client
+            .notification::<lsp_types::notification::Exit>(())
+            .await
+            .unwrap();
+
+        let echoed = tokio::time::timeout(
+            Duration::from_secs(10),
+            client.transport.read_requests_from_server(),
+        )
+        .await
+        .unwrap();
+        match echoed {
+            Some(jsonrpc_core::Call::Notification(n)) => assert_eq!(n.method, "exit"),
+            other => panic!("Unexpected message {:?}", other),
+        }
+        client.shutdown(Duration::from_millis(100)).await.unwrap();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn server_environment() {
+        // Sends a notification named after its environment, then waits to be killed
+        let script = r#"msg="{\"jsonrpc\":\"2.0\",\"method\":\"$YCMD_TEST_VAR:$HOME\"}"
+            printf 'Content-Length: %d\r\n\r\n%s' ${#msg} "$msg"
+            sleep 1000"#;
+        let env = vec![(String::from("YCMD_TEST_VAR"), String::from("set"))]
+            .into_iter()
+            .collect();
+        let method = |clear_env| {
+            let env = &env;
+            async move {
+                let mut client = LspClient::new("/bin/sh", &["-c", script], None, env, clear_env)
+                    .await
+                    .unwrap();
+                let message = tokio::time::timeout(
+                    Duration::from_secs(10),
+                    client.transport.read_requests_from_server(),
+                )
+                .await
+                .unwrap();
+                client.shutdown(Duration::from_millis(100)).await.unwrap();
+                match message {
+                    Some(jsonrpc_core::Call::Notification(n)) => n.method,
+                    other => panic!("Unexpected message {:?}", other),
+                }
+            }
+        };
+
+        let home = std::env::var("HOME").unwrap_or_default();
+        assert_eq!(method(false).await, format!("set:{}", home));
+        assert_eq!(method(true).await, "set:");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn stderr_lines_forwarded() {
+        let mut child = tokio::process::Command::new("sh")
+            .args(["-c", "echo starting >&2; printf 'error: oops\\r\\n' >&2"])
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let mut lines = vec![];
+        tokio::time::timeout(
+            Duration::from_secs(10),
+            forward_lines(child.stderr.take().unwrap(), |line| {
+                lines.push(line.to_owned())
+            }),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(lines, vec!["starting", "error: oops"]);
+        assert_eq!(stderr_level(&lines[0]), Level::Debug);
+        assert_eq!(stderr_level(&lines[1]), Level::Warn);
+        child.wait().await.unwrap();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn shutdown_kills_stuck_server() {
+        // Never answers nor exits on its own
+        let client = LspClient::new("sleep", &["1000"], None, &HashMap::default(), false)
+            .await
+            .unwrap();
+        let pid = client.child.lock().unwrap().as_ref().unwrap().id().unwrap();
+
+        tokio::time::timeout(
+            Duration::from_secs(10),
+            client.shutdown(Duration::from_millis(100)),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+
+        let alive = std::process::Command::new("kill")
+            .args(["-0", &pid.to_string()])
+            .stderr(Stdio::null())
+            .status()
+            .unwrap();
+        assert!(!alive.success());
+        assert!(client.child.lock().unwrap().is_none());
+    }
+}
@@ -1,13 +1,200 @@
-use std::ffi::OsStr;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
+use futures::future::BoxFuture;
+use log::{debug, error, warn};
+use lsp_types::{
+    notification::{
+        DidChangeTextDocument, DidCloseTextDocument, DidOpenTextDocument, DidSaveTextDocument,
+        Initialized, Notification, PublishDiagnostics,
+    },
+    request::{
+        Completion, DocumentSymbolRequest, Initialize, PrepareRenameRequest, Rename, Request,
+        ResolveCompletionItem, WorkspaceSymbol,
+    },
+    ClientCapabilities, CompletionClientCapabilities, CompletionItem, CompletionItemCapability,
+    CompletionItemKind, CompletionItemTag, CompletionParams, CompletionResponse,
+    CompletionTextEdit, Diagnostic, DiagnosticSeverity, DidChangeTextDocumentParams,
+    DidCloseTextDocumentParams, DidOpenTextDocumentParams, DidSaveTextDocumentParams,
+    DocumentChangeOperation, DocumentChanges, DocumentSymbolParams, DocumentSymbolResponse,
+    Documentation, InitializeParams, InitializedParams, InsertTextFormat, OneOf, Position,
+    PublishDiagnosticsParams, RenameClientCapabilities, RenameParams, ServerCapabilities,
+    TagSupport, TextDocumentClientCapabilities, TextDocumentContentChangeEvent,
+    TextDocumentIdentifier, TextDocumentItem, TextDocumentPositionParams,
+    TextDocumentSyncCapability, TextDocumentSyncClientCapabilities, TextDocumentSyncKind,
+    TextDocumentSyncSaveOptions, Url, VersionedTextDocumentIdentifier, WorkspaceEdit,
+    WorkspaceSymbolParams,
+};
+
+use crate::core::query::EmptyOrder;
+use crate::ycmd_types::{
+    Candidate, DiagnosticData, DiagnosticKind, Event, EventNotification, Fixit, FixitChunk,
+    GoToLocation, Location, SimpleRequest, Snippet,
+};
+
+use super::ultisnips::{parse_snippet, snippet_plain_text};
 use super::{Completer, CompleterInner, CompletionConfig};
+use registry::LspServerConfig;
 
 pub mod client;
+pub mod registry;
+pub mod root;
 pub mod transport;
 
+/// How long a request may go unanswered before the server is considered stuck
+const RESPONSE_GRACE: Duration = Duration::from_secs(30);
+
+/// How long a server is given to answer each step of the shutdown sequence
+pub const SHUTDOWN_GRACE: Duration = Duration::from_secs(3);
+
 pub struct LspCompleter {
-    client: client::LspClient,
+    /// `lsp:` followed by the filetype the registry started the server for
+    name: String,
+    client: Arc<client::LspClient>,
     config: CompletionConfig,
+    /// Documents the server was told about, by path
+    open_documents: HashMap<String, OpenDocument>,
+    /// As answered to the initialize request
+    capabilities: ServerCapabilities,
+    /// Completion items to resolve, by the resolve id of their candidate
+    resolvable: Arc<Mutex<ResolvableItems>>,
+    /// Last complete answer to a completion request, see `request_completions`
+    completion_cache: Arc<Mutex<Option<CachedCompletions>>>,
+    /// Requests about documents that are cancelled once the document changes
+    in_flight: InFlightRequests,
+    /// Last diagnostics the server published, by file
+    published_diagnostics: PublishedDiagnostics,
+}
+
+type PublishedDiagnostics = Arc<Mutex<HashMap<PathBuf, Vec<Diagnostic>>>>;
+
+/// Handle what the server sends on its own until it disconnects. Only
+/// diagnostics are kept, requests are left unanswered
+async fn handle_server_calls(
+    mut calls: tokio::sync::mpsc::Receiver<jsonrpc_core::types::Call>,
+    published: PublishedDiagnostics,
+) {
+    while let Some(call) = calls.recv().await {
+        match call {
+            jsonrpc_core::types::Call::Notification(n)
+                if n.method == PublishDiagnostics::METHOD =>
+            {
+                match n.params.parse::<PublishDiagnosticsParams>() {
+                    Ok(params) => {
+                        published
+                            .lock()
+                            .unwrap()
+                            .insert(uri_path(&params.uri), params.diagnostics);
+                    }
+                    Err(e) => error!("Malformed diagnostics from lsp server: {}", e),
+                }
+            }
+            call => debug!("Ignoring call from lsp server: {:?}", call),
+        }
+    }
+}
+
+/// Diagnostic as reported to the client at the start of its range, converted
+/// with the `lines` of the file at `path`. Diagnostics without severity are errors
+fn diagnostic_data(path: &Path, lines: &[String], diagnostic: &Diagnostic) -> DiagnosticData {
+    let kind = match diagnostic.severity {
+        Some(DiagnosticSeverity::Warning) => DiagnosticKind::WARNING,
+        Some(DiagnosticSeverity::Information) => DiagnosticKind::INFORMATION,
+        Some(DiagnosticSeverity::Hint) => DiagnosticKind::HINT,
+        _ => DiagnosticKind::ERROR,
+    };
+    DiagnosticData::new(
+        lsp_location(path, Some(lines), diagnostic.range.start),
+        kind,
+        diagnostic.message.clone(),
+    )
+}
+
+/// Candidates the server returned for `query` starting at `start_column`
+struct CachedCompletions {
+    filepath: PathBuf,
+    line_num: usize,
+    start_column: usize,
+    query: String,
+    candidates: Vec<Candidate>,
+}
+
+impl CachedCompletions {
+    /// Whether the request only extends the cached query, in which case the
+    /// cached candidates are a superset of what the server would answer
+    fn covers(&self, request: &SimpleRequest) -> bool {
+        self.filepath == request.filepath
+            && self.line_num == request.line_num
+            && self.start_column == request.start_column()
+            && request.query().starts_with(&self.query)
+    }
+}
+
+/// Items of the last completion answer with details left to resolve
+#[derive(Default)]
+struct ResolvableItems {
+    items: HashMap<usize, CompletionItem>,
+    /// Ids are never reused, so a stale one can't resolve another item
+    next_id: usize,
+}
+
+impl ResolvableItems {
+    /// Keep `item` until the next completion answer, returns its resolve id
+    fn insert(&mut self, item: CompletionItem) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.items.insert(id, item);
+        id
+    }
+}
+
+/// Last version of a document sent to the server
+struct OpenDocument {
+    version: i32,
+    contents: String,
+}
+
+/// Ids of the requests awaiting an answer, by the path of the document they are about
+#[derive(Clone, Default)]
+struct InFlightRequests(Arc<Mutex<HashMap<String, Vec<usize>>>>);
+
+impl InFlightRequests {
+    /// Keep track of request `id` until the returned guard is dropped
+    fn track(&self, filepath: &str, id: usize) -> TrackedRequest {
+        let mut requests = self.0.lock().unwrap();
+        requests.entry(filepath.to_owned()).or_default().push(id);
+        TrackedRequest {
+            requests: self.clone(),
+            filepath: filepath.to_owned(),
+            id,
+        }
+    }
+
+    /// Ids of the requests about `filepath`, which are no longer tracked
+    fn take(&self, filepath: &str) -> Vec<usize> {
+        self.0.lock().unwrap().remove(filepath).unwrap_or_default()
+    }
+}
+
+/// Forgets its request once answered or abandoned
+struct TrackedRequest {
+    requests: InFlightRequests,
+    filepath: String,
+    id: usize,
+}
+
+impl Drop for TrackedRequest {
+    fn drop(&mut self) {
+        let mut requests = self.requests.0.lock().unwrap();
+        if let Some(ids) = requests.get_mut(&self.filepath) {
+            ids.retain(|id| *id != self.id);
+            if ids.is_empty() {
+                requests.remove(&self.filepath);
+            }
+        }
+    }
 }
 
 impl CompleterInner for LspCompleter {
@@ -20,22 +207,1693 @@ impl CompleterInner for LspCompleter {
     }
 }
 
+fn document_uri(filepath: &str) -> Result<Url, anyhow::Error> {
+    Url::from_file_path(filepath).map_err(|_| anyhow::anyhow!("Not a valid path: {}", filepath))
+}
+
+/// Name of `kind` sent to clients as the candidate kind
+pub fn completion_kind_to_string(kind: CompletionItemKind) -> &'static str {
+    match kind {
+        CompletionItemKind::Text => "Text",
+        CompletionItemKind::Method => "Method",
+        CompletionItemKind::Function => "Function",
+        CompletionItemKind::Constructor => "Constructor",
+        CompletionItemKind::Field => "Field",
+        CompletionItemKind::Variable => "Variable",
+        CompletionItemKind::Class => "Class",
+        CompletionItemKind::Interface => "Interface",
+        CompletionItemKind::Module => "Module",
+        CompletionItemKind::Property => "Property",
+        CompletionItemKind::Unit => "Unit",
+        CompletionItemKind::Value => "Value",
+        CompletionItemKind::Enum => "Enum",
+        CompletionItemKind::Keyword => "Keyword",
+        CompletionItemKind::Snippet => "Snippet",
+        CompletionItemKind::Color => "Color",
+        CompletionItemKind::File => "File",
+        CompletionItemKind::Reference => "Reference",
+        CompletionItemKind::Folder => "Folder",
+        CompletionItemKind::EnumMember => "EnumMember",
+        CompletionItemKind::Constant => "Constant",
+        CompletionItemKind::Struct => "Struct",
+        CompletionItemKind::Event => "Event",
+        CompletionItemKind::Operator => "Operator",
+        CompletionItemKind::TypeParameter => "TypeParameter",
+    }
+}
+
+/// Inverse of [`completion_kind_to_string`], `None` for names of no LSP kind
+pub fn completion_kind_from_string(kind: &str) -> Option<CompletionItemKind> {
+    Some(match kind {
+        "Text" => CompletionItemKind::Text,
+        "Method" => CompletionItemKind::Method,
+        "Function" => CompletionItemKind::Function,
+        "Constructor" => CompletionItemKind::Constructor,
+        "Field" => CompletionItemKind::Field,
+        "Variable" => CompletionItemKind::Variable,
+        "Class" => CompletionItemKind::Class,
+        "Interface" => CompletionItemKind::Interface,
+        "Module" => CompletionItemKind::Module,
+        "Property" => CompletionItemKind::Property,
+        "Unit" => CompletionItemKind::Unit,
+        "Value" => CompletionItemKind::Value,
+        "Enum" => CompletionItemKind::Enum,
+        "Keyword" => CompletionItemKind::Keyword,
+        "Snippet" => CompletionItemKind::Snippet,
+        "Color" => CompletionItemKind::Color,
+        "File" => CompletionItemKind::File,
+        "Reference" => CompletionItemKind::Reference,
+        "Folder" => CompletionItemKind::Folder,
+        "EnumMember" => CompletionItemKind::EnumMember,
+        "Constant" => CompletionItemKind::Constant,
+        "Struct" => CompletionItemKind::Struct,
+        "Event" => CompletionItemKind::Event,
+        "Operator" => CompletionItemKind::Operator,
+        "TypeParameter" => CompletionItemKind::TypeParameter,
+        _ => return None,
+    })
+}
+
+/// Byte offset in `line` of an LSP position `character`, which counts UTF-16
+/// code units. Characters past the end of the line are clamped to it
+pub fn utf16_to_byte_offset(line: &str, character: u32) -> usize {
+    let mut units = 0;
+    for (offset, c) in line.char_indices() {
+        if units >= character as usize {
+            return offset;
+        }
+        units += c.len_utf16();
+    }
+    line.len()
+}
+
+fn uri_path(uri: &Url) -> PathBuf {
+    uri.to_file_path()
+        .unwrap_or_else(|_| PathBuf::from(uri.path()))
+}
+
+/// Location of an LSP `position` in `path`, whose `lines` are needed to turn
+/// its character into a byte column. Without them the character is taken as is
+fn lsp_location(path: &Path, lines: Option<&[String]>, position: Position) -> Location {
+    let column = match lines.and_then(|l| l.get(position.line as usize)) {
+        Some(line) => utf16_to_byte_offset(line, position.character),
+        None => position.character as usize,
+    };
+    Location::new(
+        path.display().to_string(),
+        position.line as usize + 1,
+        column + 1,
+    )
+}
+
+/// Locations of the symbols of a `textDocument/documentSymbol` (or `workspace/symbol`)
+/// response, positions in `filepath` being converted with its `lines`.
+/// Nested symbols are flattened, each following its parent
+fn symbol_locations(
+    response: DocumentSymbolResponse,
+    filepath: &Path,
+    lines: &[String],
+) -> Vec<GoToLocation> {
+    let location = |path: &Path, position: Position, description: String| GoToLocation {
+        location: lsp_location(path, Some(lines).filter(|_| path == filepath), position),
+        description,
+    };
+    match response {
+        DocumentSymbolResponse::Flat(symbols) => symbols
+            .into_iter()
+            .map(|s| {
+                let description = format!("{:?}: {}", s.kind, s.name);
+                location(
+                    &uri_path(&s.location.uri),
+                    s.location.range.start,
+                    description,
+                )
+            })
+            .collect(),
+        DocumentSymbolResponse::Nested(mut pending) => {
+            pending.reverse();
+            let mut locations = vec![];
+            while let Some(symbol) = pending.pop() {
+                let description = format!("{:?}: {}", symbol.kind, symbol.name);
+                let start = symbol.selection_range.start;
+                locations.push(location(filepath, start, description));
+                if let Some(children) = symbol.children {
+                    pending.extend(children.into_iter().rev());
+                }
+            }
+            locations
+        }
+    }
+}
+
+/// Chunks of `edit` ordered by file. Positions are converted with the contents
+/// of the files in `contents`, or else of the files on disk. Like clients
+/// supporting both, `documentChanges` are used when present and `changes`
+/// otherwise. Resource operations (creating, renaming or deleting files) are left out
+fn workspace_edit_chunks(
+    edit: WorkspaceEdit,
+    contents: &HashMap<PathBuf, String>,
+) -> Vec<FixitChunk> {
+    let document_edits = match edit.document_changes {
+        Some(DocumentChanges::Edits(edits)) => Some(edits),
+        Some(DocumentChanges::Operations(operations)) => Some(
+            operations
+                .into_iter()
+                .filter_map(|o| match o {
+                    DocumentChangeOperation::Edit(edit) => Some(edit),
+                    DocumentChangeOperation::Op(op) => {
+                        warn!("Ignoring unsupported resource operation {:?}", op);
+                        None
+                    }
+                })
+                .collect(),
+        ),
+        None => None,
+    };
+    let mut files = match document_edits {
+        Some(document_edits) => document_edits
+            .into_iter()
+            .map(|e| {
+                let edits = e
+                    .edits
+                    .into_iter()
+                    .map(|edit| match edit {
+                        OneOf::Left(edit) => edit,
+                        OneOf::Right(annotated) => annotated.text_edit,
+                    })
+                    .collect();
+                (uri_path(&e.text_document.uri), edits)
+            })
+            .collect::<Vec<_>>(),
+        None => edit
+            .changes
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(uri, edits)| (uri_path(&uri), edits))
+            .collect(),
+    };
+    files.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+    let mut chunks = vec![];
+    for (path, edits) in files {
+        let lines = match contents.get(&path) {
+            Some(c) => Some(c.clone()),
+            None => std::fs::read_to_string(&path).ok(),
+        }
+        .map(|c| c.lines().map(String::from).collect::<Vec<_>>());
+        let lines = lines.as_deref();
+        chunks.extend(edits.into_iter().map(|edit| {
+            FixitChunk::new(
+                edit.new_text,
+                crate::ycmd_types::Range::new(
+                    lsp_location(&path, lines, edit.range.start),
+                    lsp_location(&path, lines, edit.range.end),
+                ),
+            )
+        }));
+    }
+    chunks
+}
+
+/// LSP position of the byte `offset` in `text`
+fn offset_position(text: &str, offset: usize) -> Position {
+    let before = &text[..offset];
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    Position {
+        line: before.matches('\n').count() as u32,
+        character: before[line_start..].encode_utf16().count() as u32,
+    }
+}
+
+/// Single change turning `old` into `new`, replacing whatever is between their
+/// common prefix and suffix. `None` if they are the same
+fn incremental_change(old: &str, new: &str) -> Option<TextDocumentContentChangeEvent> {
+    if old == new {
+        return None;
+    }
+    let prefix = old
+        .char_indices()
+        .zip(new.chars())
+        .find(|((_, a), b)| a != b)
+        .map_or(old.len().min(new.len()), |((i, _), _)| i);
+    let max_suffix = old.len().min(new.len()) - prefix;
+    let suffix = old[prefix..]
+        .char_indices()
+        .rev()
+        .zip(new[prefix..].chars().rev())
+        .take_while(|((_, a), b)| a == b)
+        .map(|((_, a), _)| a.len_utf8())
+        .scan(0, |len, c| {
+            *len += c;
+            Some(*len)
+        })
+        .take_while(|len| *len <= max_suffix)
+        .last()
+        .unwrap_or(0);
+    Some(TextDocumentContentChangeEvent {
+        range: Some(lsp_types::Range {
+            start: offset_position(old, prefix),
+            end: offset_position(old, old.len() - suffix),
+        }),
+        range_length: None,
+        text: String::from(&new[prefix..new.len() - suffix]),
+    })
+}
+
+/// `textDocument/completion` with the answer left as JSON, so that it can go
+/// through `lenient_completion_response`
+enum RawCompletion {}
+
+impl Request for RawCompletion {
+    type Params = CompletionParams;
+    type Result = serde_json::Value;
+    const METHOD: &'static str = Completion::METHOD;
+}
+
+/// Completion answer `response` where items of a kind newer than the protocol
+/// version known here have no kind, instead of failing the whole answer
+fn lenient_completion_response(
+    mut response: serde_json::Value,
+) -> Result<Option<CompletionResponse>, serde_json::Error> {
+    let items = match response.get_mut("items") {
+        Some(items) => items.as_array_mut(),
+        None => response.as_array_mut(),
+    };
+    let items = items
+        .into_iter()
+        .flatten()
+        .filter_map(|i| i.as_object_mut());
+    for item in items {
+        let unknown = match item.get("kind") {
+            Some(kind) => serde_json::from_value::<CompletionItemKind>(kind.clone()).is_err(),
+            None => false,
+        };
+        if unknown {
+            let kind = item.remove("kind");
+            debug!("Ignoring unknown completion kind {:?}", kind);
+        }
+    }
+    serde_json::from_value(response)
+}
+
+fn completion_candidate(item: CompletionItem) -> Candidate {
+    let insertion_text = match (item.insert_text, item.text_edit) {
+        (Some(text), _) => text,
+        (None, Some(CompletionTextEdit::Edit(edit))) => edit.new_text,
+        (None, Some(CompletionTextEdit::InsertAndReplace(edit))) => edit.new_text,
+        (None, None) => item.label.clone(),
+    };
+    let deprecated = item.deprecated == Some(true)
+        || item
+            .tags
+            .iter()
+            .flatten()
+            .any(|tag| *tag == CompletionItemTag::Deprecated);
+    let mut candidate = Candidate {
+        insertion_text,
+        menu_text: Some(item.label),
+        extra_menu_info: item.detail,
+        detailed_info: item.documentation.map(|d| match d {
+            Documentation::String(s) => s,
+            Documentation::MarkupContent(m) => m.value,
+        }),
+        kind: item
+            .kind
+            .map(|k| String::from(completion_kind_to_string(k))),
+        extra_data: None,
+        filter_text: item.filter_text,
+    };
+    // Clients that don't expand snippets insert the text with placeholders filled in
+    if item.insert_text_format == Some(InsertTextFormat::Snippet) {
+        let body = std::mem::take(&mut candidate.insertion_text);
+        candidate.insertion_text = snippet_plain_text(&body);
+        if let Some(elements) = parse_snippet(&body) {
+            candidate.set_snippet(Snippet { body, elements });
+        }
+    }
+    if deprecated {
+        candidate.set_deprecated();
+    }
+    candidate
+}
+
 impl LspCompleter {
-    pub async fn new<P, S, I>(
-        path: P,
-        args: I,
-        port: Option<u32>,
+    /// Completer for a freshly spawned `server`, initialized with `root`
+    pub async fn new(
+        server: &LspServerConfig,
+        config: CompletionConfig,
+        root: &Path,
+    ) -> Result<Self, anyhow::Error> {
+        let (path, args) = server
+            .cmdline
+            .split_first()
+            .ok_or_else(|| anyhow::anyhow!("Empty cmdline for {}", server.name))?;
+        let client =
+            client::LspClient::new(path, args, server.port, &server.env, server.clear_env).await?;
+        Self::initialized(client, config, root).await
+    }
+
+    /// Completer for `client` once the initialize handshake with its server is done,
+    /// servers aren't expected to answer anything else before that
+    pub async fn initialized(
+        client: client::LspClient,
         config: CompletionConfig,
-    ) -> Result<Self, anyhow::Error>
-    where
-        I: IntoIterator<Item = S>,
-        S: AsRef<OsStr>,
-        P: AsRef<OsStr>,
-    {
-        let client = client::LspClient::new(path, args, port).await?;
+        root: &Path,
+    ) -> Result<Self, anyhow::Error> {
+        #[allow(deprecated)]
+        let params = InitializeParams {
+            process_id: Some(std::process::id()),
+            root_path: None,
+            root_uri: Url::from_directory_path(root).ok(),
+            initialization_options: None,
+            capabilities: ClientCapabilities {
+                text_document: Some(TextDocumentClientCapabilities {
+                    synchronization: Some(TextDocumentSyncClientCapabilities {
+                        did_save: Some(true),
+                        ..Default::default()
+                    }),
+                    completion: Some(CompletionClientCapabilities {
+                        completion_item: Some(CompletionItemCapability {
+                            deprecated_support: Some(true),
+                            tag_support: Some(TagSupport {
+                                value_set: vec![CompletionItemTag::Deprecated],
+                            }),
+                            ..Default::default()
+                        }),
+                        ..Default::default()
+                    }),
+                    rename: Some(RenameClientCapabilities {
+                        prepare_support: Some(true),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+            trace: None,
+            workspace_folders: None,
+            client_info: None,
+            locale: None,
+        };
+        let result = client.request::<Initialize>(params).await?;
+        client
+            .notification::<Initialized>(InitializedParams {})
+            .await?;
+        let mut completer = Self::with_client(client, config);
+        completer.capabilities = result.capabilities;
+        Ok(completer)
+    }
+
+    pub fn with_client(mut client: client::LspClient, config: CompletionConfig) -> Self {
+        let published_diagnostics = PublishedDiagnostics::default();
+        if let Some(calls) = client.take_server_requests() {
+            tokio::spawn(handle_server_calls(calls, published_diagnostics.clone()));
+        }
+        Self {
+            name: String::from("lsp"),
+            client: Arc::new(client),
+            // Items are ranked by the server, see `request_completions`
+            config: CompletionConfig {
+                empty_query_order: EmptyOrder::AsGiven,
+                ..config
+            },
+            open_documents: HashMap::default(),
+            capabilities: ServerCapabilities::default(),
+            resolvable: Arc::default(),
+            completion_cache: Arc::default(),
+            in_flight: InFlightRequests::default(),
+            published_diagnostics,
+        }
+    }
+
+    /// Same completer reporting `name`, see `Completer::name`
+    pub fn named(self, name: String) -> Self {
+        Self { name, ..self }
+    }
+
+    pub fn client(&self) -> Arc<client::LspClient> {
+        self.client.clone()
+    }
+
+    pub fn is_document_open(&self, filepath: &str) -> bool {
+        self.open_documents.contains_key(filepath)
+    }
+
+    /// Diagnostics the server last published for the event's file
+    pub fn diagnostics(&self, event: &EventNotification) -> Vec<DiagnosticData> {
+        let path = Path::new(&event.filepath);
+        let published = self.published_diagnostics.lock().unwrap();
+        let diagnostics = match published.get(path) {
+            Some(d) => d,
+            None => return vec![],
+        };
+        let lines = event
+            .file_data
+            .get(&event.filepath)
+            .map(|f| f.contents.lines().map(String::from).collect::<Vec<_>>())
+            .unwrap_or_default();
+        diagnostics
+            .iter()
+            .map(|d| diagnostic_data(path, &lines, d))
+            .collect()
+    }
+
+    /// Ask the server for completions at the request's start column.
+    /// The returned future doesn't borrow the completer, so it can be awaited
+    /// without holding any locks; dropping it cancels the request.
+    /// Candidates are returned unfiltered.
+    pub fn request_completions(
+        &self,
+        request: &SimpleRequest,
+    ) -> BoxFuture<'static, Result<Vec<Candidate>, anyhow::Error>> {
+        if let Some(cached) = &*self.completion_cache.lock().unwrap() {
+            if cached.covers(request) {
+                // Filtered against the longer query by the caller like any other answer
+                let candidates = cached.candidates.clone();
+                return Box::pin(async move { Ok(candidates) });
+            }
+        }
+        let client = self.client.clone();
+        let params = Url::from_file_path(&request.filepath)
+            .map_err(|_| anyhow::anyhow!("Not a valid path: {:?}", request.filepath))
+            .map(|uri| CompletionParams {
+                text_document_position: TextDocumentPositionParams {
+                    text_document: TextDocumentIdentifier { uri },
+                    position: Position {
+                        line: request.line_num.saturating_sub(1) as u32,
+                        character: request.prefix().encode_utf16().count() as u32,
+                    },
+                },
+                work_done_progress_params: Default::default(),
+                partial_result_params: Default::default(),
+                context: None,
+            });
+        let resolve = self
+            .capabilities
+            .completion_provider
+            .as_ref()
+            .and_then(|c| c.resolve_provider)
+            == Some(true);
+        let resolvable = self.resolvable.clone();
+        let in_flight = self.in_flight.clone();
+        let filepath = request.filepath.to_string_lossy().into_owned();
+        let cache = self.completion_cache.clone();
+        *cache.lock().unwrap() = None;
+        let mut cached = CachedCompletions {
+            filepath: request.filepath.clone(),
+            line_num: request.line_num,
+            start_column: request.start_column(),
+            query: request.query().to_owned(),
+            candidates: vec![],
+        };
+        Box::pin(async move {
+            let mut tracked = None;
+            let response = client
+                .request_with_id::<RawCompletion>(params?, |id| {
+                    tracked = Some(in_flight.track(&filepath, id))
+                })
+                .await?;
+            let (mut items, complete) = match lenient_completion_response(response)? {
+                Some(CompletionResponse::Array(items)) => (items, true),
+                Some(CompletionResponse::List(list)) => (list.items, !list.is_incomplete),
+                None => (vec![], true),
+            };
+            // Servers rank items by their sort text, empty queries keep that order
+            items.sort_by(|a, b| {
+                let a = a.sort_text.as_ref().unwrap_or(&a.label);
+                a.cmp(b.sort_text.as_ref().unwrap_or(&b.label))
+            });
+            let candidates: Vec<_> = {
+                let mut resolvable = resolvable.lock().unwrap();
+                resolvable.items.clear();
+                items
+                    .into_iter()
+                    .map(|item| {
+                        let incomplete = resolve && item.documentation.is_none();
+                        let id = incomplete.then(|| resolvable.insert(item.clone()));
+                        let mut candidate = completion_candidate(item);
+                        if let Some(id) = id {
+                            candidate.set_resolve(id);
+                        }
+                        candidate
+                    })
+                    .collect()
+            };
+            if complete {
+                cached.candidates = candidates.clone();
+                *cache.lock().unwrap() = Some(cached);
+            }
+            Ok(candidates)
+        })
+    }
+
+    /// Ask the server for the details of the candidate with resolve id `id` from
+    /// the last completion request, see `request_completions`
+    pub fn request_resolve(
+        &self,
+        id: usize,
+    ) -> BoxFuture<'static, Result<Candidate, anyhow::Error>> {
+        let client = self.client.clone();
+        let item = self.resolvable.lock().unwrap().items.get(&id).cloned();
+        Box::pin(async move {
+            let item =
+                item.ok_or_else(|| anyhow::anyhow!("No completion to resolve with id {}", id))?;
+            let item = client.request::<ResolveCompletionItem>(item).await?;
+            Ok(completion_candidate(item))
+        })
+    }
+
+    /// Ask the server for the symbols of the request's file, see `request_completions`
+    pub fn request_document_symbols(
+        &self,
+        request: &SimpleRequest,
+    ) -> BoxFuture<'static, Result<Vec<GoToLocation>, anyhow::Error>> {
+        let client = self.client.clone();
+        let in_flight = self.in_flight.clone();
+        let filepath = request.filepath.clone();
+        let lines = request.lines().map(String::from).collect::<Vec<_>>();
+        let params = Url::from_file_path(&request.filepath)
+            .map_err(|_| anyhow::anyhow!("Not a valid path: {:?}", request.filepath))
+            .map(|uri| DocumentSymbolParams {
+                text_document: TextDocumentIdentifier { uri },
+                work_done_progress_params: Default::default(),
+                partial_result_params: Default::default(),
+            });
+        Box::pin(async move {
+            let mut tracked = None;
+            let response = client
+                .request_with_id::<DocumentSymbolRequest>(params?, |id| {
+                    tracked = Some(in_flight.track(&filepath.to_string_lossy(), id))
+                })
+                .await?;
+            Ok(match response {
+                Some(response) => symbol_locations(response, &filepath, &lines),
+                None => vec![],
+            })
+        })
+    }
+
+    /// Ask the server for the symbols of the whole workspace matching `query`,
+    /// see `request_completions`
+    pub fn request_workspace_symbols(
+        &self,
+        request: &SimpleRequest,
+        query: &str,
+    ) -> BoxFuture<'static, Result<Vec<GoToLocation>, anyhow::Error>> {
+        let client = self.client.clone();
+        let filepath = request.filepath.clone();
+        let lines = request.lines().map(String::from).collect::<Vec<_>>();
+        let params = WorkspaceSymbolParams {
+            partial_result_params: Default::default(),
+            work_done_progress_params: Default::default(),
+            query: String::from(query),
+        };
+        Box::pin(async move {
+            Ok(match client.request::<WorkspaceSymbol>(params).await? {
+                Some(symbols) => {
+                    symbol_locations(DocumentSymbolResponse::Flat(symbols), &filepath, &lines)
+                }
+                None => vec![],
+            })
+        })
+    }
+
+    /// Rename the symbol under the cursor to `new_name`, as a single fix it whose
+    /// chunks may span several files. Servers that want to are asked first whether
+    /// the symbol can be renamed. See `request_completions`
+    pub fn request_rename(
+        &self,
+        request: &SimpleRequest,
+        new_name: &str,
+    ) -> BoxFuture<'static, Result<Fixit, anyhow::Error>> {
+        let prepare = match &self.capabilities.rename_provider {
+            None | Some(OneOf::Left(false)) => {
+                return Box::pin(async {
+                    Err(anyhow::anyhow!("Language server doesn't support renaming"))
+                })
+            }
+            Some(OneOf::Left(true)) => false,
+            Some(OneOf::Right(options)) => options.prepare_provider == Some(true),
+        };
+        let client = self.client.clone();
+        let cursor = Location::new(
+            request.filepath.display().to_string(),
+            request.line_num,
+            request.column_num,
+        );
+        let contents = request
+            .file_data
+            .iter()
+            .map(|(path, file)| (path.clone(), file.contents.clone()))
+            .collect::<HashMap<_, _>>();
+        let position = Url::from_file_path(&request.filepath)
+            .map_err(|_| anyhow::anyhow!("Not a valid path: {:?}", request.filepath))
+            .map(|uri| TextDocumentPositionParams {
+                text_document: TextDocumentIdentifier { uri },
+                position: Position {
+                    line: request.line_num.saturating_sub(1) as u32,
+                    character: request
+                        .line_value()
+                        .get(..request.column_num.saturating_sub(1))
+                        .unwrap_or_default()
+                        .encode_utf16()
+                        .count() as u32,
+                },
+            });
+        let new_name = String::from(new_name);
+        Box::pin(async move {
+            let position = position?;
+            if prepare
+                && client
+                    .request::<PrepareRenameRequest>(position.clone())
+                    .await
+                    .map_err(|e| anyhow::anyhow!("Rename failed: {}", e))?
+                    .is_none()
+            {
+                anyhow::bail!("Can't rename the symbol under the cursor");
+            }
+            let params = RenameParams {
+                text_document_position: position,
+                new_name,
+                work_done_progress_params: Default::default(),
+            };
+            let edit = client
+                .request::<Rename>(params)
+                .await
+                .map_err(|e| anyhow::anyhow!("Rename failed: {}", e))?
+                .ok_or_else(|| anyhow::anyhow!("Nothing to rename"))?;
+            let chunks = workspace_edit_chunks(edit, &contents);
+            Ok(Fixit::new(String::new(), cursor, String::new(), chunks))
+        })
+    }
 
-        Ok(Self { client, config })
+    /// Send the current contents of the event's file, opening it first if needed
+    fn sync_document(&mut self, event: &EventNotification) -> Result<(), anyhow::Error> {
+        let file = match event.file_data.get(&event.filepath) {
+            Some(f) => f,
+            None => return Ok(()),
+        };
+        let uri = document_uri(&event.filepath)?;
+        let incremental = match &self.capabilities.text_document_sync {
+            Some(TextDocumentSyncCapability::Kind(kind)) => {
+                *kind == TextDocumentSyncKind::Incremental
+            }
+            Some(TextDocumentSyncCapability::Options(options)) => {
+                options.change == Some(TextDocumentSyncKind::Incremental)
+            }
+            None => false,
+        };
+        match self.open_documents.get_mut(&event.filepath) {
+            Some(document) => {
+                let change = if incremental {
+                    match incremental_change(&document.contents, &file.contents) {
+                        Some(change) => change,
+                        None => return Ok(()),
+                    }
+                } else {
+                    TextDocumentContentChangeEvent {
+                        range: None,
+                        range_length: None,
+                        text: file.contents.clone(),
+                    }
+                };
+                document.version += 1;
+                document.contents = file.contents.clone();
+                // Answers about the previous version would be stale
+                for id in self.in_flight.take(&event.filepath) {
+                    self.client.cancel(id);
+                }
+                self.client.notification_nowait::<DidChangeTextDocument>(
+                    DidChangeTextDocumentParams {
+                        text_document: VersionedTextDocumentIdentifier {
+                            uri,
+                            version: document.version,
+                        },
+                        content_changes: vec![change],
+                    },
+                )
+            }
+            None => {
+                self.client.notification_nowait::<DidOpenTextDocument>(
+                    DidOpenTextDocumentParams {
+                        text_document: TextDocumentItem {
+                            uri,
+                            language_id: file.filetypes.first().cloned().unwrap_or_default(),
+                            version: 1,
+                            text: file.contents.clone(),
+                        },
+                    },
+                )?;
+                self.open_documents.insert(
+                    event.filepath.clone(),
+                    OpenDocument {
+                        version: 1,
+                        contents: file.contents.clone(),
+                    },
+                );
+                Ok(())
+            }
+        }
+    }
+
+    /// Tell the server the file was saved, if it asked to be told. A document
+    /// changed since the last sync is synced first, so the server knows the saved text
+    fn save_document(&mut self, event: &EventNotification) -> Result<(), anyhow::Error> {
+        let include_text = match &self.capabilities.text_document_sync {
+            Some(TextDocumentSyncCapability::Options(options)) => match &options.save {
+                Some(TextDocumentSyncSaveOptions::Supported(true)) => false,
+                Some(TextDocumentSyncSaveOptions::SaveOptions(save)) => {
+                    save.include_text == Some(true)
+                }
+                _ => return Ok(()),
+            },
+            _ => return Ok(()),
+        };
+        let synced = match (
+            self.open_documents.get(&event.filepath),
+            event.file_data.get(&event.filepath),
+        ) {
+            (Some(document), Some(file)) => document.contents == file.contents,
+            _ => false,
+        };
+        if !synced {
+            self.sync_document(event)?;
+        }
+        let document = match self.open_documents.get(&event.filepath) {
+            Some(document) => document,
+            None => return Ok(()),
+        };
+        self.client
+            .notification_nowait::<DidSaveTextDocument>(DidSaveTextDocumentParams {
+                text_document: TextDocumentIdentifier {
+                    uri: document_uri(&event.filepath)?,
+                },
+                text: Some(document.contents.clone()).filter(|_| include_text),
+            })
+    }
+
+    fn close_document(&mut self, filepath: &str) -> Result<(), anyhow::Error> {
+        if self.open_documents.remove(filepath).is_some() {
+            for id in self.in_flight.take(filepath) {
+                self.client.cancel(id);
+            }
+            self.client.notification_nowait::<DidCloseTextDocument>(
+                DidCloseTextDocumentParams {
+                    text_document: TextDocumentIdentifier {
+                        uri: document_uri(filepath)?,
+                    },
+                },
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl Completer for LspCompleter {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn is_healthy(&mut self) -> bool {
+        self.client.is_healthy(RESPONSE_GRACE)
+    }
+
+    fn on_event(&mut self, event: &EventNotification) {
+        // The buffer may have changed, so may have what the server would answer
+        *self.completion_cache.lock().unwrap() = None;
+        let result = match event.event_name {
+            Event::FileReadyToParse => self.sync_document(event),
+            Event::BufferUnload => self.close_document(&event.filepath),
+            Event::FileSave => self.save_document(event),
+            _ => Ok(()),
+        };
+        if let Err(e) = result {
+            error!("Failed to sync {} with lsp server: {}", event.filepath, e);
+        }
     }
 }
 
-impl Completer for LspCompleter {}
+#[cfg(test)]
+mod tests {
+    use tokio::io::{
+        AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader,
+    };
+
+    use super::*;
+    use crate::completer::filter_and_sort_completions;
+    use crate::completer::tests::test_config;
+    use crate::ycmd_types::FileData;
+
+    #[test]
+    fn completion_kind_names() {
+        // Every kind of the protocol, by its number
+        for number in 1..=25 {
+            let kind: CompletionItemKind =
+                serde_json::from_value(serde_json::json!(number)).unwrap();
+            let name = completion_kind_to_string(kind);
+            assert_eq!(name, format!("{:?}", kind));
+            assert_eq!(completion_kind_from_string(name), Some(kind));
+        }
+        assert!(serde_json::from_value::<CompletionItemKind>(serde_json::json!(26)).is_err());
+
+        assert_eq!(completion_kind_from_string("function"), None);
+        assert_eq!(completion_kind_from_string(""), None);
+    }
+
+    #[test]
+    fn unknown_completion_kinds_ignored() {
+        let item = |kind: u32| serde_json::json!({ "label": "foo", "kind": kind });
+        let kinds = |response| {
+            let items = match lenient_completion_response(response).unwrap() {
+                Some(CompletionResponse::Array(items)) => items,
+                Some(CompletionResponse::List(list)) => list.items,
+                None => vec![],
+            };
+            items.into_iter().map(|i| i.kind).collect::<Vec<_>>()
+        };
+
+        let expected = vec![Some(CompletionItemKind::Function), None];
+        assert_eq!(kinds(serde_json::json!([item(3), item(26)])), expected);
+        assert_eq!(
+            kinds(serde_json::json!({
+                "isIncomplete": false,
+                "items": [item(3), item(26)],
+            })),
+            expected
+        );
+        assert_eq!(kinds(serde_json::Value::Null), vec![]);
+    }
+
+    #[test]
+    fn snippet_completion_item() {
+        let item: CompletionItem = serde_json::from_value(serde_json::json!({
+            "label": "push",
+            "insertText": "push(${1:value})$0",
+            "insertTextFormat": 2,
+        }))
+        .unwrap();
+        let candidate = completion_candidate(item);
+        assert_eq!(candidate.insertion_text, "push(value)");
+        let candidate = serde_json::to_value(candidate).unwrap();
+        assert_eq!(
+            candidate["extra_data"]["snippet"]["body"],
+            "push(${1:value})$0"
+        );
+        assert_eq!(
+            candidate["extra_data"]["snippet"]["elements"][1]["placeholder"][0]["text"],
+            "value"
+        );
+
+        // Plain text is inserted as it is
+        let item: CompletionItem = serde_json::from_value(serde_json::json!({
+            "label": "cost",
+            "insertText": "cost$1",
+            "insertTextFormat": 1,
+        }))
+        .unwrap();
+        let candidate = completion_candidate(item);
+        assert_eq!(candidate.insertion_text, "cost$1");
+        assert!(candidate.extra_data.is_none());
+    }
+
+    #[test]
+    fn deprecated_completion_items() {
+        let candidates: Vec<_> = [
+            serde_json::json!({"label": "abc", "deprecated": true}),
+            serde_json::json!({"label": "abd", "tags": [1]}),
+            serde_json::json!({"label": "abe", "deprecated": false, "tags": []}),
+        ]
+        .iter()
+        .map(|item| completion_candidate(serde_json::from_value(item.clone()).unwrap()))
+        .collect();
+        let deprecated: Vec<_> = candidates.iter().map(Candidate::is_deprecated).collect();
+        assert_eq!(deprecated, [true, true, false]);
+        let serialized = serde_json::to_value(&candidates).unwrap();
+        assert_eq!(serialized[0]["extra_data"]["deprecated"], true);
+        assert_eq!(serialized[1]["extra_data"]["deprecated"], true);
+        assert!(serialized[2]["extra_data"].is_null());
+
+        let request = test_request("ab", 3);
+        let texts = |config: &CompletionConfig| {
+            filter_and_sort_completions(candidates.clone(), &request, config)
+                .into_iter()
+                .map(|c| c.insertion_text)
+                .collect::<Vec<_>>()
+        };
+        let mut config = test_config();
+        assert_eq!(texts(&config), ["abc", "abd", "abe"]);
+        config.sink_deprecated = true;
+        assert_eq!(texts(&config), ["abe", "abc", "abd"]);
+    }
+
+    #[test]
+    fn utf16_offsets() {
+        assert_eq!(utf16_to_byte_offset("abc", 1), 1);
+        assert_eq!(utf16_to_byte_offset("éa", 1), 2);
+        // Surrogate pair
+        assert_eq!(utf16_to_byte_offset("😀a", 2), 4);
+        assert_eq!(utf16_to_byte_offset("abc", 10), 3);
+    }
+
+    #[test]
+    fn nested_document_symbols_flattened() {
+        let symbol = |name: &str, line: u32, character: u32, children| {
+            serde_json::json!({
+                "name": name,
+                "kind": 12,
+                "range": {
+                    "start": { "line": line, "character": 0 },
+                    "end": { "line": line + 1, "character": 0 },
+                },
+                "selectionRange": {
+                    "start": { "line": line, "character": character },
+                    "end": { "line": line, "character": character + 1 },
+                },
+                "children": children,
+            })
+        };
+        let response: DocumentSymbolResponse = serde_json::from_value(serde_json::json!([
+            symbol(
+                "outer",
+                0,
+                3,
+                serde_json::json!([symbol(
+                    "inner",
+                    1,
+                    7,
+                    serde_json::json!([symbol("innermost", 2, 1, serde_json::json!([]))])
+                )])
+            ),
+            symbol("last", 3, 3, serde_json::Value::Null),
+        ]))
+        .unwrap();
+        let lines = ["fn outer() {", "    fn é() {", "\tlet", "fn last() {}"]
+            .iter()
+            .map(|l| String::from(*l))
+            .collect::<Vec<_>>();
+
+        let locations = symbol_locations(response, Path::new("/foo.rs"), &lines);
+        let expected = [
+            ("outer", 1, 4),
+            ("inner", 2, 8),
+            ("innermost", 3, 2),
+            ("last", 4, 4),
+        ];
+        assert_eq!(
+            locations,
+            expected
+                .iter()
+                .map(|(name, line, column)| GoToLocation {
+                    location: Location::new(String::from("/foo.rs"), *line, *column),
+                    description: format!("Function: {}", name),
+                })
+                .collect::<Vec<_>>()
+        );
+    }
+
+    fn test_event(event_name: Event, filepath: &str, contents: &str) -> EventNotification {
+        let mut file_data = HashMap::default();
+        file_data.insert(
+            String::from(filepath),
+            FileData {
+                filetypes: vec![String::from("rust")],
+                contents: String::from(contents),
+            },
+        );
+        EventNotification {
+            line_num: 1,
+            column_num: 1,
+            filepath: String::from(filepath),
+            file_data,
+            completer_target: None,
+            working_dir: None,
+            extra_conf_data: None,
+            event_name,
+            ultisnips_snippets: None,
+        }
+    }
+
+    /// Read one framed message written by the client
+    async fn read_message<R: AsyncRead + Unpin>(reader: &mut BufReader<R>) -> serde_json::Value {
+        let mut content_len = 0;
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).await.unwrap();
+            match line.trim().strip_prefix("Content-Length:") {
+                Some(len) => content_len = len.trim().parse().unwrap(),
+                None if line.trim().is_empty() => break,
+                None => {}
+            }
+        }
+        let mut content = vec![0; content_len];
+        reader.read_exact(&mut content).await.unwrap();
+        serde_json::from_slice(&content).unwrap()
+    }
+
+    /// Answer `request` with `result`
+    async fn respond<W: AsyncWrite + Unpin>(
+        writer: &mut W,
+        request: &serde_json::Value,
+        result: serde_json::Value,
+    ) {
+        let response = serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": request["id"],
+            "result": result,
+        })
+        .to_string();
+        writer
+            .write_all(format!("Content-Length: {}\r\n\r\n{}", response.len(), response).as_bytes())
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn unanswered_request_is_unresponsive() {
+        let (client, server) = tokio::io::duplex(4096);
+        let (client_r, client_w) = tokio::io::split(client);
+        let client = std::sync::Arc::new(client::LspClient::from_transport(
+            transport::LspTransport::new(client_r, client_w),
+        ));
+        let mut server = BufReader::new(server);
+        assert!(client.is_responsive(Duration::from_secs(0)));
+
+        let requester = client.clone();
+        tokio::spawn(async move {
+            let _ = requester.request::<lsp_types::request::Shutdown>(()).await;
+        });
+        let message = read_message(&mut server).await;
+        assert_eq!(message["method"], "shutdown");
+
+        assert!(!client.is_responsive(Duration::from_secs(0)));
+        assert!(client.is_responsive(Duration::from_secs(60)));
+    }
+
+    #[tokio::test]
+    async fn initialize_handshake() {
+        let (client, server) = tokio::io::duplex(4096);
+        let (client_r, client_w) = tokio::io::split(client);
+        let client =
+            client::LspClient::from_transport(transport::LspTransport::new(client_r, client_w));
+        let completer = tokio::spawn(async {
+            LspCompleter::initialized(client, test_config(), Path::new("/project")).await
+        });
+        let mut server = BufReader::new(server);
+
+        let request = read_message(&mut server).await;
+        assert_eq!(request["method"], "initialize");
+        assert_eq!(request["params"]["processId"], std::process::id());
+        assert_eq!(request["params"]["rootUri"], "file:///project/");
+        let response = serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": request["id"],
+            "result": {"capabilities": {}},
+        })
+        .to_string();
+        server
+            .get_mut()
+            .write_all(format!("Content-Length: {}\r\n\r\n{}", response.len(), response).as_bytes())
+            .await
+            .unwrap();
+
+        assert_eq!(read_message(&mut server).await["method"], "initialized");
+        assert!(completer.await.unwrap().is_ok());
+    }
+
+    #[test]
+    fn multi_file_rename_edit() {
+        let uri = |path: &str| Url::from_file_path(path).unwrap();
+        let edit = |line: u32, start: u32, end: u32, text: &str| {
+            serde_json::json!({
+                "range": {
+                    "start": { "line": line, "character": start },
+                    "end": { "line": line, "character": end },
+                },
+                "newText": text,
+            })
+        };
+        let foo_edits = serde_json::json!([edit(0, 4, 7, "new"), edit(1, 2, 5, "new")]);
+        let mut changes = serde_json::Map::new();
+        changes.insert(uri("/foo.rs").to_string(), foo_edits.clone());
+        // The same edits both ways, as servers may send to support either client
+        let workspace_edit: WorkspaceEdit = serde_json::from_value(serde_json::json!({
+            "changes": changes,
+            "documentChanges": [{
+                "textDocument": { "uri": uri("/bar.rs"), "version": 1 },
+                "edits": [edit(3, 0, 3, "new")],
+            }, {
+                "textDocument": { "uri": uri("/foo.rs"), "version": 1 },
+                "edits": foo_edits,
+            }],
+        }))
+        .unwrap();
+        let mut contents = HashMap::new();
+        contents.insert(
+            PathBuf::from("/foo.rs"),
+            String::from("let old = 1;\né old;"),
+        );
+
+        let range = |path: &str, line, start, end| {
+            crate::ycmd_types::Range::new(
+                Location::new(String::from(path), line, start),
+                Location::new(String::from(path), line, end),
+            )
+        };
+        assert_eq!(
+            workspace_edit_chunks(workspace_edit, &contents),
+            vec![
+                // Not open nor on disk, characters are taken as bytes
+                FixitChunk::new(String::from("new"), range("/bar.rs", 4, 1, 4)),
+                FixitChunk::new(String::from("new"), range("/foo.rs", 1, 5, 8)),
+                // "é" is one UTF-16 code unit but two bytes
+                FixitChunk::new(String::from("new"), range("/foo.rs", 2, 4, 7)),
+            ]
+        );
+        let workspace_edit: WorkspaceEdit =
+            serde_json::from_value(serde_json::json!({ "changes": changes })).unwrap();
+        assert_eq!(
+            workspace_edit_chunks(workspace_edit, &contents),
+            vec![
+                FixitChunk::new(String::from("new"), range("/foo.rs", 1, 5, 8)),
+                FixitChunk::new(String::from("new"), range("/foo.rs", 2, 4, 7)),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn workspace_symbols() {
+        let (client, server) = tokio::io::duplex(4096);
+        let (client_r, client_w) = tokio::io::split(client);
+        let completer = LspCompleter::with_client(
+            client::LspClient::from_transport(transport::LspTransport::new(client_r, client_w)),
+            test_config(),
+        );
+        let mut server = BufReader::new(server);
+
+        let symbols = completer.request_workspace_symbols(&test_request("é foo", 1), "foo");
+        let symbols = tokio::spawn(symbols);
+        let request = read_message(&mut server).await;
+        assert_eq!(request["method"], "workspace/symbol");
+        assert_eq!(request["params"]["query"], "foo");
+        let symbol = |name: &str, path: &str, line: u32, character: u32| {
+            serde_json::json!({
+                "name": name,
+                "kind": 12,
+                "location": {
+                    "uri": Url::from_file_path(path).unwrap(),
+                    "range": {
+                        "start": { "line": line, "character": character },
+                        "end": { "line": line, "character": character + 3 },
+                    },
+                },
+            })
+        };
+        respond(
+            server.get_mut(),
+            &request,
+            serde_json::json!([
+                symbol("foo", "/foo.rs", 0, 2),
+                symbol("foo_bar", "/bar.rs", 4, 3)
+            ]),
+        )
+        .await;
+
+        assert_eq!(
+            symbols.await.unwrap().unwrap(),
+            vec![
+                GoToLocation {
+                    // "é" is one UTF-16 code unit but two bytes
+                    location: Location::new(String::from("/foo.rs"), 1, 4),
+                    description: String::from("Function: foo"),
+                },
+                GoToLocation {
+                    location: Location::new(String::from("/bar.rs"), 5, 4),
+                    description: String::from("Function: foo_bar"),
+                },
+            ]
+        );
+    }
+
+    fn test_request(contents: &str, column_num: usize) -> SimpleRequest {
+        SimpleRequest {
+            column_num,
+            ..crate::completer::tests::test_request(contents, "rust")
+        }
+    }
+
+    #[tokio::test]
+    async fn dropped_completion_request_is_cancelled() {
+        let (client, server) = tokio::io::duplex(4096);
+        let (client_r, client_w) = tokio::io::split(client);
+        let client =
+            client::LspClient::from_transport(transport::LspTransport::new(client_r, client_w));
+        let completer = LspCompleter::with_client(client, test_config());
+        let mut server = BufReader::new(server);
+
+        let pending = completer.request_completions(&test_request("é.fo", 6));
+        assert!(tokio::time::timeout(Duration::from_millis(10), pending)
+            .await
+            .is_err());
+
+        let request = read_message(&mut server).await;
+        assert_eq!(request["method"], "textDocument/completion");
+        // Start column counts UTF-16 code units, "é" is a single one
+        assert_eq!(request["params"]["position"]["character"], 2);
+        let cancel = read_message(&mut server).await;
+        assert_eq!(cancel["method"], "$/cancelRequest");
+        assert_eq!(cancel["params"]["id"], request["id"]);
+    }
+
+    #[tokio::test]
+    async fn change_cancels_pending_requests() {
+        let (client, server) = tokio::io::duplex(4096);
+        let (client_r, client_w) = tokio::io::split(client);
+        let client =
+            client::LspClient::from_transport(transport::LspTransport::new(client_r, client_w));
+        let mut completer = LspCompleter::with_client(client, test_config());
+        let mut server = BufReader::new(server);
+
+        completer.on_event(&test_event(Event::FileReadyToParse, "/foo.rs", "x.fo"));
+        assert_eq!(
+            read_message(&mut server).await["method"],
+            "textDocument/didOpen"
+        );
+        let pending = tokio::spawn(completer.request_completions(&test_request("x.fo", 5)));
+        let request = read_message(&mut server).await;
+        assert_eq!(request["method"], "textDocument/completion");
+
+        completer.on_event(&test_event(Event::FileReadyToParse, "/foo.rs", "x.foo"));
+        let cancel = read_message(&mut server).await;
+        assert_eq!(cancel["method"], "$/cancelRequest");
+        assert_eq!(cancel["params"]["id"], request["id"]);
+        assert_eq!(
+            read_message(&mut server).await["method"],
+            "textDocument/didChange"
+        );
+        // A late answer is dropped along with the request
+        respond(
+            server.get_mut(),
+            &request,
+            serde_json::json!([{ "label": "foo" }]),
+        )
+        .await;
+        let error = pending.await.unwrap().unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<jsonrpc_core::Error>().unwrap().code,
+            jsonrpc_core::ErrorCode::ServerError(transport::REQUEST_CANCELLED)
+        );
+        assert!(completer.in_flight.take("/foo.rs").is_empty());
+    }
+
+    #[tokio::test]
+    async fn resolve_completion_details() {
+        let (client, server) = tokio::io::duplex(4096);
+        let (client_r, client_w) = tokio::io::split(client);
+        let client =
+            client::LspClient::from_transport(transport::LspTransport::new(client_r, client_w));
+        let mut completer = LspCompleter::with_client(client, test_config());
+        completer.capabilities = serde_json::from_value(serde_json::json!({
+            "completionProvider": { "resolveProvider": true },
+        }))
+        .unwrap();
+        let mut server = BufReader::new(server);
+
+        let pending = tokio::spawn(completer.request_completions(&test_request("fo", 3)));
+        let request = read_message(&mut server).await;
+        respond(
+            server.get_mut(),
+            &request,
+            serde_json::json!([
+                { "label": "foo", "data": 42 },
+                { "label": "fop", "documentation": "Already there" },
+            ]),
+        )
+        .await;
+        let candidates = serde_json::to_value(pending.await.unwrap().unwrap()).unwrap();
+        assert_eq!(candidates[0]["extra_data"]["resolve"], 0);
+        assert!(candidates[1]["extra_data"].is_null());
+
+        let resolved = tokio::spawn(completer.request_resolve(0));
+        let request = read_message(&mut server).await;
+        assert_eq!(request["method"], "completionItem/resolve");
+        // Servers get their item back as they sent it
+        assert_eq!(request["params"]["data"], 42);
+        respond(
+            server.get_mut(),
+            &request,
+            serde_json::json!({
+                "label": "foo",
+                "detail": "fn foo()",
+                "documentation": { "kind": "markdown", "value": "Does foo" },
+            }),
+        )
+        .await;
+        let resolved = resolved.await.unwrap().unwrap();
+        assert_eq!(resolved.detailed_info.as_deref(), Some("Does foo"));
+        assert_eq!(resolved.extra_menu_info.as_deref(), Some("fn foo()"));
+        assert!(completer.request_resolve(1).await.is_err());
+
+        // Ids of earlier answers are not given to new items, nor resolved anymore
+        let pending = tokio::spawn(completer.request_completions(&test_request("ba", 3)));
+        let request = read_message(&mut server).await;
+        respond(
+            server.get_mut(),
+            &request,
+            serde_json::json!([{ "label": "bar" }]),
+        )
+        .await;
+        let candidates = serde_json::to_value(pending.await.unwrap().unwrap()).unwrap();
+        assert_eq!(candidates[0]["extra_data"]["resolve"], 1);
+        assert!(completer.request_resolve(0).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn completions_in_sort_text_order() {
+        let (client, server) = tokio::io::duplex(4096);
+        let (client_r, client_w) = tokio::io::split(client);
+        let client =
+            client::LspClient::from_transport(transport::LspTransport::new(client_r, client_w));
+        let completer = LspCompleter::with_client(client, test_config());
+        let mut server = BufReader::new(server);
+
+        let request = test_request("x.", 3);
+        let pending = tokio::spawn(completer.request_completions(&request));
+        let message = read_message(&mut server).await;
+        respond(
+            server.get_mut(),
+            &message,
+            serde_json::json!([
+                { "label": "alpha", "sortText": "2" },
+                { "label": "beta", "sortText": "1" },
+                { "label": "gamma" },
+            ]),
+        )
+        .await;
+        let candidates = pending.await.unwrap().unwrap();
+        let candidates =
+            filter_and_sort_completions(candidates, &request, completer.get_settings());
+        let labels = candidates
+            .iter()
+            .map(|c| c.insertion_text.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(labels, vec!["beta", "alpha", "gamma"]);
+    }
+
+    #[tokio::test]
+    async fn completions_matched_by_filter_text() {
+        let (client, server) = tokio::io::duplex(4096);
+        let (client_r, client_w) = tokio::io::split(client);
+        let client =
+            client::LspClient::from_transport(transport::LspTransport::new(client_r, client_w));
+        let completer = LspCompleter::with_client(client, test_config());
+        let mut server = BufReader::new(server);
+
+        let request = test_request("form", 5);
+        let pending = tokio::spawn(completer.request_completions(&request));
+        let message = read_message(&mut server).await;
+        respond(
+            server.get_mut(),
+            &message,
+            serde_json::json!([
+                { "label": "fmt()", "insertText": "fmt", "filterText": "format" },
+                { "label": "fork" },
+            ]),
+        )
+        .await;
+        let candidates = pending.await.unwrap().unwrap();
+        let candidates =
+            filter_and_sort_completions(candidates, &request, completer.get_settings());
+        assert_eq!(candidates.len(), 1);
+        assert_eq!(candidates[0].insertion_text, "fmt");
+        assert_eq!(candidates[0].menu_text.as_deref(), Some("fmt()"));
+    }
+
+    #[tokio::test]
+    async fn extended_query_reuses_completions() {
+        let (client, server) = tokio::io::duplex(4096);
+        let (client_r, client_w) = tokio::io::split(client);
+        let client =
+            client::LspClient::from_transport(transport::LspTransport::new(client_r, client_w));
+        let mut completer = LspCompleter::with_client(client, test_config());
+        let mut server = BufReader::new(server);
+        let labels = |candidates: Vec<Candidate>| {
+            candidates
+                .into_iter()
+                .map(|c| c.insertion_text)
+                .collect::<Vec<_>>()
+        };
+
+        let pending = tokio::spawn(completer.request_completions(&test_request("f", 2)));
+        let request = read_message(&mut server).await;
+        respond(
+            server.get_mut(),
+            &request,
+            serde_json::json!({
+                "isIncomplete": false,
+                "items": [{ "label": "far" }, { "label": "foo" }],
+            }),
+        )
+        .await;
+        assert_eq!(labels(pending.await.unwrap().unwrap()), vec!["far", "foo"]);
+
+        // Answered from the cache, the server never sees this one
+        let cached = completer.request_completions(&test_request("fo", 3)).await;
+        assert_eq!(labels(cached.unwrap()), vec!["far", "foo"]);
+        assert!(
+            tokio::time::timeout(Duration::from_millis(10), read_message(&mut server))
+                .await
+                .is_err()
+        );
+
+        // Nor does a different word get the cached answer
+        let pending = tokio::spawn(completer.request_completions(&test_request("b", 2)));
+        let request = read_message(&mut server).await;
+        respond(
+            server.get_mut(),
+            &request,
+            serde_json::json!({
+                "isIncomplete": true,
+                "items": [{ "label": "bar" }],
+            }),
+        )
+        .await;
+        assert_eq!(labels(pending.await.unwrap().unwrap()), vec!["bar"]);
+
+        // Incomplete answers are not cached
+        let pending = tokio::spawn(completer.request_completions(&test_request("ba", 3)));
+        let request = read_message(&mut server).await;
+        respond(
+            server.get_mut(),
+            &request,
+            serde_json::json!([{ "label": "bar" }]),
+        )
+        .await;
+        pending.await.unwrap().unwrap();
+
+        // Buffer events drop the cache
+        completer.on_event(&test_event(Event::BufferVisit, "/foo.rs", "bar"));
+        let pending = tokio::spawn(completer.request_completions(&test_request("bar", 4)));
+        let request = read_message(&mut server).await;
+        respond(server.get_mut(), &request, serde_json::json!([])).await;
+        assert!(pending.await.unwrap().unwrap().is_empty());
+    }
+
+    #[test]
+    fn incremental_changes() {
+        let change = |old, new| {
+            let change = incremental_change(old, new).unwrap();
+            let range = change.range.unwrap();
+            (
+                (range.start.line, range.start.character),
+                (range.end.line, range.end.character),
+                change.text,
+            )
+        };
+        assert_eq!(
+            change("a\n é = 1;\n", "a\n é = 12;\n"),
+            ((1, 6), (1, 6), String::from("2"))
+        );
+        assert_eq!(change("aaa", "aa"), ((0, 2), (0, 3), String::new()));
+        assert_eq!(change("ab\ncd", "ad"), ((0, 1), (1, 1), String::new()));
+        assert_eq!(change("", "x"), ((0, 0), (0, 0), String::from("x")));
+        assert_eq!(change("éé", "éaé"), ((0, 1), (0, 1), String::from("a")));
+        assert!(incremental_change("same", "same").is_none());
+    }
+
+    #[tokio::test]
+    async fn incremental_did_change() {
+        let (client, server) = tokio::io::duplex(4096);
+        let (client_r, client_w) = tokio::io::split(client);
+        let client =
+            client::LspClient::from_transport(transport::LspTransport::new(client_r, client_w));
+        let mut completer = LspCompleter::with_client(client, test_config());
+        completer.capabilities = serde_json::from_value(serde_json::json!({
+            "textDocumentSync": { "openClose": true, "change": 2 },
+        }))
+        .unwrap();
+        let mut server = BufReader::new(server);
+
+        let contents = "fn main() {\n    let é = 1;\n}";
+        completer.on_event(&test_event(Event::FileReadyToParse, "/foo.rs", contents));
+        assert_eq!(
+            read_message(&mut server).await["method"],
+            "textDocument/didOpen"
+        );
+
+        let contents = "fn main() {\n    let é = 2;\n}";
+        completer.on_event(&test_event(Event::FileReadyToParse, "/foo.rs", contents));
+        let message = read_message(&mut server).await;
+        assert_eq!(message["method"], "textDocument/didChange");
+        assert_eq!(message["params"]["textDocument"]["version"], 2);
+        assert_eq!(
+            message["params"]["contentChanges"],
+            serde_json::json!([{
+                "range": {
+                    "start": { "line": 1, "character": 12 },
+                    "end": { "line": 1, "character": 13 },
+                },
+                "text": "2",
+            }])
+        );
+    }
+
+    #[tokio::test]
+    async fn buffer_unload_closes_document() {
+        let (client, server) = tokio::io::duplex(4096);
+        let (client_r, client_w) = tokio::io::split(client);
+        let client =
+            client::LspClient::from_transport(transport::LspTransport::new(client_r, client_w));
+        let mut completer = LspCompleter::with_client(client, test_config());
+        let mut server = BufReader::new(server);
+
+        completer.on_event(&test_event(Event::FileReadyToParse, "/foo.rs", "fn main"));
+        assert!(completer.is_document_open("/foo.rs"));
+        let message = read_message(&mut server).await;
+        assert_eq!(message["method"], "textDocument/didOpen");
+        assert_eq!(message["params"]["textDocument"]["uri"], "file:///foo.rs");
+
+        completer.on_event(&test_event(Event::BufferUnload, "/foo.rs", "fn main"));
+        assert!(!completer.is_document_open("/foo.rs"));
+        let message = read_message(&mut server).await;
+        assert_eq!(message["method"], "textDocument/didClose");
+        assert_eq!(message["params"]["textDocument"]["uri"], "file:///foo.rs");
+    }
+
+    #[tokio::test]
+    async fn file_save_notifies_server() {
+        let (client, server) = tokio::io::duplex(4096);
+        let (client_r, client_w) = tokio::io::split(client);
+        let client =
+            client::LspClient::from_transport(transport::LspTransport::new(client_r, client_w));
+        let mut completer = LspCompleter::with_client(client, test_config());
+        completer.capabilities = serde_json::from_value(serde_json::json!({
+            "textDocumentSync": { "openClose": true, "change": 1, "save": { "includeText": true } },
+        }))
+        .unwrap();
+        let mut server = BufReader::new(server);
+
+        completer.on_event(&test_event(Event::FileReadyToParse, "/foo.rs", "fn main"));
+        assert_eq!(
+            read_message(&mut server).await["method"],
+            "textDocument/didOpen"
+        );
+
+        completer.on_event(&test_event(Event::FileSave, "/foo.rs", "fn main() {}"));
+        assert_eq!(
+            read_message(&mut server).await["method"],
+            "textDocument/didChange"
+        );
+        let message = read_message(&mut server).await;
+        assert_eq!(message["method"], "textDocument/didSave");
+        assert_eq!(message["params"]["textDocument"]["uri"], "file:///foo.rs");
+        assert_eq!(message["params"]["text"], "fn main() {}");
+
+        // Without asking for the text the server only learns about the save
+        completer.capabilities = serde_json::from_value(serde_json::json!({
+            "textDocumentSync": { "openClose": true, "change": 1, "save": true },
+        }))
+        .unwrap();
+        completer.on_event(&test_event(Event::FileSave, "/foo.rs", "fn main() {}"));
+        let message = read_message(&mut server).await;
+        assert_eq!(message["method"], "textDocument/didSave");
+        assert!(message["params"].get("text").is_none());
+
+        // Nor is it told when it did not ask at all
+        completer.capabilities = serde_json::from_value(serde_json::json!({
+            "textDocumentSync": { "openClose": true, "change": 1 },
+        }))
+        .unwrap();
+        completer.on_event(&test_event(Event::FileSave, "/foo.rs", "fn main() {}"));
+        completer.on_event(&test_event(Event::BufferUnload, "/foo.rs", "fn main() {}"));
+        assert_eq!(
+            read_message(&mut server).await["method"],
+            "textDocument/didClose"
+        );
+    }
+
+    #[tokio::test]
+    async fn published_diagnostics() {
+        let (client, mut server) = tokio::io::duplex(4096);
+        let (client_r, client_w) = tokio::io::split(client);
+        let client =
+            client::LspClient::from_transport(transport::LspTransport::new(client_r, client_w));
+        let completer = LspCompleter::with_client(client, test_config());
+        let event = test_event(Event::FileReadyToParse, "/foo.rs", "é foo\nbar");
+        assert!(completer.diagnostics(&event).is_empty());
+
+        let notification = serde_json::json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/publishDiagnostics",
+            "params": {
+                "uri": "file:///foo.rs",
+                "diagnostics": [
+                    {
+                        "range": {
+                            "start": {"line": 0, "character": 2},
+                            "end": {"line": 0, "character": 5},
+                        },
+                        "severity": 2,
+                        "message": "unused",
+                    },
+                    {
+                        "range": {
+                            "start": {"line": 1, "character": 0},
+                            "end": {"line": 1, "character": 3},
+                        },
+                        "message": "unknown",
+                    },
+                ],
+            },
+        })
+        .to_string();
+        server
+            .write_all(
+                format!(
+                    "Content-Length: {}\r\n\r\n{}",
+                    notification.len(),
+                    notification
+                )
+                .as_bytes(),
+            )
+            .await
+            .unwrap();
+
+        let mut diagnostics = completer.diagnostics(&event);
+        while diagnostics.is_empty() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            diagnostics = completer.diagnostics(&event);
+        }
+        let kinds = diagnostics.iter().map(|d| d.kind()).collect::<Vec<_>>();
+        // Without severity the diagnostic is an error
+        assert_eq!(kinds, vec![DiagnosticKind::WARNING, DiagnosticKind::ERROR]);
+        // "é" is one UTF-16 code unit but two bytes
+        let first = serde_json::to_value(&diagnostics[0]).unwrap();
+        assert_eq!(
+            first["location"],
+            serde_json::json!({"line_num": 1, "column_num": 4, "filepath": "/foo.rs"})
+        );
+        // Other files have none
+        let other = test_event(Event::FileReadyToParse, "/bar.rs", "");
+        assert!(completer.diagnostics(&other).is_empty());
+    }
+}
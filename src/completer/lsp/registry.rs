@@ -0,0 +1,325 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use futures::future::BoxFuture;
+use log::{error, info};
+
+use super::super::CompletionConfig;
+use super::root::{resolve_root, RootResolution};
+use super::{LspCompleter, SHUTDOWN_GRACE};
+use crate::ycmd_types::Available;
+
+/// How to start a language server, as given in `language_server` option
+#[derive(serde::Deserialize, Clone, Debug)]
+pub struct LspServerConfig {
+    pub name: String,
+    pub filetypes: Vec<String>,
+    pub cmdline: Vec<String>,
+    pub port: Option<u32>,
+    /// Variables set for the server on top of the inherited environment
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+    /// Start the server with only `env`, without inheriting anything
+    #[serde(default)]
+    pub clear_env: bool,
+}
+
+/// Starts a server given its config and the root directory to initialize it with
+pub type Spawner = Arc<
+    dyn Fn(
+            LspServerConfig,
+            CompletionConfig,
+            PathBuf,
+        ) -> BoxFuture<'static, Result<LspCompleter, anyhow::Error>>
+        + Send
+        + Sync,
+>;
+
+pub enum ServerSlot {
+    Starting,
+    Running(Arc<Mutex<LspCompleter>>),
+    Failed,
+}
+
+/// Lazily started language servers, keyed by server name
+pub struct LspRegistry {
+    configs: Vec<LspServerConfig>,
+    config: CompletionConfig,
+    spawner: Spawner,
+    servers: Arc<Mutex<HashMap<String, ServerSlot>>>,
+    root_resolution: RootResolution,
+    /// Root each server was started with, keyed by server name
+    roots: Mutex<HashMap<String, PathBuf>>,
+}
+
+fn spawn_server(
+    server: LspServerConfig,
+    config: CompletionConfig,
+    root: PathBuf,
+) -> BoxFuture<'static, Result<LspCompleter, anyhow::Error>> {
+    Box::pin(async move { LspCompleter::new(&server, config, &root).await })
+}
+
+impl LspRegistry {
+    pub fn new(configs: Vec<LspServerConfig>, config: CompletionConfig) -> Self {
+        Self::with_spawner(configs, config, Arc::new(spawn_server))
+    }
+
+    pub fn with_spawner(
+        configs: Vec<LspServerConfig>,
+        config: CompletionConfig,
+        spawner: Spawner,
+    ) -> Self {
+        Self {
+            configs,
+            config,
+            spawner,
+            servers: Arc::default(),
+            root_resolution: RootResolution::default(),
+            roots: Mutex::default(),
+        }
+    }
+
+    /// Same registry, starting servers at roots chosen by `root_resolution`
+    pub fn with_root_resolution(self, root_resolution: RootResolution) -> Self {
+        Self {
+            root_resolution,
+            ..self
+        }
+    }
+
+    /// Server for `filetype`, unless it is blacklisted for it by its name or as `lsp`
+    pub fn server_config(&self, filetype: &str) -> Option<&LspServerConfig> {
+        let filetypes = [filetype.to_string()];
+        let blacklist = &self.config.completer_blacklist;
+        self.configs
+            .iter()
+            .find(|c| c.filetypes.iter().any(|f| f == filetype))
+            .filter(|c| {
+                !blacklist.disables(&c.name, &filetypes) && !blacklist.disables("lsp", &filetypes)
+            })
+    }
+
+    /// Start server for `filetype` in the background unless it was already attempted,
+    /// rooted at the project of `filepath` and named `lsp:<filetype>`. Returns
+    /// whether a new spawn was started
+    pub fn ensure_started(
+        &self,
+        filetype: &str,
+        filepath: &Path,
+        working_dir: Option<&Path>,
+    ) -> bool {
+        let server = match self.server_config(filetype) {
+            Some(s) => s.clone(),
+            None => return false,
+        };
+        {
+            let mut servers = self.servers.lock().unwrap();
+            if servers.contains_key(&server.name) {
+                return false;
+            }
+            servers.insert(server.name.clone(), ServerSlot::Starting);
+        }
+
+        let root = resolve_root(self.root_resolution, filepath, working_dir);
+        info!(
+            "Starting language server {} in {}",
+            server.name,
+            root.display()
+        );
+        self.roots
+            .lock()
+            .unwrap()
+            .insert(server.name.clone(), root.clone());
+        let servers = self.servers.clone();
+        let name = format!("lsp:{}", filetype);
+        let spawn = (self.spawner)(server.clone(), self.config.clone(), root);
+        tokio::spawn(async move {
+            let slot = match spawn.await {
+                Ok(completer) => {
+                    info!("Started language server {}", server.name);
+                    let completer = completer.named(name);
+                    ServerSlot::Running(Arc::new(Mutex::new(completer)))
+                }
+                Err(e) => {
+                    error!("Failed to start language server {}: {}", server.name, e);
+                    ServerSlot::Failed
+                }
+            };
+            servers.lock().unwrap().insert(server.name, slot);
+        });
+        true
+    }
+
+    /// Root the server for `filetype` was started with
+    pub fn root(&self, filetype: &str) -> Option<PathBuf> {
+        let name = &self.server_config(filetype)?.name;
+        self.roots.lock().unwrap().get(name).cloned()
+    }
+
+    /// Running completer serving `filetype`
+    pub fn completer(&self, filetype: &str) -> Option<Arc<Mutex<LspCompleter>>> {
+        let name = &self.server_config(filetype)?.name;
+        match self.servers.lock().unwrap().get(name) {
+            Some(ServerSlot::Running(c)) => Some(c.clone()),
+            _ => None,
+        }
+    }
+
+    /// Availability of each filetype some server is configured for:
+    /// `PENDING` while its server starts, `NO` if it failed to
+    pub fn filetype_availability(&self) -> HashMap<String, Available> {
+        let servers = self.servers.lock().unwrap();
+        self.configs
+            .iter()
+            .flat_map(|c| c.filetypes.iter())
+            .filter_map(|f| {
+                let available = match servers.get(&self.server_config(f)?.name) {
+                    Some(ServerSlot::Starting) => Available::PENDING,
+                    Some(ServerSlot::Failed) => Available::NO,
+                    Some(ServerSlot::Running(_)) | None => Available::YES,
+                };
+                Some((f.clone(), available))
+            })
+            .collect()
+    }
+
+    /// Shut down every running server. Doesn't borrow the registry, so it can be
+    /// awaited without holding any locks. Servers aren't restarted afterwards
+    pub fn shutdown(&self) -> BoxFuture<'static, ()> {
+        let clients = self
+            .servers
+            .lock()
+            .unwrap()
+            .iter_mut()
+            .filter_map(
+                |(name, slot)| match std::mem::replace(slot, ServerSlot::Failed) {
+                    ServerSlot::Running(c) => Some((name.clone(), c.lock().unwrap().client())),
+                    other => {
+                        *slot = other;
+                        None
+                    }
+                },
+            )
+            .collect::<Vec<_>>();
+        Box::pin(async move {
+            let shutdowns = clients.into_iter().map(|(name, client)| async move {
+                match client.shutdown(SHUTDOWN_GRACE).await {
+                    Ok(()) => info!("Stopped language server {}", name),
+                    Err(e) => error!("Failed to stop language server {}: {}", name, e),
+                }
+            });
+            futures::future::join_all(shutdowns).await;
+        })
+    }
+
+    pub fn running(&self) -> Vec<Arc<Mutex<LspCompleter>>> {
+        self.servers
+            .lock()
+            .unwrap()
+            .values()
+            .filter_map(|s| match s {
+                ServerSlot::Running(c) => Some(c.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::super::{client::LspClient, transport::LspTransport};
+    use super::*;
+    use crate::completer::tests::test_config;
+    use crate::completer::Completer;
+
+    #[tokio::test]
+    async fn ensure_started_spawns_once() {
+        let spawned = Arc::new(AtomicUsize::new(0));
+        let counter = spawned.clone();
+        let registry = LspRegistry::with_spawner(
+            vec![LspServerConfig {
+                name: String::from("rust-analyzer"),
+                filetypes: vec![String::from("rust")],
+                cmdline: vec![String::from("rust-analyzer")],
+                port: None,
+                env: HashMap::default(),
+                clear_env: false,
+            }],
+            test_config(),
+            Arc::new(move |_, _, _| {
+                counter.fetch_add(1, Ordering::SeqCst);
+                Box::pin(async { Err(anyhow::anyhow!("no server in tests")) })
+            }),
+        );
+
+        let filepath = Path::new("/foo.rs");
+        assert!(!registry.ensure_started("python", filepath, None));
+        assert!(registry.ensure_started("rust", filepath, None));
+        assert!(!registry.ensure_started("rust", filepath, None));
+        tokio::task::yield_now().await;
+        assert!(!registry.ensure_started("rust", filepath, None));
+        assert_eq!(spawned.load(Ordering::SeqCst), 1);
+        assert!(registry.completer("rust").is_none());
+    }
+
+    #[tokio::test]
+    async fn started_completer_named_after_server() {
+        let registry = LspRegistry::with_spawner(
+            vec![LspServerConfig {
+                name: String::from("rust-analyzer"),
+                filetypes: vec![String::from("rust")],
+                cmdline: vec![String::from("rust-analyzer")],
+                port: None,
+                env: HashMap::default(),
+                clear_env: false,
+            }],
+            test_config(),
+            Arc::new(|_, config, _| {
+                let (_server, client) = tokio::io::duplex(4096);
+                let (r, w) = tokio::io::split(client);
+                let client = LspClient::from_transport(LspTransport::new(r, w));
+                Box::pin(async move { Ok(LspCompleter::with_client(client, config)) })
+            }),
+        );
+
+        assert!(registry.ensure_started("rust", Path::new("/foo.rs"), None));
+        tokio::task::yield_now().await;
+        let completer = registry.completer("rust").unwrap();
+        assert_eq!(completer.lock().unwrap().name(), "lsp:rust");
+    }
+
+    #[tokio::test]
+    async fn server_started_at_project_root() {
+        let dir = tempfile::tempdir().unwrap();
+        let project = dir.path().join("project");
+        std::fs::create_dir_all(project.join(".git")).unwrap();
+        std::fs::create_dir_all(project.join("src")).unwrap();
+        let roots = Arc::new(Mutex::new(vec![]));
+        let spawned_roots = roots.clone();
+        let registry = LspRegistry::with_spawner(
+            vec![LspServerConfig {
+                name: String::from("rust-analyzer"),
+                filetypes: vec![String::from("rust")],
+                cmdline: vec![String::from("rust-analyzer")],
+                port: None,
+                env: HashMap::default(),
+                clear_env: false,
+            }],
+            test_config(),
+            Arc::new(move |_, _, root| {
+                spawned_roots.lock().unwrap().push(root);
+                Box::pin(async { Err(anyhow::anyhow!("no server in tests")) })
+            }),
+        )
+        .with_root_resolution(RootResolution::Markers);
+
+        let filepath = project.join("src").join("main.rs");
+        assert!(registry.ensure_started("rust", &filepath, Some(dir.path())));
+        assert_eq!(*roots.lock().unwrap(), vec![project.clone()]);
+        assert_eq!(registry.root("rust"), Some(project));
+    }
+}
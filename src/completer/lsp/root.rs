@@ -0,0 +1,74 @@
+use std::path::{Path, PathBuf};
+
+/// Files found at the root of a project
+pub const ROOT_MARKERS: &[&str] = &[".git", "Cargo.toml", "package.json"];
+
+/// How the root directory a language server is initialized with is chosen
+#[derive(serde::Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum RootResolution {
+    /// Closest directory holding one of `ROOT_MARKERS`, from the file's one up
+    Markers,
+    /// Working directory the editor sent along with the event
+    WorkingDir,
+    /// Directory of the file
+    FileDirectory,
+}
+
+// `#[default]` on variants needs a newer compiler
+#[allow(clippy::derivable_impls)]
+impl Default for RootResolution {
+    fn default() -> Self {
+        RootResolution::Markers
+    }
+}
+
+/// Root for a server started for `filepath`, the directory of the file when
+/// `strategy` comes up with nothing
+pub fn resolve_root(
+    strategy: RootResolution,
+    filepath: &Path,
+    working_dir: Option<&Path>,
+) -> PathBuf {
+    let directory = filepath.parent().unwrap_or(filepath);
+    let root = match strategy {
+        RootResolution::Markers => directory
+            .ancestors()
+            .find(|d| ROOT_MARKERS.iter().any(|m| d.join(m).exists())),
+        RootResolution::WorkingDir => working_dir,
+        RootResolution::FileDirectory => None,
+    };
+    root.unwrap_or(directory).to_path_buf()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn root_of_project_tree() {
+        let dir = tempfile::tempdir().unwrap();
+        let project = dir.path().join("project");
+        let source_dir = project.join("src").join("nested");
+        std::fs::create_dir_all(project.join(".git")).unwrap();
+        std::fs::create_dir_all(&source_dir).unwrap();
+        let filepath = source_dir.join("main.rs");
+        let elsewhere = dir.path().join("elsewhere");
+
+        let root = |strategy, working_dir| resolve_root(strategy, &filepath, working_dir);
+        assert_eq!(root(RootResolution::Markers, None), project);
+        assert_eq!(root(RootResolution::FileDirectory, None), source_dir);
+        assert_eq!(
+            root(RootResolution::WorkingDir, Some(elsewhere.as_path())),
+            elsewhere
+        );
+        assert_eq!(root(RootResolution::WorkingDir, None), source_dir);
+
+        // Without any marker up the tree there is only the file's directory
+        let loose = dir.path().join("loose.rs");
+        assert_eq!(
+            resolve_root(RootResolution::Markers, &loose, None),
+            dir.path()
+        );
+    }
+}
@@ -1,25 +1,252 @@
-use std::collections::HashMap;
 use std::sync::Arc;
 
 use log::{debug, error};
 
-use bytes::{Bytes, BytesMut};
+use bytes::{Buf, Bytes, BytesMut};
 use sharded_slab::Slab;
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
-use tokio::sync::{mpsc, oneshot};
+use tokio::sync::{mpsc, oneshot, watch};
 
 use jsonrpc_core::types as jrpc_types;
 
+use crate::correlation;
+
+/// Largest message read from a server by default, bigger ones are skipped
+pub const MAX_MESSAGE_SIZE: usize = 64 * 1024 * 1024;
+
+/// Error code of requests cancelled by the client, as defined by the protocol
+pub const REQUEST_CANCELLED: i64 = -32800;
+
+/// Where the decoder is within the current message
+enum FrameState {
+    /// Reading header lines, with the content length seen so far and
+    /// why the content can't be read, if it can't
+    Headers {
+        content_len: Option<usize>,
+        unreadable: Option<String>,
+    },
+    /// Waiting for this many bytes of content
+    Content {
+        len: usize,
+        unreadable: Option<String>,
+    },
+    /// Dropping this many more bytes of a message too big to buffer
+    Discarding { remaining: usize, reason: String },
+}
+
+impl Default for FrameState {
+    fn default() -> Self {
+        FrameState::Headers {
+            content_len: None,
+            unreadable: None,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Frame {
+    Message(Bytes),
+    /// Complete message whose content can't be read, and why
+    Skipped(String),
+}
+
+/// Charset given in a `Content-Type` header value, if it isn't UTF-8
+fn unsupported_charset(content_type: &str) -> Option<String> {
+    let charset = content_type
+        .split(';')
+        .skip(1)
+        .filter_map(|param| param.split_once('='))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("charset"))
+        .map(|(_, value)| value.trim().trim_matches('"').to_ascii_lowercase())?;
+    // `utf8` is accepted for backwards compatibility, as the spec says
+    match charset.as_str() {
+        "utf-8" | "utf8" => None,
+        _ => Some(charset),
+    }
+}
+
+/// Splits a byte stream into the contents of `Content-Length` framed messages.
+/// Data is read into [`FrameDecoder::buffer`] and may end anywhere, including
+/// in the middle of a header or spanning several messages
+struct FrameDecoder {
+    buf: BytesMut,
+    state: FrameState,
+    max_message_size: usize,
+}
+
+impl Default for FrameDecoder {
+    fn default() -> Self {
+        Self::new(MAX_MESSAGE_SIZE)
+    }
+}
+
+impl FrameDecoder {
+    /// Decoder skipping messages with content longer than `max_message_size`
+    fn new(max_message_size: usize) -> Self {
+        Self {
+            buf: BytesMut::with_capacity(16535),
+            state: FrameState::default(),
+            max_message_size,
+        }
+    }
+
+    /// Buffer to append data read from the stream to
+    fn buffer(&mut self) -> &mut BytesMut {
+        &mut self.buf
+    }
+
+    /// Next complete message, `None` if more data is needed.
+    /// Errors mean the stream can't be framed any further
+    fn decode(&mut self) -> Result<Option<Frame>, anyhow::Error> {
+        loop {
+            match &mut self.state {
+                FrameState::Headers {
+                    content_len,
+                    unreadable,
+                } => {
+                    let newline = match self.buf.iter().position(|b| *b == b'\n') {
+                        Some(n) => n,
+                        None => return Ok(None),
+                    };
+                    let line = self.buf.split_to(newline + 1);
+                    let line = std::str::from_utf8(&line)?.trim();
+                    if line.is_empty() {
+                        let len = content_len
+                            .ok_or_else(|| anyhow::anyhow!("Missing Content-Length header"))?;
+                        self.state = if len > self.max_message_size {
+                            FrameState::Discarding {
+                                remaining: len,
+                                reason: format!(
+                                    "Content-Length {} exceeds the limit of {}",
+                                    len, self.max_message_size
+                                ),
+                            }
+                        } else {
+                            FrameState::Content {
+                                len,
+                                unreadable: unreadable.take(),
+                            }
+                        };
+                        continue;
+                    }
+                    let (name, value) = line
+                        .split_once(':')
+                        .ok_or_else(|| anyhow::anyhow!("Invalid header: {:?}", line))?;
+                    let name = name.trim();
+                    if name.eq_ignore_ascii_case("Content-Length") {
+                        *content_len = Some(value.trim().parse()?);
+                    } else if name.eq_ignore_ascii_case("Content-Type") {
+                        *unreadable = unsupported_charset(value)
+                            .map(|charset| format!("Unsupported charset {:?}", charset));
+                    }
+                }
+                FrameState::Content { len, unreadable } => {
+                    let len = *len;
+                    if self.buf.len() < len {
+                        self.buf.reserve(len - self.buf.len());
+                        return Ok(None);
+                    }
+                    let content = self.buf.split_to(len).freeze();
+                    let frame = match unreadable.take() {
+                        Some(reason) => Frame::Skipped(reason),
+                        None => Frame::Message(content),
+                    };
+                    self.state = FrameState::default();
+                    return Ok(Some(frame));
+                }
+                FrameState::Discarding { remaining, reason } => {
+                    let discarded = (*remaining).min(self.buf.len());
+                    self.buf.advance(discarded);
+                    *remaining -= discarded;
+                    if *remaining > 0 {
+                        return Ok(None);
+                    }
+                    let frame = Frame::Skipped(std::mem::take(reason));
+                    self.state = FrameState::default();
+                    return Ok(Some(frame));
+                }
+            }
+        }
+    }
+}
+
+/// Route a message from the server to whoever awaits it
+async fn dispatch(
+    content: &[u8],
+    response_channels: &Slab<oneshot::Sender<jrpc_types::Output>>,
+    server_requests: &mpsc::Sender<jrpc_types::Call>,
+) {
+    let output: serde_json::Result<jrpc_types::Output> = serde_json::from_slice(content);
+    match output {
+        Ok(output) => match output.id() {
+            jsonrpc_core::Id::Num(n) => {
+                //response
+                match response_channels.take(*n as usize) {
+                    Some(c) => {
+                        // Fails only if the request was cancelled in the meantime
+                        let _ = c.send(output);
+                    }
+                    None => {
+                        error!(
+                            "Got response from lsp with unknown id: '{}', response: {:?}",
+                            n, output
+                        );
+                    }
+                }
+            }
+            _ => {
+                error!(
+                    "Got response from lsp with unsupported id, response: {:?}",
+                    output
+                );
+            }
+        },
+
+        Err(_) => {
+            let call: serde_json::Result<jrpc_types::Call> = serde_json::from_slice(content);
+            match call {
+                Ok(call) => {
+                    debug!("Sending call from server from bg task: {:?}", call);
+                    server_requests.send(call).await.unwrap()
+                }
+                Err(_) => {
+                    error!(
+                        "Failed to decode message from server: {:?}",
+                        std::str::from_utf8(content)
+                    );
+                }
+            }
+        }
+    };
+}
+
 /// Object responsible for multiplexing requests, dispatching responses and notifications
 pub struct LspTransport {
     response_channels: Arc<Slab<oneshot::Sender<jrpc_types::Output>>>,
-    server_requests: mpsc::Receiver<jrpc_types::Call>,
+    /// `None` once taken, see `take_server_requests`
+    server_requests: Option<mpsc::Receiver<jrpc_types::Call>>,
     client_requests: mpsc::Sender<jrpc_types::Call>,
+    /// Becomes true once reading from or writing to the server failed
+    failed: watch::Receiver<bool>,
 }
 
 impl LspTransport {
     /// Create a new LSP Transport from read/write streams
-    pub fn new<R, W>(mut stream_in: R, mut stream_out: W) -> Self
+    pub fn new<R, W>(stream_in: R, stream_out: W) -> Self
+    where
+        R: AsyncRead + Unpin + Send + 'static,
+        W: AsyncWrite + Unpin + Send + 'static,
+    {
+        Self::with_max_message_size(stream_in, stream_out, MAX_MESSAGE_SIZE)
+    }
+
+    /// Same as `new`, skipping messages from the server with content
+    /// longer than `max_message_size` instead of buffering them
+    pub fn with_max_message_size<R, W>(
+        mut stream_in: R,
+        mut stream_out: W,
+        max_message_size: usize,
+    ) -> Self
     where
         R: AsyncRead + Unpin + Send + 'static,
         W: AsyncWrite + Unpin + Send + 'static,
@@ -29,143 +256,127 @@ impl LspTransport {
         let (client_requests_sender, mut client_requests_receiver) = mpsc::channel(1024);
 
         let response_channels = Arc::default();
+        let (failed_sender, failed) = watch::channel(false);
+        let failed_sender = Arc::new(failed_sender);
 
         let result = Self {
-            server_requests: server_requests_receiver,
+            server_requests: Some(server_requests_receiver),
             client_requests: client_requests_sender,
             response_channels,
+            failed,
         };
 
         let response_channels = result.response_channels.clone();
+        let reader_failed = failed_sender.clone();
 
         // Spawn reader
         tokio::spawn(async move {
-            // NOTE: we could use BufReader which implements AsyncBufRead and AsyncBufReadExt that
-            // has read_line. However it seems like it'll be more memcopy and I already did this
-            // one
-            let mut buf = BytesMut::with_capacity(16535);
-            #[allow(clippy::mutable_key_type)]
-            let mut headers: HashMap<Bytes, Bytes> = HashMap::default();
-            let content_len_key = Bytes::from("Content-Length".as_bytes());
+            let mut decoder = FrameDecoder::new(max_message_size);
             loop {
-                /* each message */
-                let mut last_checked_index = 0;
-                loop {
-                    /* each header */
-                    let newline_offset = buf[last_checked_index..].iter().position(|b| *b == b'\n');
-
-                    if let Some(n) = newline_offset {
-                        let newline_index = last_checked_index + n;
-                        last_checked_index = 0;
-                        let mut value = buf.split_to(newline_index + 1).split_to(newline_index - 1);
-
-                        if value.is_empty() {
-                            // This is `/r/n` line, end of headers
-                            break;
-                        }
-                        let sep_index = value.iter().position(|b| *b == b':').unwrap();
-                        let name = value.split_to(sep_index + 1).split_to(sep_index);
-
-                        headers.insert(name.freeze(), value.freeze());
-                    } else {
-                        last_checked_index = buf.len();
+                match decoder.decode() {
+                    Ok(Some(Frame::Message(content))) => {
+                        dispatch(&content, &response_channels, &server_requests_sender).await
                     }
-
-                    if last_checked_index >= buf.len()
-                        && stream_in.read_buf(&mut buf).await.unwrap() == 0
-                    {
-                        return;
+                    Ok(Some(Frame::Skipped(reason))) => {
+                        error!("Skipped message from lsp server: {}", reason);
                     }
-                }
-                let content_len: usize =
-                    std::str::from_utf8(headers.get(&content_len_key).unwrap())
-                        .unwrap()
-                        .trim()
-                        .parse()
-                        .unwrap();
-
-                if buf.capacity() < content_len {
-                    buf.reserve(content_len - buf.capacity());
-                }
-
-                while buf.len() < content_len {
-                    stream_in.read_buf(&mut buf).await.unwrap();
-                }
-
-                headers.clear();
-                let content = buf.split_to(content_len);
-                let output: serde_json::Result<jrpc_types::Output> =
-                    serde_json::from_slice(&content[..]);
-                match output {
-                    Ok(output) => match output.id() {
-                        jsonrpc_core::Id::Num(n) => {
-                            //response
-                            match response_channels.take(*n as usize) {
-                                Some(c) => {
-                                    c.send(output).unwrap();
-                                }
-                                None => {
-                                    error!(
-                                    "Got response from lsp with unknown id: '{}', response: {:?}",
-                                    n, output
-                                );
-                                }
-                            }
-                        }
-                        _ => {
-                            error!(
-                                "Got response from lsp with unsupported id, response: {:?}",
-                                output
-                            );
+                    Ok(None) => match stream_in.read_buf(decoder.buffer()).await {
+                        Ok(0) => break,
+                        Ok(_) => {}
+                        Err(e) => {
+                            error!("Failed to read from lsp server: {}", e);
+                            break;
                         }
                     },
-
-                    Err(_) => {
-                        let call: serde_json::Result<jrpc_types::Call> =
-                            serde_json::from_slice(&content[..]);
-                        match call {
-                            Ok(call) => {
-                                debug!("Sending call from server from bg task: {:?}", call);
-                                server_requests_sender.send(call).await.unwrap()
-                            }
-                            Err(_) => {
-                                error!(
-                                    "Failed to decode message from server: {:?}",
-                                    std::str::from_utf8(&content[..])
-                                );
-                            }
-                        }
+                    Err(e) => {
+                        error!("Malformed message from lsp server: {}", e);
+                        break;
                     }
-                };
+                }
             }
+            // Nothing will answer pending calls anymore
+            let _ = reader_failed.send(true);
         });
 
-        // Spawn writer
+        // Spawn writer. Flushing each message keeps a slow server from having
+        // unbounded data queued up for it, the channel holds the rest
         tokio::spawn(async move {
             while let Some(request) = client_requests_receiver.recv().await {
                 let bytes = serde_json::to_vec(&request).unwrap();
                 let headers = format!("Content-Length: {}\r\n\r\n", bytes.len());
-                stream_out.write_all(headers.as_bytes()).await.unwrap();
-                stream_out.write_all(&bytes).await.unwrap();
+                let written = async {
+                    stream_out.write_all(headers.as_bytes()).await?;
+                    stream_out.write_all(&bytes).await?;
+                    stream_out.flush().await
+                };
+                if let Err(e) = written.await {
+                    error!("Failed to write to lsp server: {}", e);
+                    let _ = failed_sender.send(true);
+                    return;
+                }
             }
         });
 
         result
     }
 
+    /// Queue `request` for writing, it is dropped if the writer already failed
     async fn write_request(&self, request: jsonrpc_core::types::Call) {
-        self.client_requests.send(request).await.unwrap()
+        if self.client_requests.send(request).await.is_err() {
+            error!("Lsp server connection is closed, dropping message");
+        }
     }
 
-    /// Read next notification
+    /// Resolves once the connection to the server failed
+    async fn failure(&self) {
+        let mut failed = self.failed.clone();
+        while !*failed.borrow() {
+            if failed.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+
+    /// Read next notification, `None` once the requests were taken
     pub async fn read_requests_from_server(&mut self) -> Option<jrpc_types::Call> {
-        self.server_requests.recv().await
+        self.server_requests.as_mut()?.recv().await
+    }
+
+    /// Requests and notifications from the server, for a task to handle them.
+    /// They must be read as the reader stops dispatching once the channel is full
+    pub fn take_server_requests(&mut self) -> Option<mpsc::Receiver<jrpc_types::Call>> {
+        self.server_requests.take()
     }
 
-    /// Send request returning awaitable result
+    /// Send request returning awaitable result.
+    /// Dropping the future before the response arrives cancels the request.
+    /// If the connection fails first, this resolves to an internal error
     pub async fn call(&self, method: String, params: jrpc_types::Params) -> jrpc_types::Output {
+        self.call_with_id(method, params, |_| {}).await
+    }
+
+    /// Like [`LspTransport::call`], handing the id of the request to `on_id`
+    /// before it is sent, for [`LspTransport::cancel`]
+    pub async fn call_with_id(
+        &self,
+        method: String,
+        params: jrpc_types::Params,
+        on_id: impl FnOnce(usize),
+    ) -> jrpc_types::Output {
         let (sender, receiver) = oneshot::channel();
         let id = self.response_channels.insert(sender).unwrap();
+        debug!(
+            "[{}] Calling {} with id {}",
+            correlation::current(),
+            method,
+            id
+        );
+        on_id(id);
+        let mut pending = PendingCall {
+            transport: self,
+            id,
+            answered: false,
+        };
 
         let request = jrpc_types::Call::MethodCall(jrpc_types::MethodCall {
             jsonrpc: Some(jrpc_types::Version::V2),
@@ -175,11 +386,61 @@ impl LspTransport {
         });
 
         self.write_request(request).await;
-        receiver.await.unwrap()
+        // The response channel is only dropped unanswered by `cancel`
+        let output = tokio::select! {
+            biased;
+            output = receiver => output.map_err(|_| {
+                (jsonrpc_core::ErrorCode::ServerError(REQUEST_CANCELLED), "Request cancelled")
+            }),
+            _ = self.failure() => Err((
+                jsonrpc_core::ErrorCode::InternalError,
+                "Connection to lsp server failed",
+            )),
+        };
+        pending.answered = true;
+        match output {
+            Ok(output) => {
+                debug!("[{}] Got response for id {}", correlation::current(), id);
+                output
+            }
+            Err((code, message)) => {
+                self.response_channels.remove(id);
+                jrpc_types::Output::Failure(jrpc_types::Failure {
+                    jsonrpc: Some(jrpc_types::Version::V2),
+                    error: jsonrpc_core::Error {
+                        code,
+                        message: String::from(message),
+                        data: None,
+                    },
+                    id: jrpc_types::Id::Num(id as u64),
+                })
+            }
+        }
+    }
+
+    /// Stop waiting for the response to request `id` and ask the server to stop
+    /// working on it. The call resolves to a `REQUEST_CANCELLED` error
+    pub fn cancel(&self, id: usize) {
+        if self.response_channels.take(id).is_some() {
+            self.notify_cancel(id);
+        }
+    }
+
+    /// Queue `$/cancelRequest` for request `id`
+    fn notify_cancel(&self, id: usize) {
+        let mut params = serde_json::Map::new();
+        params.insert(String::from("id"), id.into());
+        if let Err(e) = self.try_notify(
+            "$/cancelRequest".to_string(),
+            jrpc_types::Params::Map(params),
+        ) {
+            error!("Failed to cancel request {}: {}", id, e);
+        }
     }
 
     /// Notify server
     pub async fn notify(&self, method: String, params: jrpc_types::Params) {
+        debug!("[{}] Notifying {}", correlation::current(), method);
         let request = jrpc_types::Call::Notification(jrpc_types::Notification {
             jsonrpc: Some(jrpc_types::Version::V2),
             method,
@@ -188,6 +449,42 @@ impl LspTransport {
 
         self.write_request(request).await;
     }
+
+    /// Notify server without waiting, fails if the outgoing queue is full.
+    /// Notifications queued this way keep their relative order
+    pub fn try_notify(
+        &self,
+        method: String,
+        params: jrpc_types::Params,
+    ) -> Result<(), anyhow::Error> {
+        debug!("[{}] Notifying {}", correlation::current(), method);
+        let request = jrpc_types::Call::Notification(jrpc_types::Notification {
+            jsonrpc: Some(jrpc_types::Version::V2),
+            method,
+            params,
+        });
+
+        Ok(self.client_requests.try_send(request)?)
+    }
+}
+
+/// Forgets the response slot of an abandoned call and asks the server to stop working on it
+struct PendingCall<'a> {
+    transport: &'a LspTransport,
+    id: usize,
+    answered: bool,
+}
+
+impl Drop for PendingCall<'_> {
+    fn drop(&mut self) {
+        if self.answered {
+            return;
+        }
+        // Already taken if the request was cancelled through `cancel`
+        if self.transport.response_channels.remove(self.id) {
+            self.transport.notify_cancel(self.id);
+        }
+    }
 }
 
 #[cfg(test)]
@@ -236,6 +533,186 @@ mod tests {
         assert_eq!(buf, expected_buf);
     }
 
+    #[tokio::test]
+    async fn test_oversized_message_skipped() {
+        let (client, mut server) = tokio::io::duplex(64);
+        let (client_r, client_w) = tokio::io::split(client);
+        let mut lsp = LspTransport::with_max_message_size(client_r, client_w, 64);
+
+        let notification = jrpc_types::Notification {
+            jsonrpc: Some(jrpc_types::Version::V2),
+            method: "method".to_string(),
+            params: jrpc_types::Params::None,
+        };
+        let data = frame(&format!("{{\"padding\":\"{}\"}}", "x".repeat(1000)))
+            + &frame(&serde_json::to_string(&notification).unwrap());
+        // Much more than the pipe holds, write while the transport reads
+        tokio::spawn(async move { server.write_all(data.as_bytes()).await.unwrap() });
+
+        assert_eq!(
+            lsp.read_requests_from_server().await.unwrap(),
+            jrpc_types::Call::Notification(notification)
+        );
+    }
+
+    #[test]
+    fn test_decode_oversized_split_across_reads() {
+        let data = (frame(&"x".repeat(100)) + &frame("{\"b\":2}")).into_bytes();
+        let mut decoder = FrameDecoder::new(10);
+        let mut frames = vec![];
+        for chunk in data.chunks(7) {
+            decoder.buffer().extend_from_slice(chunk);
+            frames.extend(decode_all(&mut decoder));
+            assert!(decoder.buffer().len() < 32);
+        }
+        assert_eq!(
+            frames,
+            vec![
+                Frame::Skipped(String::from("Content-Length 100 exceeds the limit of 10")),
+                message("{\"b\":2}"),
+            ]
+        );
+    }
+
+    fn frame(content: &str) -> String {
+        format!("Content-Length: {}\r\n\r\n{}", content.len(), content)
+    }
+
+    fn decode_all(decoder: &mut FrameDecoder) -> Vec<Frame> {
+        std::iter::from_fn(|| decoder.decode().unwrap()).collect()
+    }
+
+    fn message(content: &'static str) -> Frame {
+        Frame::Message(Bytes::from(content))
+    }
+
+    #[test]
+    fn test_decode_messages_in_one_read() {
+        let mut decoder = FrameDecoder::default();
+        decoder
+            .buffer()
+            .extend_from_slice((frame("{\"a\":1}") + &frame("{\"b\":2}")).as_bytes());
+        assert_eq!(
+            decode_all(&mut decoder),
+            vec![message("{\"a\":1}"), message("{\"b\":2}")]
+        );
+    }
+
+    #[test]
+    fn test_decode_message_split_across_reads() {
+        let data = (frame("{\"a\":1}") + &frame("{\"b\":2}")).into_bytes();
+        // Split everywhere, including inside headers and their line terminators
+        for split in 0..data.len() {
+            let mut decoder = FrameDecoder::default();
+            decoder.buffer().extend_from_slice(&data[..split]);
+            let mut messages = decode_all(&mut decoder);
+            decoder.buffer().extend_from_slice(&data[split..]);
+            messages.extend(decode_all(&mut decoder));
+            assert_eq!(
+                messages,
+                vec![message("{\"a\":1}"), message("{\"b\":2}")],
+                "split at {}",
+                split
+            );
+        }
+    }
+
+    #[test]
+    fn test_decode_trailing_partial_headers() {
+        let mut decoder = FrameDecoder::default();
+        let data = frame("{}") + "Content-Type: application/vscode-jsonrpc\r\nContent-Le";
+        decoder.buffer().extend_from_slice(data.as_bytes());
+        assert_eq!(decode_all(&mut decoder), vec![message("{}")]);
+
+        decoder.buffer().extend_from_slice(b"ngth: 3\r\n\r\n[1]");
+        assert_eq!(decode_all(&mut decoder), vec![message("[1]")]);
+    }
+
+    #[test]
+    fn test_decode_content_type() {
+        let mut decoder = FrameDecoder::default();
+        let data = "Content-Length: 2\r\nContent-Type: application/vscode-jsonrpc; charset=utf-8\r\n\r\n{}"
+            .to_string()
+            + "Content-Type: application/vscode-jsonrpc; charset=utf8\r\nContent-Length: 2\r\n\r\n[]"
+            + "Content-Length: 2\r\nContent-Type: application/vscode-jsonrpc; charset=\"latin1\"\r\n\r\n{}"
+            + &frame("[1]");
+        decoder.buffer().extend_from_slice(data.as_bytes());
+        assert_eq!(
+            decode_all(&mut decoder),
+            vec![
+                message("{}"),
+                message("[]"),
+                Frame::Skipped(String::from("Unsupported charset \"latin1\"")),
+                message("[1]"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_decode_missing_content_length() {
+        let mut decoder = FrameDecoder::default();
+        decoder.buffer().extend_from_slice(b"Foo: bar\r\n\r\n{}");
+        assert!(decoder.decode().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_notifications_in_one_write() {
+        let (client, mut server) = tokio::io::duplex(4096);
+        let (client_r, client_w) = tokio::io::split(client);
+        let mut lsp = LspTransport::new(client_r, client_w);
+
+        let notification = |method: &str| jrpc_types::Notification {
+            jsonrpc: Some(jrpc_types::Version::V2),
+            method: method.to_string(),
+            params: jrpc_types::Params::None,
+        };
+        let data = frame(&serde_json::to_string(&notification("first")).unwrap())
+            + &frame(&serde_json::to_string(&notification("second")).unwrap());
+        server.write_all(data.as_bytes()).await.unwrap();
+
+        for method in ["first", "second"] {
+            assert_eq!(
+                jrpc_types::Call::Notification(notification(method)),
+                lsp.read_requests_from_server().await.unwrap()
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_failed_write_resolves_pending_calls() {
+        let (client_r, _server_w) = tokio::io::duplex(4096);
+        let (client_w, mut server_r) = tokio::io::duplex(4096);
+        let lsp = Arc::new(LspTransport::new(client_r, client_w));
+
+        let caller = lsp.clone();
+        let first = tokio::spawn(async move {
+            caller
+                .call("first".to_string(), jrpc_types::Params::None)
+                .await
+        });
+        // Wait for the first request to go out before closing the write side
+        let mut buf = [0; 16];
+        server_r.read_exact(&mut buf).await.unwrap();
+        drop(server_r);
+
+        let second = lsp.call("second".to_string(), jrpc_types::Params::None);
+        let second = tokio::time::timeout(std::time::Duration::from_secs(5), second)
+            .await
+            .unwrap();
+        let first = tokio::time::timeout(std::time::Duration::from_secs(5), first)
+            .await
+            .unwrap()
+            .unwrap();
+        for output in [first, second] {
+            match output {
+                jrpc_types::Output::Failure(f) => {
+                    assert_eq!(f.error.code, jsonrpc_core::ErrorCode::InternalError)
+                }
+                _ => panic!("Expected failure, got {:?}", output),
+            }
+        }
+    }
+
     #[tokio::test]
     async fn test_request_response() {
         let (client, mut server) = tokio::io::duplex(4096);
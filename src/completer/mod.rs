@@ -1,18 +1,37 @@
 use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 
+use futures::future::BoxFuture;
+use futures::{FutureExt, TryFutureExt};
 use regex::RegexSet;
+use serde::Serialize;
 
 pub mod filename;
+pub mod identifier;
 pub mod lsp;
+pub mod registry;
+pub mod timing;
 pub mod trigger;
 pub mod ultisnips;
 
-use crate::core::query::filter_and_sort_generic_candidates;
+use crate::core::character::Segmentation;
+use crate::core::query::{
+    filter_and_sort_generic_candidates_with_metadata, merge_ranked_generic_candidates, EmptyOrder,
+    FilterOptions, ScoringWeights,
+};
 
 use filename::FilenameCompleter;
+use lsp::registry::LspRegistry;
+use lsp::LspCompleter;
+use timing::CompleterTimings;
 
-use super::ycmd_types::{Candidate, EventNotification, SimpleRequest};
-use trigger::PatternMatcher;
+use super::ycmd_types::{
+    Available, Candidate, CompleterDecision, DiagnosticData, Event, EventNotification,
+    ExceptionResponse, Fixit, GoToLocation, SimpleRequest,
+};
+use trigger::{MatchContext, PatternMatcher};
 
 #[derive(Clone)]
 pub struct CompletionConfig {
@@ -22,6 +41,229 @@ pub struct CompletionConfig {
     pub signature_triggers: HashMap<String, RegexSet>,
     pub max_candidates: usize,
     pub max_candidates_to_detail: isize,
+    pub scoring_weights: ScoringWeights,
+    pub completer_blacklist: CompleterBlacklist,
+    /// Where identifiers are kept across restarts, if anywhere
+    pub identifier_cache: Option<PathBuf>,
+    /// Order of the candidates when there is no query to rank them by
+    pub empty_query_order: EmptyOrder,
+    /// Units queries and candidates are matched in
+    pub segmentation: Segmentation,
+    /// Also collect identifiers from every other file sent along with events,
+    /// e.g. unsaved buffers, not only from the current one
+    pub collect_identifiers_from_all_files: bool,
+    /// Put deprecated candidates after all others, keeping the ranking otherwise
+    pub sink_deprecated: bool,
+    /// Identifiers shorter than this many characters aren't collected
+    pub min_identifier_length: usize,
+    /// Leave out candidates whose insertion text is exactly the query, case
+    /// included, as it is already typed in full
+    pub drop_exact_matches: bool,
+    /// Longer insertion texts are cut to this many characters, 0 for no limit
+    pub max_candidate_length: usize,
+}
+
+/// Completers disabled per filetype, keyed by completer name.
+/// A `*` filetype disables the completer everywhere
+#[derive(Clone, Default, Debug)]
+pub struct CompleterBlacklist(HashMap<String, Vec<String>>);
+
+impl CompleterBlacklist {
+    pub fn new(blacklist: HashMap<String, Vec<String>>) -> Self {
+        Self(blacklist)
+    }
+
+    /// Whether completer `name` is disabled for any of `filetypes`
+    pub fn disables(&self, name: &str, filetypes: &[String]) -> bool {
+        match self.0.get(name) {
+            Some(disabled) => disabled.iter().any(|f| f == "*" || filetypes.contains(f)),
+            None => false,
+        }
+    }
+}
+
+/// Cut insertion text of `candidate` to `max_length` characters, 0 for no limit,
+/// keeping the full text in its detailed info
+fn truncate_candidate(candidate: &mut Candidate, max_length: usize) {
+    let end = match candidate.insertion_text.char_indices().nth(max_length) {
+        Some((end, _)) if max_length > 0 => end,
+        _ => return,
+    };
+    let full = candidate.insertion_text.clone();
+    candidate.insertion_text.truncate(end);
+    candidate.detailed_info = Some(match candidate.detailed_info.take() {
+        Some(info) => format!("{}\n{}", full, info),
+        None => full,
+    });
+}
+
+/// Keep at most `max` of `diagnostics`, errors first, then warnings, then the
+/// rest. A `max` of 0 keeps all of them
+pub fn truncate_diagnostics(diagnostics: &mut Vec<DiagnosticData>, max: usize) {
+    if max == 0 || diagnostics.len() <= max {
+        return;
+    }
+    diagnostics.sort_by_key(|d| d.kind().priority());
+    diagnostics.truncate(max);
+}
+
+/// Filter and sort `candidates` by the request's query, attaching match
+/// metadata if the request asked for it. Candidates of kinds the request
+/// leaves out are dropped beforehand, see `SimpleRequest::kinds`. Overly long
+/// candidates are truncated after filtering, see
+/// `CompletionConfig::max_candidate_length`
+pub fn filter_and_sort_completions(
+    mut candidates: Vec<Candidate>,
+    request: &SimpleRequest,
+    config: &CompletionConfig,
+) -> Vec<Candidate> {
+    let kinds = request.kinds();
+    candidates.retain(|c| kinds.allows(c.kind.as_deref()));
+    let mut candidates = rank_completions(candidates, request, config);
+    candidates.truncate(config.max_candidates);
+    finish_completions(&mut candidates, config);
+    candidates
+}
+
+/// What is left to do once `candidates` are ranked and capped
+fn finish_completions(candidates: &mut Vec<Candidate>, config: &CompletionConfig) {
+    if config.sink_deprecated {
+        // Stable, so the ranking holds within deprecated and other candidates
+        candidates.sort_by_key(Candidate::is_deprecated);
+    }
+    for candidate in candidates {
+        truncate_candidate(candidate, config.max_candidate_length);
+    }
+}
+
+fn rank_completions(
+    mut candidates: Vec<Candidate>,
+    request: &SimpleRequest,
+    config: &CompletionConfig,
+) -> Vec<Candidate> {
+    if candidates.is_empty() {
+        return candidates;
+    }
+    let query = request.query_word(config.segmentation);
+    if config.drop_exact_matches {
+        // Before ranking, a dropped exact match must not take the room of another
+        // candidate
+        candidates.retain(|c| c.insertion_text != query.text);
+    }
+    let options = FilterOptions {
+        max_candidates: config.max_candidates,
+        weights: config.scoring_weights,
+        include_metadata: request.include_match_metadata,
+        empty_order: config.empty_query_order,
+    };
+    filter_and_sort_generic_candidates_with_metadata(candidates, &query, &options, |c| {
+        c.filter_text()
+    })
+    .into_iter()
+    .map(|(mut candidate, metadata)| {
+        if let Some(mut metadata) = metadata {
+            // Positions in the filter text say nothing about the insertion text,
+            // unless it starts with it like the ones of snippets
+            if candidate
+                .filter_text()
+                .starts_with(&candidate.insertion_text)
+            {
+                let len = candidate.insertion_text.len();
+                metadata.match_positions.retain(|&p| p < len);
+            } else {
+                metadata.match_positions.clear();
+            }
+            candidate.set_match_metadata(metadata);
+        }
+        candidate
+    })
+    .collect()
+}
+
+/// Candidates computed right away along with the errors of the completers that
+/// failed, or a language server request to await once the completers are
+/// unlocked, along with the settings to filter its result by
+pub enum Completions {
+    Ready(Vec<Candidate>, Vec<CompleterError>),
+    Pending(
+        BoxFuture<'static, Result<Vec<Candidate>, CompleterError>>,
+        Box<CompletionConfig>,
+    ),
+}
+
+impl From<Result<Vec<Candidate>, CompleterError>> for Completions {
+    fn from(result: Result<Vec<Candidate>, CompleterError>) -> Self {
+        match result {
+            Ok(candidates) => Completions::Ready(candidates, vec![]),
+            Err(e) => Completions::Ready(vec![], vec![e]),
+        }
+    }
+}
+
+/// Why a completer could not answer a request. Serializes like the
+/// `ExceptionResponse` clients get for it
+#[derive(Debug)]
+pub enum CompleterError {
+    Lsp(anyhow::Error),
+    Io(std::io::Error),
+    Timeout,
+    /// Server behind the completer isn't done starting yet
+    NotInitialized,
+    Unsupported,
+    /// None of the filetypes has a semantic completer
+    NoCompleter(Vec<String>),
+    /// Command unknown or missing arguments
+    InvalidCommand(String),
+}
+
+impl fmt::Display for CompleterError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CompleterError::Lsp(e) => write!(f, "{:#}", e),
+            CompleterError::Io(e) => write!(f, "{}", e),
+            CompleterError::Timeout => write!(f, "Completer timed out"),
+            CompleterError::NotInitialized => write!(f, "Completer is not initialized yet"),
+            CompleterError::Unsupported => write!(f, "Not supported by this completer"),
+            CompleterError::NoCompleter(filetypes) => {
+                write!(f, "No semantic completer for {:?}", filetypes)
+            }
+            CompleterError::InvalidCommand(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for CompleterError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CompleterError::Lsp(e) => Some(e.as_ref()),
+            CompleterError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<anyhow::Error> for CompleterError {
+    fn from(e: anyhow::Error) -> Self {
+        CompleterError::Lsp(e)
+    }
+}
+
+impl From<std::io::Error> for CompleterError {
+    fn from(e: std::io::Error) -> Self {
+        CompleterError::Io(e)
+    }
+}
+
+impl From<&CompleterError> for ExceptionResponse {
+    fn from(e: &CompleterError) -> Self {
+        ExceptionResponse::new(e.to_string())
+    }
+}
+
+impl Serialize for CompleterError {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        ExceptionResponse::from(self).serialize(serializer)
+    }
 }
 
 // This is something to store state/settings for default Completer impl
@@ -31,6 +273,10 @@ pub trait CompleterInner {
 }
 
 pub trait Completer: CompleterInner {
+    /// Name the completer is referred to by in `filetype_completer_blacklist` and
+    /// reports such as completion timings
+    fn name(&self) -> &str;
+
     fn supported_filetypes(&self) -> &[String] {
         &[]
     }
@@ -51,32 +297,54 @@ pub trait Completer: CompleterInner {
         }
     }
 
+    /// 0-based byte offset the completer's candidates would start at
+    fn completion_start_column(&self, request: &SimpleRequest) -> usize {
+        request.start_column()
+    }
+
     fn should_use_now_inner(&self, filetype: &str, request: &SimpleRequest) -> bool {
-        self.get_settings()
-            .completion_triggers
-            .matches_for_filetype(
-                filetype,
-                request.line_value(),
-                request.start_column(),
-                request.column_num,
-            )
+        self.trigger_context(filetype, request).is_some()
+    }
+
+    /// Completion trigger the request's cursor is after, with what precedes it, so
+    /// that a completer can also decide based on e.g. the identifier before a `.`
+    fn trigger_context(&self, filetype: &str, request: &SimpleRequest) -> Option<MatchContext> {
+        self.get_settings().completion_triggers.match_context(
+            filetype,
+            request.line_value(),
+            request.start_column(),
+            request.completion_column(),
+        )
     }
 
     fn on_event(&mut self, _event: &EventNotification) {}
 
-    fn compute_candidates(&self, request: &mut SimpleRequest) -> Vec<Candidate> {
+    /// Called once the server is about to exit
+    fn shutdown(&mut self) {}
+
+    /// Whether the completer (and any server behind it) is in working order
+    fn is_healthy(&mut self) -> bool {
+        true
+    }
+
+    fn compute_candidates(
+        &self,
+        request: &mut SimpleRequest,
+    ) -> Result<Vec<Candidate>, CompleterError> {
         // Here be cache and some other stuff
-        let candidates = self.compute_candidates_inner(request);
-        filter_and_sort_generic_candidates(
+        let candidates = self.compute_candidates_inner(request)?;
+        Ok(filter_and_sort_completions(
             candidates,
-            request.query(),
-            self.get_settings().max_candidates,
-            |c| &c.insertion_text,
-        )
+            request,
+            self.get_settings(),
+        ))
     }
 
-    fn compute_candidates_inner(&self, _request: &SimpleRequest) -> Vec<Candidate> {
-        vec![]
+    fn compute_candidates_inner(
+        &self,
+        _request: &SimpleRequest,
+    ) -> Result<Vec<Candidate>, CompleterError> {
+        Ok(vec![])
     }
 
     fn query_length_above_min_threshold(
@@ -90,8 +358,266 @@ pub trait Completer: CompleterInner {
 
 pub struct GenericCompleters {
     pub completers: Vec<Box<dyn Completer + Send>>,
-    pub fname_completer: FilenameCompleter,
+    /// Tried before any other completer, `None` if filename completion is disabled
+    pub fname_completer: Option<FilenameCompleter>,
+    /// Semantic completers keyed by the filetype they serve
+    pub filetype_completers: HashMap<String, Box<dyn Completer + Send>>,
+    pub lsp: LspRegistry,
     pub config: CompletionConfig,
+    /// How long each completer took on its last requests, for `debug_info`
+    pub timings: CompleterTimings,
+}
+
+impl GenericCompleters {
+    fn is_blacklisted(&self, completer: &dyn Completer, filetypes: &[String]) -> bool {
+        self.config
+            .completer_blacklist
+            .disables(completer.name(), filetypes)
+    }
+
+    /// Whether `completer` may not run for `request`, being blacklisted for
+    /// its filetypes or left out of its `completers`
+    fn is_excluded(&self, completer: &dyn Completer, request: &SimpleRequest) -> bool {
+        self.is_blacklisted(completer, request.filetypes())
+            || !request.wants_completer(completer.name())
+    }
+
+    fn timed_candidates(
+        &self,
+        completer: &dyn Completer,
+        request: &mut SimpleRequest,
+    ) -> Result<Vec<Candidate>, CompleterError> {
+        self.timings
+            .time(completer.name(), || completer.compute_candidates(request))
+    }
+
+    /// Semantic completer for the first of `filetypes` that has one
+    pub fn filetype_completer(&self, filetypes: &[String]) -> Option<&(dyn Completer + Send)> {
+        filetypes
+            .iter()
+            .find_map(|f| self.filetype_completers.get(f))
+            .map(|c| c.as_ref())
+            .filter(|c| !self.is_blacklisted(*c, filetypes))
+    }
+
+    /// Whether any of `filetypes` has a semantic completer ready to answer,
+    /// language servers only count once initialized
+    pub fn semantic_completer_available(&self, filetypes: &[String]) -> bool {
+        self.filetype_completer(filetypes).is_some()
+            || filetypes.iter().any(|f| self.lsp.completer(f).is_some())
+    }
+
+    /// Filetypes something can complete semantically, see `LspRegistry::filetype_availability`
+    pub fn filetype_availability(&self) -> HashMap<String, Available> {
+        let mut available = self.lsp.filetype_availability();
+        for (filetype, completer) in &self.filetype_completers {
+            let filetypes = [filetype.clone()];
+            if !self.is_blacklisted(completer.as_ref(), &filetypes) {
+                available.insert(filetype.clone(), Available::YES);
+            }
+        }
+        available
+    }
+
+    /// Language server request for `filetype`, if its server is running
+    fn lsp_completions(
+        &self,
+        filetype: &str,
+        request: &SimpleRequest,
+        only_if_triggered: bool,
+    ) -> Option<Completions> {
+        let completer = self.lsp.completer(filetype)?;
+        let completer = completer.lock().unwrap();
+        if !request.wants_completer(completer.name())
+            || only_if_triggered && !completer.should_use_now(request)
+        {
+            return None;
+        }
+        let pending = completer
+            .request_completions(request)
+            .map_err(CompleterError::from)
+            .boxed();
+        Some(Completions::Pending(
+            self.timings.time_future(completer.name(), pending),
+            Box::new(completer.get_settings().clone()),
+        ))
+    }
+
+    /// Language server of the first of `filetypes` that has one running
+    fn running_lsp(&self, filetypes: &[String]) -> Option<Arc<Mutex<LspCompleter>>> {
+        filetypes.iter().find_map(|f| self.lsp.completer(f))
+    }
+
+    /// Latest diagnostics known for the event's file, see `running_lsp`
+    pub fn diagnostics(&self, event: &EventNotification) -> Vec<DiagnosticData> {
+        event
+            .file_data
+            .get(&event.filepath)
+            .and_then(|f| self.running_lsp(&f.filetypes))
+            .map(|c| c.lock().unwrap().diagnostics(event))
+            .unwrap_or_default()
+    }
+
+    /// Symbols of the request's file, see `running_lsp`
+    pub fn document_symbols(
+        &self,
+        request: &SimpleRequest,
+    ) -> Option<BoxFuture<'static, Result<Vec<GoToLocation>, anyhow::Error>>> {
+        let completer = self.running_lsp(request.filetypes())?;
+        let completer = completer.lock().unwrap();
+        Some(completer.request_document_symbols(request))
+    }
+
+    /// Symbols of the whole workspace matching `query`, see `running_lsp`
+    pub fn workspace_symbols(
+        &self,
+        request: &SimpleRequest,
+        query: &str,
+    ) -> Option<BoxFuture<'static, Result<Vec<GoToLocation>, anyhow::Error>>> {
+        let completer = self.running_lsp(request.filetypes())?;
+        let completer = completer.lock().unwrap();
+        Some(completer.request_workspace_symbols(request, query))
+    }
+
+    /// Details of a candidate from the last completion request, see `running_lsp`
+    pub fn resolve_completion(
+        &self,
+        request: &SimpleRequest,
+        id: usize,
+    ) -> Option<BoxFuture<'static, Result<Candidate, anyhow::Error>>> {
+        let completer = self.running_lsp(request.filetypes())?;
+        let completer = completer.lock().unwrap();
+        Some(completer.request_resolve(id))
+    }
+
+    /// Fix it renaming the symbol under the cursor, see `running_lsp`
+    pub fn rename(
+        &self,
+        request: &SimpleRequest,
+        new_name: &str,
+    ) -> Option<BoxFuture<'static, Result<Fixit, anyhow::Error>>> {
+        let completer = self.running_lsp(request.filetypes())?;
+        let completer = completer.lock().unwrap();
+        Some(completer.request_rename(request, new_name))
+    }
+
+    /// Candidates from the semantic completer for `filetype` only.
+    /// Returns nothing if there is no such completer.
+    pub fn filetype_completions(&self, filetype: &str, request: &mut SimpleRequest) -> Completions {
+        match self.filetype_completers.get(filetype) {
+            Some(c) if self.is_excluded(c.as_ref(), request) => Completions::Ready(vec![], vec![]),
+            Some(c) => Completions::from(self.timed_candidates(c.as_ref(), request)),
+            None => self
+                .lsp_completions(filetype, request, false)
+                .unwrap_or_else(|| Completions::Ready(vec![], vec![])),
+        }
+    }
+
+    fn decision(&self, completer: &dyn Completer, request: &SimpleRequest) -> CompleterDecision {
+        let start_column = completer.completion_start_column(request);
+        let end = request.completion_column().saturating_sub(1);
+        CompleterDecision {
+            completer: completer.name().to_owned(),
+            should_use_now: completer.should_use_now(request),
+            blacklisted: self.is_blacklisted(completer, request.filetypes()),
+            start_column: start_column + 1,
+            query: request
+                .line_value()
+                .get(start_column..end)
+                .unwrap_or_default()
+                .to_owned(),
+        }
+    }
+
+    /// What every completer that could answer `request` thinks of it, without
+    /// computing any candidates. Language servers are only listed once running
+    pub fn completion_decisions(&self, request: &SimpleRequest) -> Vec<CompleterDecision> {
+        let mut decisions = vec![];
+        if let Some(c) = &self.fname_completer {
+            decisions.push(self.decision(c, request));
+        }
+        decisions.extend(
+            self.completers
+                .iter()
+                .map(|c| self.decision(c.as_ref(), request)),
+        );
+        for filetype in request.filetypes() {
+            if let Some(c) = self.filetype_completers.get(filetype) {
+                decisions.push(self.decision(c.as_ref(), request));
+            }
+            if let Some(c) = self.lsp.completer(filetype) {
+                decisions.push(self.decision(&*c.lock().unwrap(), request));
+            }
+        }
+        decisions
+    }
+
+    /// Semantic completer when it wants to run, identifier/generic path otherwise.
+    pub fn default_completions(&self, request: &mut SimpleRequest) -> Completions {
+        if let Some(c) = self.filetype_completer(request.filetypes()) {
+            if request.wants_completer(c.name()) && c.should_use_now(request) {
+                return Completions::from(self.timed_candidates(c, request));
+            }
+        }
+        let lsp = request
+            .filetypes()
+            .iter()
+            .find_map(|f| self.lsp_completions(f, request, true));
+        match lsp {
+            Some(completions) => completions,
+            None => {
+                let (candidates, errors) = self.generic_candidates(request);
+                Completions::Ready(candidates, errors)
+            }
+        }
+    }
+
+    /// Candidates of the filename completer, or else the merged ones of the other
+    /// generic completers. Completers that fail are left out, their errors being
+    /// returned along with the candidates of the others
+    pub fn generic_candidates(
+        &self,
+        request: &mut SimpleRequest,
+    ) -> (Vec<Candidate>, Vec<CompleterError>) {
+        let mut errors = vec![];
+        let candidates = match &self.fname_completer {
+            Some(c) if !self.is_excluded(c, request) => {
+                self.timed_candidates(c, request).unwrap_or_else(|e| {
+                    errors.push(e);
+                    vec![]
+                })
+            }
+            _ => vec![],
+        };
+        if !candidates.is_empty() {
+            return (candidates, errors);
+        }
+        // Filename start column doesn't apply to identifiers
+        request.start_column = None;
+        let included: Vec<_> = self
+            .completers
+            .iter()
+            .filter(|c| !self.is_excluded(c.as_ref(), request))
+            .collect();
+        let mut ranked = vec![];
+        for c in included {
+            match self.timed_candidates(c.as_ref(), request) {
+                Ok(candidates) => ranked.push(candidates),
+                Err(e) => errors.push(e),
+            }
+        }
+        // Each completer ranked and capped its own results already
+        let mut candidates = merge_ranked_generic_candidates(
+            ranked,
+            &request.query_word(self.config.segmentation),
+            &self.config.scoring_weights,
+            self.config.empty_query_order,
+            |c| c.filter_text(),
+        );
+        candidates.truncate(self.config.max_candidates);
+        finish_completions(&mut candidates, &self.config);
+        (candidates, errors)
+    }
 }
 
 impl CompleterInner for GenericCompleters {
@@ -105,21 +631,251 @@ impl CompleterInner for GenericCompleters {
 }
 
 impl Completer for GenericCompleters {
-    fn compute_candidates(&self, request: &mut SimpleRequest) -> Vec<Candidate> {
-        let candidates = self.fname_completer.compute_candidates(request);
-        if !candidates.is_empty() {
-            candidates
-        } else {
-            self.completers
+    fn name(&self) -> &str {
+        "generic"
+    }
+
+    fn is_healthy(&mut self) -> bool {
+        self.fname_completer.iter_mut().all(|c| c.is_healthy())
+            && self
+                .completers
+                .iter_mut()
+                .chain(self.filetype_completers.values_mut())
+                .all(|c| c.is_healthy())
+            && self
+                .lsp
+                .running()
                 .iter()
-                .map(|c| c.compute_candidates(request))
-                .flatten()
-                .collect()
+                .all(|c| c.lock().unwrap().is_healthy())
+    }
+
+    fn compute_candidates(
+        &self,
+        request: &mut SimpleRequest,
+    ) -> Result<Vec<Candidate>, CompleterError> {
+        // Failing only when no completer had anything to offer, see `generic_candidates`
+        let (candidates, errors) = self.generic_candidates(request);
+        match errors.into_iter().next() {
+            Some(e) if candidates.is_empty() => Err(e),
+            _ => Ok(candidates),
         }
     }
 
     fn on_event(&mut self, event: &EventNotification) {
-        self.completers.iter_mut().for_each(|c| c.on_event(event))
+        if let Event::BufferVisit = event.event_name {
+            // Warm up language server so it's ready by the time completion is requested
+            if let Some(file) = event.file_data.get(&event.filepath) {
+                let filepath = Path::new(&event.filepath);
+                let working_dir = event.working_dir.as_deref().map(Path::new);
+                file.filetypes.iter().for_each(|f| {
+                    self.lsp.ensure_started(f, filepath, working_dir);
+                });
+            }
+        }
+        if let Some(c) = &mut self.fname_completer {
+            c.on_event(event);
+        }
+        self.completers
+            .iter_mut()
+            .chain(self.filetype_completers.values_mut())
+            .for_each(|c| c.on_event(event));
+        self.lsp
+            .running()
+            .iter()
+            .for_each(|c| c.lock().unwrap().on_event(event));
+    }
+
+    fn shutdown(&mut self) {
+        if let Some(c) = &mut self.fname_completer {
+            c.shutdown();
+        }
+        self.completers
+            .iter_mut()
+            .chain(self.filetype_completers.values_mut())
+            .for_each(|c| c.shutdown());
     }
 }
 
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::ycmd_types::FileData;
+
+    /// Settings completers are tested with, tests change them with struct update syntax
+    pub fn test_config() -> CompletionConfig {
+        CompletionConfig {
+            min_num_chars: 1,
+            max_diagnostics_to_display: 1,
+            completion_triggers: Default::default(),
+            signature_triggers: Default::default(),
+            max_candidates: 10,
+            max_candidates_to_detail: 1,
+            scoring_weights: Default::default(),
+            completer_blacklist: Default::default(),
+            identifier_cache: None,
+            empty_query_order: EmptyOrder::Lexicographic,
+            segmentation: Segmentation::Grapheme,
+            collect_identifiers_from_all_files: false,
+            sink_deprecated: false,
+            min_identifier_length: 1,
+            drop_exact_matches: false,
+            max_candidate_length: 0,
+        }
+    }
+
+    /// Request at the end of `contents`, the only line of "/foo.rs" of `filetype`.
+    /// Tests change it with struct update syntax
+    pub fn test_request(contents: &str, filetype: &str) -> SimpleRequest {
+        let filepath = PathBuf::from("/foo.rs");
+        let mut file_data = HashMap::default();
+        file_data.insert(
+            filepath.clone(),
+            FileData {
+                filetypes: vec![String::from(filetype)],
+                contents: String::from(contents),
+            },
+        );
+        SimpleRequest {
+            line_num: 1,
+            column_num: contents.len() + 1,
+            filepath,
+            file_data,
+            completer_target: None,
+            working_dir: None,
+            extra_conf_data: None,
+            start_column: None,
+            query_word: None,
+            include_match_metadata: false,
+            dry_run: false,
+            completers: None,
+            kind_filter: None,
+            completion_column_num: None,
+        }
+    }
+
+    #[test]
+    fn errors_serialize_as_exception_response() {
+        let errors = vec![
+            (
+                CompleterError::from(anyhow::anyhow!("server crashed")),
+                "server crashed",
+            ),
+            (
+                CompleterError::from(std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    "no such file",
+                )),
+                "no such file",
+            ),
+            (CompleterError::Timeout, "Completer timed out"),
+            (
+                CompleterError::NotInitialized,
+                "Completer is not initialized yet",
+            ),
+            (
+                CompleterError::Unsupported,
+                "Not supported by this completer",
+            ),
+            (
+                CompleterError::NoCompleter(vec![String::from("rust")]),
+                "No semantic completer for [\"rust\"]",
+            ),
+            (
+                CompleterError::InvalidCommand(String::from("Unknown command: \"Foo\"")),
+                "Unknown command: \"Foo\"",
+            ),
+        ];
+        for (error, message) in errors {
+            assert_eq!(
+                serde_json::to_value(&error).unwrap(),
+                serde_json::json!({
+                    "exception": { "message": message },
+                    "message": message,
+                    "traceback": "",
+                })
+            );
+        }
+    }
+
+    fn candidate(text: &str) -> Candidate {
+        Candidate {
+            insertion_text: String::from(text),
+            menu_text: None,
+            extra_menu_info: None,
+            detailed_info: None,
+            kind: None,
+            extra_data: None,
+            filter_text: None,
+        }
+    }
+
+    fn texts(candidates: Vec<Candidate>) -> Vec<String> {
+        candidates.into_iter().map(|c| c.insertion_text).collect()
+    }
+
+    #[test]
+    fn kinds_filtered_before_capping() {
+        let candidates = [("foo_fn", "Function"), ("foo_struct", "Struct")]
+            .iter()
+            .map(|(text, kind)| Candidate {
+                kind: Some(String::from(*kind)),
+                ..candidate(text)
+            })
+            .collect();
+        let request = SimpleRequest {
+            kind_filter: Some(vec![String::from("Struct")]),
+            ..test_request("foo", "rust")
+        };
+        let config = CompletionConfig {
+            max_candidates: 1,
+            ..test_config()
+        };
+        assert_eq!(
+            texts(filter_and_sort_completions(candidates, &request, &config)),
+            vec!["foo_struct"]
+        );
+    }
+
+    #[test]
+    fn exact_matches_dropped() {
+        let request = test_request("foo", "rust");
+        let config = |drop_exact_matches| CompletionConfig {
+            drop_exact_matches,
+            ..test_config()
+        };
+        let candidates = || {
+            vec![
+                candidate("Foo"),
+                candidate("foobar"),
+                candidate("foo"),
+                // Only what gets inserted counts, not what is matched
+                Candidate {
+                    filter_text: Some(String::from("foo")),
+                    ..candidate("foo_snippet")
+                },
+                Candidate {
+                    filter_text: Some(String::from("foo snippet")),
+                    ..candidate("foo")
+                },
+            ]
+        };
+
+        let kept = filter_and_sort_completions(candidates(), &request, &config(false));
+        assert_eq!(kept.len(), 5);
+        // Only a case sensitive match is dropped
+        let dropped = filter_and_sort_completions(candidates(), &request, &config(true));
+        let mut dropped = texts(dropped);
+        dropped.sort_unstable();
+        assert_eq!(dropped, vec!["Foo", "foo_snippet", "foobar"]);
+
+        // The exact match doesn't count towards `max_candidates` either
+        let config = CompletionConfig {
+            max_candidates: 2,
+            ..config(true)
+        };
+        let candidates = vec![candidate("foo"), candidate("foobar"), candidate("f_o_o")];
+        let mut results = texts(filter_and_sort_completions(candidates, &request, &config));
+        results.sort_unstable();
+        assert_eq!(results, vec!["f_o_o", "foobar"]);
+    }
+}
@@ -0,0 +1,76 @@
+use std::collections::HashMap;
+
+use log::error;
+
+use super::{
+    identifier::IdentifierCompleter, ultisnips::UltisnipsCompleter, Completer, CompletionConfig,
+};
+
+/// Builds a completer from the shared completion settings
+pub trait CompleterFactory: Send + Sync {
+    fn build(&self, config: &CompletionConfig) -> Box<dyn Completer + Send>;
+}
+
+impl<F> CompleterFactory for F
+where
+    F: Fn(&CompletionConfig) -> Box<dyn Completer + Send> + Send + Sync,
+{
+    fn build(&self, config: &CompletionConfig) -> Box<dyn Completer + Send> {
+        self(config)
+    }
+}
+
+/// Completer factories by name, so the set of completers can come from options
+pub struct CompleterRegistry {
+    factories: HashMap<String, Box<dyn CompleterFactory>>,
+}
+
+/// Completers used when options don't list any
+pub fn default_completers() -> Vec<String> {
+    vec![String::from("ultisnips"), String::from("identifier")]
+}
+
+impl Default for CompleterRegistry {
+    /// Registry of the built in completers
+    fn default() -> Self {
+        let mut registry = Self::empty();
+        registry.register("ultisnips", |config: &CompletionConfig| {
+            Box::new(UltisnipsCompleter::new(config.clone())) as Box<dyn Completer + Send>
+        });
+        registry.register("identifier", |config: &CompletionConfig| {
+            Box::new(IdentifierCompleter::new(config.clone())) as Box<dyn Completer + Send>
+        });
+        registry
+    }
+}
+
+impl CompleterRegistry {
+    pub fn empty() -> Self {
+        Self {
+            factories: HashMap::default(),
+        }
+    }
+
+    /// Add a factory, replacing the one already registered under `name`
+    pub fn register<F: CompleterFactory + 'static>(&mut self, name: &str, factory: F) {
+        self.factories.insert(String::from(name), Box::new(factory));
+    }
+
+    /// Completers for `names` in order, unknown names are skipped
+    pub fn build(
+        &self,
+        names: &[String],
+        config: &CompletionConfig,
+    ) -> Vec<Box<dyn Completer + Send>> {
+        names
+            .iter()
+            .filter_map(|name| match self.factories.get(name) {
+                Some(factory) => Some(factory.build(config)),
+                None => {
+                    error!("Unknown completer {}", name);
+                    None
+                }
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,114 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures::future::BoxFuture;
+use futures::FutureExt;
+
+/// Weight of the newest sample in the moving average, as a divisor
+const AVERAGE_WINDOW: u64 = 8;
+
+#[derive(Default)]
+pub struct Timing {
+    last_nanos: AtomicU64,
+    average_nanos: AtomicU64,
+    samples: AtomicU64,
+}
+
+impl Timing {
+    fn record(&self, elapsed: Duration) {
+        let nanos = elapsed.as_nanos().min(u64::MAX as u128) as u64;
+        self.last_nanos.store(nanos, Ordering::Relaxed);
+        let average = if self.samples.fetch_add(1, Ordering::Relaxed) == 0 {
+            nanos
+        } else {
+            // Exponential moving average, close enough without keeping a window
+            let average = self.average_nanos.load(Ordering::Relaxed) as i128;
+            (average + (nanos as i128 - average) / AVERAGE_WINDOW as i128) as u64
+        };
+        self.average_nanos.store(average, Ordering::Relaxed);
+    }
+
+    pub fn last(&self) -> Duration {
+        Duration::from_nanos(self.last_nanos.load(Ordering::Relaxed))
+    }
+
+    pub fn average(&self) -> Duration {
+        Duration::from_nanos(self.average_nanos.load(Ordering::Relaxed))
+    }
+}
+
+/// How long each completer took to compute candidates, keyed by completer name
+#[derive(Clone, Default)]
+pub struct CompleterTimings {
+    timings: Arc<Mutex<BTreeMap<String, Arc<Timing>>>>,
+}
+
+impl CompleterTimings {
+    pub fn record(&self, name: &str, elapsed: Duration) {
+        let timing = {
+            let mut timings = self.timings.lock().unwrap();
+            timings.entry(name.to_owned()).or_default().clone()
+        };
+        timing.record(elapsed);
+    }
+
+    /// Runs `f`, recording how long it took under `name`
+    pub fn time<T>(&self, name: &str, f: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let result = f();
+        self.record(name, start.elapsed());
+        result
+    }
+
+    /// Wraps `future` so it records the time until it resolves under `name`
+    pub fn time_future<T: Send + 'static>(
+        &self,
+        name: &str,
+        future: BoxFuture<'static, T>,
+    ) -> BoxFuture<'static, T> {
+        let timings = self.clone();
+        let name = name.to_owned();
+        let start = Instant::now();
+        future
+            .map(move |result| {
+                timings.record(&name, start.elapsed());
+                result
+            })
+            .boxed()
+    }
+
+    pub fn get(&self, name: &str) -> Option<Arc<Timing>> {
+        self.timings.lock().unwrap().get(name).cloned()
+    }
+
+    /// `(name, timing)` of every completer that ran so far, ordered by name
+    pub fn all(&self) -> Vec<(String, Arc<Timing>)> {
+        self.timings
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, timing)| (name.clone(), timing.clone()))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn moving_average() {
+        let timings = CompleterTimings::default();
+        timings.record("identifier", Duration::from_millis(16));
+        let timing = timings.get("identifier").unwrap();
+        assert_eq!(timing.last(), Duration::from_millis(16));
+        assert_eq!(timing.average(), Duration::from_millis(16));
+
+        timings.record("identifier", Duration::from_millis(8));
+        assert_eq!(timing.last(), Duration::from_millis(8));
+        assert_eq!(timing.average(), Duration::from_millis(15));
+        assert!(timings.get("filename").is_none());
+    }
+}
@@ -1,9 +1,13 @@
 use std::collections::{HashMap, HashSet};
 
+use log::error;
 use regex::{escape, Regex, RegexSet};
 
+use crate::core::utils::identifier::start_of_longest_identifier_ending_at_index;
+
 const REGEX_PREFIX: &str = "re!";
 
+/// Malformed `re!` triggers are logged and left out
 pub fn parse_triggers(
     triggers: Vec<HashMap<String, Vec<String>>>,
     filetypes: &HashSet<String>,
@@ -18,16 +22,25 @@ pub fn parse_triggers(
                 let re = res.entry(ftype.into()).or_insert_with(RegexSet::empty);
                 let mut patterns: Vec<_> = v
                     .iter()
-                    .map(|p| {
-                        if let Some(stripped) = p.strip_prefix(REGEX_PREFIX) {
-                            String::from(stripped)
-                        } else {
-                            escape(p)
+                    .filter_map(|p| {
+                        let stripped = match p.strip_prefix(REGEX_PREFIX) {
+                            Some(stripped) => stripped,
+                            None => return Some(escape(p)),
+                        };
+                        match Regex::new(stripped) {
+                            Ok(_) => Some(String::from(stripped)),
+                            Err(e) => {
+                                error!("Invalid trigger {} for {}: {}", p, ftype, e);
+                                None
+                            }
                         }
                     })
                     .collect();
                 patterns.extend_from_slice(re.patterns());
-                *re = RegexSet::new(&patterns).unwrap();
+                match RegexSet::new(&patterns) {
+                    Ok(set) => *re = set,
+                    Err(e) => error!("Invalid triggers {:?} for {}: {}", v, ftype, e),
+                }
             }
         }
     }
@@ -35,44 +48,87 @@ pub fn parse_triggers(
     res
 }
 
-pub trait PatternMatcher {
-    fn matches_for_filetype(&self, filetype: &str, line: &str, start: usize, column: usize)
-        -> bool;
+/// Trigger that matched and what precedes it on the line
+#[derive(Debug, PartialEq, Clone)]
+pub struct MatchContext {
+    pub trigger: String,
+    /// Line up to the trigger
+    pub preceding_text: String,
+    /// Identifier right before the trigger, if any
+    pub preceding_identifier: Option<String>,
 }
 
-impl PatternMatcher for HashMap<String, RegexSet> {
+/// Both positions are byte offsets in `line`, as in a request: `start_column` is
+/// 0-based and where the query starts, `column_num` is the 1-based cursor column
+pub trait PatternMatcher {
     fn matches_for_filetype(
         &self,
         filetype: &str,
         line: &str,
-        start: usize,
-        column: usize,
+        start_column: usize,
+        column_num: usize,
     ) -> bool {
-        let line = if column < line.len() {
-            &line[..column]
-        } else {
-            line
-        };
+        self.match_context(filetype, line, start_column, column_num)
+            .is_some()
+    }
+
+    /// Like `matches_for_filetype`, with the trigger that matched
+    fn match_context(
+        &self,
+        filetype: &str,
+        line: &str,
+        start_column: usize,
+        column_num: usize,
+    ) -> Option<MatchContext>;
+}
+
+impl PatternMatcher for HashMap<String, RegexSet> {
+    fn match_context(
+        &self,
+        filetype: &str,
+        line: &str,
+        start_column: usize,
+        column_num: usize,
+    ) -> Option<MatchContext> {
+        // Only the text before the cursor, which may not be past the end of the line
+        // nor inside a character
+        let mut end = column_num.saturating_sub(1).min(line.len());
+        while !line.is_char_boundary(end) {
+            end -= 1;
+        }
+        let line = &line[..end];
         match self.get(filetype) {
-            None => false,
+            None => None,
             Some(re) => {
                 for m in re.matches(line) {
                     for m in Regex::new(&re.patterns()[m]).unwrap().find_iter(line) {
                         /*
-                            By definition of 'start_codepoint', we know that the character just before
-                            'start_codepoint' is not an identifier character but all characters
-                            between 'start_codepoint' and 'column_codepoint' are. This means that if
+                            By definition of 'start_column', we know that the character just before
+                            'start_column' is not an identifier character but all characters
+                            between 'start_column' and the cursor are. This means that if
                             our trigger ends with an identifier character, its tail must match between
-                            'start_codepoint' and 'column_codepoint', 'start_codepoint' excluded. But
-                            if it doesn't, its tail must match exactly at 'start_codepoint'. Both
+                            'start_column' and the cursor, 'start_column' excluded. But
+                            if it doesn't, its tail must match exactly at 'start_column'. Both
                             cases are mutually exclusive hence the following condition.
                         */
-                        if start <= m.end() && m.end() <= column {
-                            return true;
+                        if start_column <= m.end() {
+                            let preceding_text = &line[..m.start()];
+                            let identifier_start = start_of_longest_identifier_ending_at_index(
+                                preceding_text,
+                                m.start(),
+                                Some(filetype),
+                            );
+                            return Some(MatchContext {
+                                trigger: String::from(m.as_str()),
+                                preceding_text: String::from(preceding_text),
+                                preceding_identifier: Some(&preceding_text[identifier_start..])
+                                    .filter(|i| !i.is_empty())
+                                    .map(String::from),
+                            });
                         }
                     }
                 }
-                false
+                None
             }
         }
     }
@@ -115,10 +171,61 @@ mod tests {
         assert!(!output["objcpp"].is_match("foo"));
     }
 
+    #[test]
+    fn malformed_regex_trigger_skipped() {
+        let input = vec![(
+            String::from("c"),
+            vec![String::from("re!(unclosed"), String::from("->")],
+        )]
+        .into_iter()
+        .collect();
+
+        let output = parse_triggers(vec![input], &HashSet::default());
+
+        assert_eq!(output["c"].len(), 1);
+        assert!(output["c"].is_match("->"));
+    }
+
     #[test]
     fn test_matcher() {
         let triggers = parse_triggers(vec![get_default()], &HashSet::default());
         assert!(triggers.matches_for_filetype("c", "foo->bar", 5, 9));
         assert!(!triggers.matches_for_filetype("c", "foo::bar", 5, 9));
     }
+
+    #[test]
+    fn test_match_context() {
+        let triggers = parse_triggers(vec![get_default()], &HashSet::default());
+        assert_eq!(
+            triggers.match_context("c", "x = foo->", 9, 10),
+            Some(MatchContext {
+                trigger: String::from("->"),
+                preceding_text: String::from("x = foo"),
+                preceding_identifier: Some(String::from("foo")),
+            })
+        );
+        assert_eq!(
+            triggers
+                .match_context("c", "(a + b).", 8, 9)
+                .unwrap()
+                .preceding_identifier,
+            None
+        );
+        assert_eq!(triggers.match_context("c", "foo::", 5, 6), None);
+    }
+
+    #[test]
+    fn test_matcher_multibyte_line() {
+        let triggers = parse_triggers(vec![get_default()], &HashSet::default());
+        // `ü` and `ö` are two bytes each
+        let line = "über.föo";
+        assert!(triggers.matches_for_filetype("c", line, 6, 7));
+        assert!(triggers.matches_for_filetype("c", line, 6, 8));
+        assert!(triggers.matches_for_filetype("c", line, 6, 11));
+        // The trigger is after the cursor
+        assert!(!triggers.matches_for_filetype("c", line, 0, 6));
+        // A cursor inside `ö` is not sliced through
+        assert!(!triggers.matches_for_filetype("c", line, 7, 9));
+        assert!(!triggers.matches_for_filetype("c", "ö", 0, 2));
+    }
 }
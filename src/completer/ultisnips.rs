@@ -1,9 +1,264 @@
-use crate::{
-    core::query::filter_and_sort_generic_candidates,
-    ycmd_types::{Candidate, SimpleRequest},
+use std::collections::HashMap;
+use std::iter::Peekable;
+use std::str::Chars;
+
+use serde::Serialize;
+
+use crate::core::candidate::Candidate as QueryCandidate;
+use crate::core::character::Segmentation;
+use crate::core::query::Word;
+use crate::ycmd_types::{Candidate, SimpleRequest, Snippet, UltisnipSnippet};
+
+use super::{
+    filter_and_sort_completions, Completer, CompleterError, CompleterInner, CompletionConfig,
 };
 
-use super::{Completer, CompleterInner, CompletionConfig};
+/// Piece of a snippet body. A tab stop index used more than once is defined by
+/// its first occurrence with a placeholder (or its first occurrence if none has one),
+/// the others mirror it
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SnippetElement {
+    Text {
+        text: String,
+    },
+    TabStop {
+        index: usize,
+        placeholder: Vec<SnippetElement>,
+    },
+    Mirror {
+        index: usize,
+    },
+}
+
+fn push_text(elements: &mut Vec<SnippetElement>, c: char) {
+    match elements.last_mut() {
+        Some(SnippetElement::Text { text }) => text.push(c),
+        _ => elements.push(SnippetElement::Text {
+            text: c.to_string(),
+        }),
+    }
+}
+
+fn parse_index(chars: &mut Peekable<Chars>) -> Option<usize> {
+    let mut digits = String::new();
+    while let Some(c) = chars.peek().filter(|c| c.is_ascii_digit()) {
+        digits.push(*c);
+        chars.next();
+    }
+    digits.parse().ok()
+}
+
+/// Tab stop following a `$`, leaves `chars` untouched if there is none
+fn parse_tab_stop(chars: &mut Peekable<Chars>) -> Option<SnippetElement> {
+    let mut lookahead = chars.clone();
+    let tab_stop = match lookahead.peek() {
+        Some('{') => {
+            lookahead.next();
+            let index = parse_index(&mut lookahead)?;
+            match lookahead.next() {
+                Some('}') => SnippetElement::TabStop {
+                    index,
+                    placeholder: vec![],
+                },
+                Some(':') => {
+                    let (placeholder, closed) = parse_elements(&mut lookahead, true);
+                    if !closed {
+                        return None;
+                    }
+                    SnippetElement::TabStop { index, placeholder }
+                }
+                _ => return None,
+            }
+        }
+        _ => SnippetElement::TabStop {
+            index: parse_index(&mut lookahead)?,
+            placeholder: vec![],
+        },
+    };
+    *chars = lookahead;
+    Some(tab_stop)
+}
+
+/// Elements up to the end of the body, or up to the `}` closing a placeholder
+/// if `nested`. Also returns whether that `}` was found
+fn parse_elements(chars: &mut Peekable<Chars>, nested: bool) -> (Vec<SnippetElement>, bool) {
+    let mut elements = vec![];
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.peek() {
+                Some(&escaped @ ('$' | '}' | '\\' | '`')) => {
+                    chars.next();
+                    push_text(&mut elements, escaped);
+                }
+                _ => push_text(&mut elements, c),
+            },
+            '$' => match parse_tab_stop(chars) {
+                Some(tab_stop) => elements.push(tab_stop),
+                None => push_text(&mut elements, c),
+            },
+            '}' if nested => return (elements, true),
+            _ => push_text(&mut elements, c),
+        }
+    }
+    (elements, false)
+}
+
+/// Record which occurrence of each tab stop, in order of appearance, defines it
+fn find_definitions(
+    elements: &[SnippetElement],
+    occurrence: &mut usize,
+    defining: &mut HashMap<usize, (usize, bool)>,
+) {
+    for element in elements {
+        if let SnippetElement::TabStop { index, placeholder } = element {
+            let has_placeholder = !placeholder.is_empty();
+            let definition = defining
+                .entry(*index)
+                .or_insert((*occurrence, has_placeholder));
+            if has_placeholder && !definition.1 {
+                *definition = (*occurrence, true);
+            }
+            *occurrence += 1;
+            find_definitions(placeholder, occurrence, defining);
+        }
+    }
+}
+
+fn mark_mirrors(
+    elements: &mut [SnippetElement],
+    occurrence: &mut usize,
+    defining: &HashMap<usize, (usize, bool)>,
+) {
+    for element in elements {
+        if let SnippetElement::TabStop { index, placeholder } = element {
+            let index = *index;
+            let this = *occurrence;
+            *occurrence += 1;
+            mark_mirrors(placeholder, occurrence, defining);
+            if defining[&index].0 != this {
+                *element = SnippetElement::Mirror { index };
+            }
+        }
+    }
+}
+
+/// Parse `${1:placeholder}`, `${1}` and `$1` tab stops out of an UltiSnips
+/// snippet body. Returns `None` if the body has no tab stops
+pub fn parse_snippet(body: &str) -> Option<Vec<SnippetElement>> {
+    let (mut elements, _) = parse_elements(&mut body.chars().peekable(), false);
+
+    let mut defining = HashMap::new();
+    find_definitions(&elements, &mut 0, &mut defining);
+    if defining.is_empty() {
+        return None;
+    }
+    mark_mirrors(&mut elements, &mut 0, &defining);
+    Some(elements)
+}
+
+fn collect_placeholders<'a>(
+    elements: &'a [SnippetElement],
+    defined: &mut HashMap<usize, &'a [SnippetElement]>,
+) {
+    for element in elements {
+        if let SnippetElement::TabStop { index, placeholder } = element {
+            if !placeholder.is_empty() {
+                defined.entry(*index).or_insert(placeholder);
+            }
+            collect_placeholders(placeholder, defined);
+        }
+    }
+}
+
+/// Appends the text of `elements`, tab stops without a placeholder and mirrors
+/// taking the one of their definition. `expanding` holds the tab stops being
+/// expanded, that a placeholder can't refer to
+fn push_plain_text(
+    elements: &[SnippetElement],
+    defined: &HashMap<usize, &[SnippetElement]>,
+    expanding: &mut Vec<usize>,
+    text: &mut String,
+) {
+    for element in elements {
+        let (index, placeholder) = match element {
+            SnippetElement::Text { text: t } => {
+                text.push_str(t);
+                continue;
+            }
+            SnippetElement::TabStop { index, placeholder } if !placeholder.is_empty() => {
+                (*index, &placeholder[..])
+            }
+            SnippetElement::TabStop { index, .. } | SnippetElement::Mirror { index } => {
+                (*index, defined.get(index).copied().unwrap_or_default())
+            }
+        };
+        if !expanding.contains(&index) {
+            expanding.push(index);
+            push_plain_text(placeholder, defined, expanding, text);
+            expanding.pop();
+        }
+    }
+}
+
+/// Text of a snippet body once expanded with its placeholders left as they are,
+/// for clients that can't expand snippets
+pub fn snippet_plain_text(body: &str) -> String {
+    let (elements, _) = parse_elements(&mut body.chars().peekable(), false);
+    let mut defined = HashMap::new();
+    collect_placeholders(&elements, &mut defined);
+    let mut text = String::new();
+    push_plain_text(&elements, &defined, &mut vec![], &mut text);
+    text
+}
+
+/// Marks snippets in the completion menu, ahead of their description
+const MENU_PREFIX: &str = "<snip> ";
+
+fn snippet_candidate(snippet: &UltisnipSnippet) -> Candidate {
+    let mut candidate = Candidate {
+        insertion_text: snippet.trigger.clone(),
+        extra_menu_info: Some(format!("{}{}", MENU_PREFIX, &snippet.description)),
+        menu_text: None,
+        detailed_info: None,
+        kind: None,
+        extra_data: None,
+        // Snippets are looked up by description as often as by trigger. Starting
+        // with the trigger keeps the match positions in it, see
+        // `filter_and_sort_completions`
+        filter_text: Some(format!("{} {}", snippet.trigger, snippet.description)),
+    };
+    if let Some(body) = &snippet.body {
+        if let Some(elements) = parse_snippet(body) {
+            candidate.set_snippet(Snippet {
+                body: body.clone(),
+                elements,
+            });
+        }
+    }
+    candidate
+}
+
+fn snippet_description(candidate: &Candidate) -> Option<&str> {
+    candidate
+        .extra_menu_info
+        .as_deref()
+        .map(|info| info.trim_start_matches(MENU_PREFIX))
+}
+
+/// Whether `query` matches the word boundaries of the snippet's trigger or of its
+/// description, like an acronym of it
+fn matches_word_boundaries(
+    candidate: &Candidate,
+    query: &Word,
+    segmentation: Segmentation,
+) -> bool {
+    std::iter::once(candidate.insertion_text.as_str())
+        .chain(snippet_description(candidate))
+        .any(|text| {
+            QueryCandidate::with_segmentation(text, segmentation).matches_word_boundaries(query)
+        })
+}
 
 pub struct UltisnipsCompleter {
     config: CompletionConfig,
@@ -30,21 +285,15 @@ impl CompleterInner for UltisnipsCompleter {
 }
 
 impl Completer for UltisnipsCompleter {
+    fn name(&self) -> &str {
+        "ultisnips"
+    }
+
     fn on_event(&mut self, event: &crate::ycmd_types::EventNotification) {
         if let crate::ycmd_types::Event::BufferVisit = event.event_name {
             match &event.ultisnips_snippets {
                 Some(s) => {
-                    self.candidates = s
-                        .iter()
-                        .map(|s| Candidate {
-                            insertion_text: s.trigger.clone(),
-                            extra_menu_info: Some(format!("<snip> {}", &s.description)),
-                            menu_text: None,
-                            detailed_info: None,
-                            kind: None,
-                            extra_data: None,
-                        })
-                        .collect();
+                    self.candidates = s.iter().map(snippet_candidate).collect();
                 }
                 None => {}
             }
@@ -52,16 +301,208 @@ impl Completer for UltisnipsCompleter {
     }
 
     fn should_use_now(&self, request: &SimpleRequest) -> bool {
-        self.query_length_above_min_threshold(request.start_column(), request.column_num)
+        self.query_length_above_min_threshold(request.start_column(), request.completion_column())
+    }
+
+    fn compute_candidates(
+        &self,
+        request: &mut SimpleRequest,
+    ) -> Result<Vec<Candidate>, CompleterError> {
+        // Capped only once the word boundary matches are moved up, so none is lost
+        let config = CompletionConfig {
+            max_candidates: usize::MAX,
+            ..self.config.clone()
+        };
+        let mut candidates =
+            filter_and_sort_completions(self.compute_candidates_inner(request)?, request, &config);
+        let query = request.query_word(self.config.segmentation);
+        // Stable, so the ranking holds among the snippets on either side
+        candidates.sort_by_key(|c| !matches_word_boundaries(c, &query, self.config.segmentation));
+        candidates.truncate(self.config.max_candidates);
+        Ok(candidates)
     }
 
-    fn compute_candidates(&self, request: &mut SimpleRequest) -> Vec<Candidate> {
-        // Here be cache and some other stuff
-        filter_and_sort_generic_candidates(
-            self.candidates.clone(),
-            request.query(),
-            self.get_settings().max_candidates,
-            |c| &c.insertion_text,
-        )
+    fn compute_candidates_inner(
+        &self,
+        _request: &SimpleRequest,
+    ) -> Result<Vec<Candidate>, CompleterError> {
+        Ok(self.candidates.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::completer::tests::test_config;
+    use SnippetElement::*;
+
+    fn text(s: &str) -> SnippetElement {
+        Text {
+            text: String::from(s),
+        }
+    }
+
+    #[test]
+    fn snippet_without_tab_stops() {
+        assert_eq!(parse_snippet("println!();"), None);
+        assert_eq!(parse_snippet(r"costs \$1"), None);
+        assert_eq!(parse_snippet("${VISUAL} $ {1}"), None);
+    }
+
+    #[test]
+    fn snippet_nested_and_mirrored_tab_stops() {
+        assert_eq!(
+            parse_snippet("for ${1:i} in ${2:0..${3:n}} {\n\t$0 $1\n}"),
+            Some(vec![
+                text("for "),
+                TabStop {
+                    index: 1,
+                    placeholder: vec![text("i")],
+                },
+                text(" in "),
+                TabStop {
+                    index: 2,
+                    placeholder: vec![
+                        text("0.."),
+                        TabStop {
+                            index: 3,
+                            placeholder: vec![text("n")],
+                        },
+                    ],
+                },
+                text(" {\n\t"),
+                TabStop {
+                    index: 0,
+                    placeholder: vec![],
+                },
+                text(" "),
+                Mirror { index: 1 },
+                text("\n}"),
+            ])
+        );
+    }
+
+    #[test]
+    fn snippet_mirror_before_placeholder() {
+        assert_eq!(
+            parse_snippet(r"${1} = ${1:\}x}"),
+            Some(vec![
+                Mirror { index: 1 },
+                text(" = "),
+                TabStop {
+                    index: 1,
+                    placeholder: vec![text("}x")],
+                },
+            ])
+        );
+    }
+
+    #[test]
+    fn plain_text_of_snippet() {
+        assert_eq!(snippet_plain_text("foo(${1:a}, $2)$0"), "foo(a, )");
+        assert_eq!(snippet_plain_text("${1:name} = $1;"), "name = name;");
+        assert_eq!(snippet_plain_text("${1:a ${2:b}} $2"), "a b b");
+        assert_eq!(snippet_plain_text(r"costs \$1"), "costs $1");
+        assert_eq!(snippet_plain_text("${1:a$1}"), "a");
+        assert_eq!(snippet_plain_text("${1:unclosed"), "${1:unclosed");
+    }
+
+    #[test]
+    fn snippet_candidate_carries_body() {
+        let mut snippet = UltisnipSnippet {
+            trigger: String::from("fn"),
+            description: String::from("function"),
+            body: Some(String::from("fn ${1:name}() {}")),
+        };
+        let candidate = serde_json::to_value(snippet_candidate(&snippet)).unwrap();
+        assert_eq!(
+            candidate["extra_data"]["snippet"]["body"],
+            "fn ${1:name}() {}"
+        );
+        assert_eq!(
+            candidate["extra_data"]["snippet"]["elements"][1],
+            serde_json::json!({
+                "type": "tab_stop",
+                "index": 1,
+                "placeholder": [{ "type": "text", "text": "name" }],
+            })
+        );
+
+        snippet.body = Some(String::from("fn main() {}"));
+        let candidate = serde_json::to_value(snippet_candidate(&snippet)).unwrap();
+        assert_eq!(
+            candidate,
+            serde_json::json!({ "insertion_text": "fn", "extra_menu_info": "<snip> function" })
+        );
+    }
+
+    fn test_request(contents: &str) -> SimpleRequest {
+        SimpleRequest {
+            include_match_metadata: true,
+            ..crate::completer::tests::test_request(contents, "rust")
+        }
+    }
+
+    #[test]
+    fn matches_snippet_description() {
+        let mut completer = UltisnipsCompleter::new(test_config());
+        completer.candidates = vec![
+            snippet_candidate(&UltisnipSnippet {
+                trigger: String::from("fn"),
+                description: String::from("function definition"),
+                body: None,
+            }),
+            snippet_candidate(&UltisnipSnippet {
+                trigger: String::from("st"),
+                description: String::from("struct"),
+                body: None,
+            }),
+        ];
+
+        let candidates = completer
+            .compute_candidates(&mut test_request("defin"))
+            .unwrap();
+        assert_eq!(candidates.len(), 1);
+        assert_eq!(candidates[0].insertion_text, "fn");
+        let candidate = serde_json::to_value(&candidates[0]).unwrap();
+        assert_eq!(
+            candidate["extra_data"]["match_metadata"]["match_positions"],
+            serde_json::json!([])
+        );
+
+        // Triggers still match as before
+        let candidates = completer
+            .compute_candidates(&mut test_request("st"))
+            .unwrap();
+        assert_eq!(candidates[0].insertion_text, "st");
+    }
+
+    #[test]
+    fn word_boundary_matches_boosted() {
+        let snippet = |trigger: &str, description: &str| {
+            snippet_candidate(&UltisnipSnippet {
+                trigger: String::from(trigger),
+                description: String::from(description),
+                body: None,
+            })
+        };
+        let mut completer = UltisnipsCompleter::new(test_config());
+        completer.candidates = vec![
+            snippet("from", "from import"),
+            snippet("forr", "for range"),
+            snippet("for_range", "for loop"),
+        ];
+
+        let candidates = completer
+            .compute_candidates(&mut test_request("fr"))
+            .unwrap();
+        let triggers = candidates
+            .iter()
+            .map(|c| c.insertion_text.as_str())
+            .collect::<Vec<_>>();
+        // A prefix match otherwise ranks first
+        assert_eq!(triggers.last(), Some(&"from"));
+        assert!(triggers[..2].contains(&"forr"));
+        assert!(triggers[..2].contains(&"for_range"));
     }
 }
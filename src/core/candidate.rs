@@ -1,7 +1,5 @@
-use unicode_segmentation::UnicodeSegmentation;
-
 use super::{
-    character::Character,
+    character::{normalize, Character, Segmentation},
     query::{QueryResult, Word},
 };
 
@@ -12,18 +10,30 @@ pub struct Candidate<'a> {
     pub text_is_lowercase: bool,
     pub case_swapped: Vec<char>,
     pub text: &'a str,
+    /// NFD form of `text`, if normalization changed it
+    pub normalized: Option<String>,
+    pub segmentation: Segmentation,
 }
 
 impl<'a> Candidate<'a> {
     pub fn new(s: &'a str) -> Self {
-        let characters: Vec<Character> = s.graphemes(true).map(Character::new).collect();
+        Self::with_segmentation(s, Segmentation::default())
+    }
+
+    /// Candidate split into `segmentation` units, only queries split the same
+    /// way match it as expected
+    pub fn with_segmentation(s: &'a str, segmentation: Segmentation) -> Self {
+        let characters = segmentation.characters(s);
         let mut word_boundary_chars = characters
             .windows(2)
             .filter_map(|chars| {
                 let prev = &chars[0];
                 let current = &chars[1];
+                // Digit runs split words too, e.g. parse|Utf|8|decode
                 if (prev.is_punctuation && !current.is_punctuation)
                     | (!prev.is_uppercase && current.is_uppercase)
+                    | (prev.is_digit && current.is_letter)
+                    | (prev.is_letter && current.is_digit)
                 {
                     Some(current.clone())
                 } else {
@@ -47,6 +57,8 @@ impl<'a> Candidate<'a> {
             text_is_lowercase,
             case_swapped,
             text: s,
+            normalized: normalize(s),
+            segmentation,
         }
     }
 
@@ -54,22 +66,76 @@ impl<'a> Candidate<'a> {
         self.characters.is_empty()
     }
 
+    /// `text` in the same normal form characters are compared in
+    pub fn normalized_text(&self) -> &str {
+        self.normalized.as_deref().unwrap_or(self.text)
+    }
+
+    /// Whether `q` is a literal prefix ending on a grapheme boundary
+    pub fn starts_with(&self, q: &Word) -> bool {
+        self.normalized_text().starts_with(q.normalized_text())
+            && self.characters.len() >= q.characters.len()
+            && self
+                .characters
+                .iter()
+                .zip(&q.characters)
+                .all(|(c, q)| c.normal == q.normal)
+    }
+
+    /// Byte offsets of the characters matched by `q`, empty if it does not match
+    pub fn match_positions(&self, q: &Word) -> Vec<usize> {
+        let mut q_iter = q.characters.iter().peekable();
+        let positions = self
+            .segmentation
+            .offsets(self.text)
+            .into_iter()
+            .zip(&self.characters)
+            .filter_map(|(offset, c)| {
+                if q_iter.peek()?.smartcaseeq(c) {
+                    q_iter.next();
+                    Some(offset)
+                } else {
+                    None
+                }
+            })
+            .collect();
+        if q_iter.peek().is_some() {
+            vec![]
+        } else {
+            positions
+        }
+    }
+
+    /// Whether `q` is a subsequence of the word boundary characters alone,
+    /// e.g. `fr` of `for_range`
+    pub fn matches_word_boundaries(&self, q: &Word) -> bool {
+        let mut boundaries = self.word_boundary_chars.iter();
+        !q.characters.is_empty()
+            && q.characters
+                .iter()
+                .all(|q| boundaries.any(|c| q.smartcaseeq(c)))
+    }
+
     pub fn matches_query<'c, 'b>(&'c self, q: &'b Word<'b>) -> QueryResult<'c, 'b> {
+        if self.starts_with(q) {
+            let n = q.characters.len();
+            return QueryResult::new(true, true, n * n.saturating_sub(1) / 2, self, q);
+        }
         let mut q_iter = q.characters.iter();
         let mut last_q = q_iter.next();
         let mut match_index_sum = 0;
         let mut is_prefix = true;
         for (i, g) in self.characters.iter().enumerate() {
-            match last_q {
-                Some(c) => {
-                    if c.smartcaseeq(g) {
-                        last_q = q_iter.next();
-                        match_index_sum += i;
-                    } else {
-                        is_prefix = false;
-                    }
-                }
-                None => return QueryResult::new(true, is_prefix, match_index_sum, self, q),
+            // Whatever follows the match can't change the result
+            let c = match last_q {
+                Some(c) => c,
+                None => break,
+            };
+            if c.smartcaseeq(g) {
+                last_q = q_iter.next();
+                match_index_sum += i;
+            } else {
+                is_prefix = false;
             }
         }
         if last_q.is_none() {
@@ -79,3 +145,96 @@ impl<'a> Candidate<'a> {
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn word_boundaries(text: &str) -> String {
+        Candidate::new(text)
+            .word_boundary_chars
+            .iter()
+            .flat_map(|c| c.normal.iter())
+            .collect()
+    }
+
+    #[test]
+    fn word_boundaries_camel_case() {
+        assert_eq!(word_boundaries("fooBarBaz"), "fBB");
+        assert_eq!(word_boundaries("foo_bar"), "fb");
+    }
+
+    #[test]
+    fn word_boundaries_digits() {
+        assert_eq!(word_boundaries("parseUtf8"), "pU8");
+        assert_eq!(word_boundaries("parseUtf8data"), "pU8d");
+        assert_eq!(word_boundaries("http2Client"), "h2C");
+        assert_eq!(word_boundaries("utf16"), "u1");
+    }
+
+    #[test]
+    fn digit_boundaries_count_as_word_boundary_matches() {
+        let candidate = Candidate::new("parseUtf8data");
+        let query = Word::new("pud");
+        assert_eq!(candidate.matches_query(&query).num_wb_matches, 3);
+    }
+
+    #[test]
+    fn nfc_and_nfd_match_the_same() {
+        let nfc = Candidate::new("caf\u{e9}_au_lait");
+        let nfd = Candidate::new("cafe\u{301}_au_lait");
+        assert!(nfc.normalized.is_some());
+        assert!(nfd.normalized.is_none());
+        assert_eq!(nfc.normalized_text(), nfd.normalized_text());
+
+        for query in ["caf\u{e9}", "cafe\u{301}", "cal"].iter() {
+            let query = Word::new(query);
+            let summary = |c: &Candidate| {
+                let r = c.matches_query(&query);
+                (
+                    r.is_subsequence,
+                    r.query_is_prefix,
+                    r.is_exact_prefix,
+                    r.char_match_index_sum,
+                    r.num_wb_matches,
+                )
+            };
+            assert_eq!(summary(&nfc), summary(&nfd));
+        }
+        assert!(nfc.matches_query(&Word::new("cafe\u{301}")).is_exact_prefix);
+        assert!(nfd.matches_query(&Word::new("caf\u{e9}")).is_exact_prefix);
+    }
+
+    #[test]
+    fn query_is_prefix_of_match() {
+        let query = Word::new("fb");
+        let is_prefix = |text: &str| {
+            let candidate = Candidate::new(text);
+            let result = candidate.matches_query(&query);
+            assert!(result.is_subsequence);
+            result.query_is_prefix
+        };
+        assert!(is_prefix("Fb"));
+        assert!(is_prefix("fb_and_a_long_tail"));
+        assert!(!is_prefix("f_b"));
+        assert!(!is_prefix("xfb"));
+        assert!(!is_prefix("foo_bar_and_a_long_tail"));
+        assert!(!Candidate::new("bf").matches_query(&query).is_subsequence);
+    }
+
+    #[test]
+    fn codepoints_match_inside_graphemes() {
+        // The flag is a single grapheme made of two regional indicators
+        let text = "a\u{1f1fa}\u{1f1f8}b";
+        let query = "a\u{1f1fa}b";
+
+        let graphemes = Candidate::new(text);
+        assert_eq!(graphemes.characters.len(), 3);
+        assert!(!graphemes.matches_query(&Word::new(query)).is_subsequence);
+
+        let codepoints = Candidate::with_segmentation(text, Segmentation::Codepoint);
+        let query = Word::with_segmentation(query, Segmentation::Codepoint);
+        assert_eq!(codepoints.characters.len(), 4);
+        assert!(codepoints.matches_query(&query).is_subsequence);
+        assert_eq!(codepoints.match_positions(&query), vec![0, 1, 9]);
+    }
+}
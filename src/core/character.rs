@@ -1,8 +1,58 @@
 use unicode_linebreak::{break_property, BreakClass};
 use unicode_normalization::UnicodeNormalization;
+use unicode_segmentation::UnicodeSegmentation;
 
 use smallvec::SmallVec;
 
+/// NFD form of `text` if it differs from `text`. Characters are compared
+/// in NFD, so offsets should be computed against this form as well
+pub fn normalize(text: &str) -> Option<String> {
+    if text.is_ascii() || text.nfd().eq(text.chars()) {
+        None
+    } else {
+        Some(text.nfd().collect())
+    }
+}
+
+/// Units text is split into before matching, each becoming one `Character`
+#[derive(serde::Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Segmentation {
+    /// Extended grapheme clusters, so a flag or a letter with its combining
+    /// marks is a single character
+    Grapheme,
+    /// Unicode scalar values, every codepoint matches on its own
+    Codepoint,
+}
+
+// `#[default]` on variants needs a newer compiler
+#[allow(clippy::derivable_impls)]
+impl Default for Segmentation {
+    fn default() -> Self {
+        Segmentation::Grapheme
+    }
+}
+
+impl Segmentation {
+    pub fn characters(self, text: &str) -> Vec<Character> {
+        match self {
+            Segmentation::Grapheme => text.graphemes(true).map(Character::new).collect(),
+            Segmentation::Codepoint => text
+                .char_indices()
+                .map(|(i, c)| Character::new(&text[i..i + c.len_utf8()]))
+                .collect(),
+        }
+    }
+
+    /// Byte offset of each unit of `text`, in the same order as `characters`
+    pub fn offsets(self, text: &str) -> Vec<usize> {
+        match self {
+            Segmentation::Grapheme => text.grapheme_indices(true).map(|(i, _)| i).collect(),
+            Segmentation::Codepoint => text.char_indices().map(|(i, _)| i).collect(),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Eq)]
 pub struct Character {
     pub normal: SmallVec<[char; 2]>,
@@ -13,6 +63,7 @@ pub struct Character {
     pub is_uppercase: bool,
     pub is_punctuation: bool,
     pub is_letter: bool,
+    pub is_digit: bool,
 }
 
 impl Character {
@@ -25,6 +76,7 @@ impl Character {
         let mut is_uppercase = false;
         let mut is_punctuation = false;
         let mut is_letter = false;
+        let mut is_digit = false;
         for c in character.nfd() {
             normal.push(c);
             match break_property(c as u32) {
@@ -43,6 +95,7 @@ impl Character {
             is_uppercase |= c.is_uppercase();
             is_punctuation |= c.is_ascii_punctuation() | c.is_whitespace();
             is_letter |= c.is_alphabetic();
+            is_digit |= c.is_numeric();
             for cc in c.to_lowercase() {
                 folded_case.push(cc);
             }
@@ -66,6 +119,7 @@ impl Character {
             is_uppercase,
             is_punctuation,
             is_letter,
+            is_digit,
         }
     }
     /// Smart base matching on top of smart case matching, e.g.:
@@ -0,0 +1,459 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::Path;
+use std::time::SystemTime;
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use super::utils::identifier::{blank_identifier_free_text, identifier_ranges_in_blanked_text};
+
+/// How many times each identifier occurs in a file
+type IdentifierCounts = HashMap<String, usize>;
+
+/// Buffer contents kept around for incremental updates,
+/// with identifiers extracted line by line
+struct Buffer {
+    filetype: String,
+    min_length: usize,
+    text: String,
+    lines: Vec<Line>,
+}
+
+/// Line of a buffer with its comments and strings blanked out,
+/// and the identifiers found in what remains
+struct Line {
+    blanked: String,
+    identifiers: Vec<String>,
+}
+
+/// Identifiers known per filetype, grouped by the file they came from
+#[derive(Default)]
+pub struct IdentifierDatabase {
+    filetypes: HashMap<String, HashMap<String, IdentifierCounts>>,
+    buffers: HashMap<String, Buffer>,
+}
+
+/// Identifiers of a file as written to the cache, along with what the file
+/// looked like on disk at the time so stale entries can be told apart
+#[derive(Serialize, Deserialize)]
+struct CachedFile {
+    filetype: String,
+    filepath: String,
+    modified: SystemTime,
+    len: u64,
+    identifiers: IdentifierCounts,
+}
+
+impl CachedFile {
+    fn is_fresh(&self) -> bool {
+        match std::fs::metadata(&self.filepath) {
+            Ok(metadata) => {
+                metadata.len() == self.len && metadata.modified().ok() == Some(self.modified)
+            }
+            Err(_) => false,
+        }
+    }
+}
+
+/// Line with identifiers of at least `min_length` characters
+fn parse_line(blanked: &str, filetype: Option<&str>, min_length: usize) -> Line {
+    Line {
+        identifiers: identifier_ranges_in_blanked_text(blanked, filetype)
+            .into_iter()
+            .map(|r| String::from(&blanked[r]))
+            .filter(|i| i.chars().count() >= min_length)
+            .collect(),
+        blanked: String::from(blanked),
+    }
+}
+
+/// Lines of `text`, see `parse_line`. Comments and strings are blanked out
+/// of the whole text first, as they may span several lines
+fn parse_lines(text: &str, filetype: &str, min_length: usize) -> Vec<Line> {
+    let filetype = Some(filetype).filter(|f| !f.is_empty());
+    blank_identifier_free_text(text, filetype)
+        .split('\n')
+        .map(|blanked| parse_line(blanked, filetype, min_length))
+        .collect()
+}
+
+/// Byte range of `old` that `new` replaces, along with the replacement
+fn changed_range<'a>(old: &str, new: &'a str) -> (usize, usize, &'a str) {
+    let mut prefix = old
+        .bytes()
+        .zip(new.bytes())
+        .take_while(|(a, b)| a == b)
+        .count();
+    while !new.is_char_boundary(prefix) {
+        prefix -= 1;
+    }
+    let mut suffix = old[prefix..]
+        .bytes()
+        .rev()
+        .zip(new[prefix..].bytes().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    while !new.is_char_boundary(new.len() - suffix) {
+        suffix -= 1;
+    }
+    (prefix, old.len() - suffix, &new[prefix..new.len() - suffix])
+}
+
+impl IdentifierDatabase {
+    pub fn add_identifiers<I>(&mut self, filetype: &str, filepath: &str, identifiers: I)
+    where
+        I: IntoIterator<Item = String>,
+    {
+        self.buffers.remove(filepath);
+        let counts = self
+            .filetypes
+            .entry(filetype.to_string())
+            .or_default()
+            .entry(filepath.to_string())
+            .or_default();
+        for identifier in identifiers {
+            *counts.entry(identifier).or_default() += 1;
+        }
+    }
+
+    /// Replace identifiers stored for `filepath` under `filetype`
+    pub fn clear_and_add_identifiers<I>(&mut self, filetype: &str, filepath: &str, identifiers: I)
+    where
+        I: IntoIterator<Item = String>,
+    {
+        if let Some(files) = self.filetypes.get_mut(filetype) {
+            files.remove(filepath);
+        }
+        self.add_identifiers(filetype, filepath, identifiers);
+    }
+
+    /// Replace identifiers of `filepath` with the ones of at least `min_length`
+    /// characters found in `text`, remembering the text so later edits can go through
+    /// `update_contents`. Contents already known for `filepath` are updated the same way
+    pub fn set_contents(
+        &mut self,
+        filetype: &str,
+        filepath: &str,
+        text: String,
+        min_length: usize,
+    ) {
+        let change = match self.buffers.get(filepath) {
+            Some(b) if b.filetype == filetype && b.min_length == min_length => {
+                Some(changed_range(&b.text, &text))
+            }
+            _ => None,
+        };
+        if let Some((start, end, new_text)) = change {
+            if self.update_contents(filepath, start, end, new_text).is_ok() {
+                return;
+            }
+        }
+        let lines = parse_lines(&text, filetype, min_length);
+        self.clear_and_add_identifiers(
+            filetype,
+            filepath,
+            lines.iter().flat_map(|l| l.identifiers.iter().cloned()),
+        );
+        self.buffers.insert(
+            filepath.to_string(),
+            Buffer {
+                filetype: filetype.to_string(),
+                min_length,
+                text,
+                lines,
+            },
+        );
+    }
+
+    /// Replace bytes `start..end` of the text previously given to `set_contents`
+    /// with `new_text`. Identifiers are only extracted again from the touched lines,
+    /// and from the lines that a comment or string starting or ending there changed
+    pub fn update_contents(
+        &mut self,
+        filepath: &str,
+        start: usize,
+        end: usize,
+        new_text: &str,
+    ) -> Result<(), anyhow::Error> {
+        let buffer = self
+            .buffers
+            .get_mut(filepath)
+            .ok_or_else(|| anyhow::anyhow!("No contents known for {}", filepath))?;
+        if start > end || !buffer.text.is_char_boundary(start) || !buffer.text.is_char_boundary(end)
+        {
+            anyhow::bail!("Invalid change range {}..{} for {}", start, end, filepath);
+        }
+
+        let first_line = buffer.text[..start].matches('\n').count();
+        let old_last_line = first_line + buffer.text[start..end].matches('\n').count();
+        let new_last_line = first_line + new_text.matches('\n').count();
+        buffer.text.replace_range(start..end, new_text);
+
+        let filetype = Some(buffer.filetype.as_str()).filter(|f| !f.is_empty());
+        let blanked = blank_identifier_free_text(&buffer.text, filetype);
+        let mut old_lines = std::mem::take(&mut buffer.lines)
+            .into_iter()
+            .map(Some)
+            .collect::<Vec<_>>();
+        let mut added = vec![];
+        for (i, blanked) in blanked.split('\n').enumerate() {
+            let old = if i < first_line {
+                Some(i)
+            } else if i > new_last_line {
+                Some(i - new_last_line + old_last_line)
+            } else {
+                None
+            };
+            let unchanged = old
+                .and_then(|j| old_lines.get_mut(j))
+                .and_then(|slot| match slot {
+                    Some(line) if line.blanked == blanked => slot.take(),
+                    _ => None,
+                });
+            let line = match unchanged {
+                Some(line) => line,
+                None => {
+                    let line = parse_line(blanked, filetype, buffer.min_length);
+                    added.extend(line.identifiers.iter().cloned());
+                    line
+                }
+            };
+            buffer.lines.push(line);
+        }
+        let removed = old_lines
+            .into_iter()
+            .flatten()
+            .flat_map(|l| l.identifiers)
+            .collect::<Vec<_>>();
+
+        let counts = self
+            .filetypes
+            .entry(buffer.filetype.clone())
+            .or_default()
+            .entry(filepath.to_string())
+            .or_default();
+        for identifier in removed {
+            if let Some(count) = counts.get_mut(&identifier) {
+                *count -= 1;
+                if *count == 0 {
+                    counts.remove(&identifier);
+                }
+            }
+        }
+        for identifier in added {
+            *counts.entry(identifier).or_default() += 1;
+        }
+        Ok(())
+    }
+
+    /// Forget identifiers of `filepath` for all filetypes
+    pub fn clear_file(&mut self, filepath: &str) {
+        self.buffers.remove(filepath);
+        for files in self.filetypes.values_mut() {
+            files.remove(filepath);
+        }
+    }
+
+    /// Unique identifiers stored for `filetype` across all files
+    pub fn identifiers(&self, filetype: &str) -> HashSet<&str> {
+        self.identifiers_for_filetypes(&[filetype])
+    }
+
+    /// Unique identifiers stored for any of `filetypes`, for buffers having several
+    pub fn identifiers_for_filetypes<S: AsRef<str>>(&self, filetypes: &[S]) -> HashSet<&str> {
+        filetypes
+            .iter()
+            .filter_map(|f| self.filetypes.get(f.as_ref()))
+            .flat_map(|files| files.values())
+            .flat_map(|counts| counts.keys())
+            .map(String::as_str)
+            .collect()
+    }
+
+    /// Write identifiers of files that exist on disk to `path`
+    pub fn save_cache(&self, path: &Path) -> Result<(), anyhow::Error> {
+        let mut cached = vec![];
+        for (filetype, files) in &self.filetypes {
+            for (filepath, identifiers) in files {
+                let metadata = match std::fs::metadata(filepath) {
+                    Ok(m) => m,
+                    Err(_) => continue,
+                };
+                cached.push(CachedFile {
+                    filetype: filetype.clone(),
+                    filepath: filepath.clone(),
+                    modified: metadata.modified()?,
+                    len: metadata.len(),
+                    identifiers: identifiers.clone(),
+                });
+            }
+        }
+        bincode::serialize_into(BufWriter::new(File::create(path)?), &cached)?;
+        Ok(())
+    }
+
+    /// Database with the identifiers cached in `path` by `save_cache`,
+    /// skipping files changed since. Starts empty if the cache can't be read
+    pub fn load_cache(path: &Path) -> Self {
+        let cached: Vec<CachedFile> = match File::open(path)
+            .map_err(anyhow::Error::from)
+            .and_then(|f| Ok(bincode::deserialize_from(BufReader::new(f))?))
+        {
+            Ok(cached) => cached,
+            Err(e) => {
+                warn!("Ignoring identifier cache {}: {}", path.display(), e);
+                return Self::default();
+            }
+        };
+        let mut db = Self::default();
+        for file in cached.into_iter().filter(CachedFile::is_fresh) {
+            db.filetypes
+                .entry(file.filetype)
+                .or_default()
+                .insert(file.filepath, file.identifiers);
+        }
+        db
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::utils::identifier::identifiers_in_text;
+
+    fn strings(s: &[&str]) -> Vec<String> {
+        s.iter().map(|s| String::from(*s)).collect()
+    }
+
+    #[test]
+    fn clear_and_add() {
+        let mut db = IdentifierDatabase::default();
+        db.add_identifiers("rust", "/a.rs", strings(&["foo", "bar"]));
+        db.add_identifiers("rust", "/b.rs", strings(&["foo", "baz"]));
+        db.add_identifiers("c", "/a.c", strings(&["qux"]));
+
+        let expected: HashSet<_> = vec!["foo", "bar", "baz"].into_iter().collect();
+        assert_eq!(db.identifiers("rust"), expected);
+
+        db.clear_and_add_identifiers("rust", "/a.rs", strings(&["zzz"]));
+        let expected: HashSet<_> = vec!["foo", "baz", "zzz"].into_iter().collect();
+        assert_eq!(db.identifiers("rust"), expected);
+
+        db.clear_file("/b.rs");
+        let expected: HashSet<_> = vec!["zzz"].into_iter().collect();
+        assert_eq!(db.identifiers("rust"), expected);
+        assert!(db.identifiers("python").is_empty());
+    }
+
+    #[test]
+    fn identifiers_for_several_filetypes() {
+        let mut db = IdentifierDatabase::default();
+        db.add_identifiers("html", "/a.html", strings(&["div", "shared"]));
+        db.add_identifiers("javascript", "/a.js", strings(&["window", "shared"]));
+        db.add_identifiers("css", "/a.css", strings(&["color"]));
+
+        let expected: HashSet<_> = vec!["div", "window", "shared"].into_iter().collect();
+        assert_eq!(
+            db.identifiers_for_filetypes(&["html", "javascript"]),
+            expected
+        );
+        assert!(db.identifiers_for_filetypes::<&str>(&[]).is_empty());
+    }
+
+    /// Applies `edits` incrementally, then as whole new contents,
+    /// and checks both results against `identifiers_in_text`
+    fn assert_incremental(text: &str, edits: &[(usize, usize, &str)]) {
+        let mut db = IdentifierDatabase::default();
+        let mut by_contents = IdentifierDatabase::default();
+        let mut expected_text = text.to_string();
+        db.set_contents("cpp", "/a.cpp", text.to_string(), 0);
+        by_contents.set_contents("cpp", "/a.cpp", text.to_string(), 0);
+        for &(start, end, new_text) in edits {
+            db.update_contents("/a.cpp", start, end, new_text).unwrap();
+            expected_text.replace_range(start..end, new_text);
+            by_contents.set_contents("cpp", "/a.cpp", expected_text.clone(), 0);
+        }
+
+        let identifiers = identifiers_in_text(&expected_text, Some("cpp"));
+        let expected = identifiers
+            .iter()
+            .map(String::as_str)
+            .collect::<HashSet<_>>();
+        assert_eq!(db.identifiers("cpp"), expected);
+        assert_eq!(by_contents.identifiers("cpp"), expected);
+    }
+
+    #[test]
+    fn update_contents_matches_full_parse() {
+        let text = "int foo = bar;\n// comment\nint baz = qux;\n";
+        // Replace `bar` within a line
+        assert_incremental(text, &[(10, 13, "quux")]);
+        // Delete from the middle of `foo` to the middle of `qux`
+        assert_incremental(text, &[(5, 36, "")]);
+        // Multi-line insert, then remove one of the inserted lines
+        assert_incremental(text, &[(14, 14, "\nint a1;\nint a2;"), (15, 23, "")]);
+        // Identifier repeated on another line survives removal of one copy
+        assert_incremental("foo\nfoo\n", &[(0, 4, "")]);
+        // Opening a comment hides the lines up to its end, closing it shows them again
+        assert_incremental(text, &[(0, 0, "/*\n"), (31, 31, "*/")]);
+        assert_incremental(text, &[(0, 0, "/*\n"), (31, 31, "*/"), (0, 3, "")]);
+        // Strings spanning lines too
+        assert_incremental("a \"b\nc\" d\ne\n", &[(2, 3, ""), (4, 5, "")]);
+    }
+
+    #[test]
+    fn multiline_comments_skipped() {
+        let mut db = IdentifierDatabase::default();
+        let text = "int foo; /* bar\nbaz */ int qux;\n";
+        db.set_contents("cpp", "/a.cpp", String::from(text), 2);
+        let expected: HashSet<_> = vec!["int", "foo", "qux"].into_iter().collect();
+        assert_eq!(db.identifiers("cpp"), expected);
+    }
+
+    #[test]
+    fn cache_drops_modified_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = |name: &str| dir.path().join(name).to_str().unwrap().to_string();
+        std::fs::write(file("a.rs"), "foo").unwrap();
+        std::fs::write(file("b.rs"), "bar").unwrap();
+        let mut db = IdentifierDatabase::default();
+        db.add_identifiers("rust", &file("a.rs"), strings(&["foo"]));
+        db.add_identifiers("rust", &file("b.rs"), strings(&["bar"]));
+        db.add_identifiers("rust", &file("gone.rs"), strings(&["baz"]));
+        let cache = dir.path().join("identifiers.cache");
+        db.save_cache(&cache).unwrap();
+
+        std::fs::write(file("b.rs"), "bar quux").unwrap();
+        let expected: HashSet<_> = vec!["foo"].into_iter().collect();
+        assert_eq!(
+            IdentifierDatabase::load_cache(&cache).identifiers("rust"),
+            expected
+        );
+    }
+
+    #[test]
+    fn corrupt_cache_starts_empty() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = dir.path().join("identifiers.cache");
+        std::fs::write(&cache, "not a cache").unwrap();
+        assert!(IdentifierDatabase::load_cache(&cache)
+            .identifiers("rust")
+            .is_empty());
+        assert!(IdentifierDatabase::load_cache(&dir.path().join("missing"))
+            .identifiers("rust")
+            .is_empty());
+    }
+
+    #[test]
+    fn update_contents_invalid() {
+        let mut db = IdentifierDatabase::default();
+        assert!(db.update_contents("/a.cpp", 0, 0, "foo").is_err());
+        db.set_contents("cpp", "/a.cpp", String::from("ä"), 0);
+        assert!(db.update_contents("/a.cpp", 1, 2, "").is_err());
+        assert!(db.update_contents("/a.cpp", 2, 0, "").is_err());
+        let expected: HashSet<_> = vec!["ä"].into_iter().collect();
+        assert_eq!(db.identifiers("cpp"), expected);
+    }
+}
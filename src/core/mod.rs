@@ -1,4 +1,5 @@
 pub mod candidate;
 pub mod character;
+pub mod identifier_database;
 pub mod query;
 pub mod utils;
@@ -1,33 +1,157 @@
+use std::borrow::Cow;
 use std::cmp::Ordering;
-
-use unicode_segmentation::UnicodeSegmentation;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
 
 use partial_sort::PartialSort;
 
-use super::{candidate::Candidate, character::Character};
+use super::{
+    candidate::Candidate,
+    character::{normalize, Character, Segmentation},
+};
 
 #[derive(PartialEq, Debug)]
 pub struct QueryResult<'a, 'b> {
     pub is_subsequence: bool,
     pub query_is_prefix: bool,
+    /// Query is a literal prefix of the candidate, these rank above everything else
+    pub is_exact_prefix: bool,
     pub first_char_is_same: bool,
+    /// Query is, ignoring case, the uppercase word boundary initials of the
+    /// candidate, like `ss` for `ServerState`
+    pub query_is_acronym: bool,
     pub char_match_index_sum: usize,
     pub num_wb_matches: usize,
+    /// How many times the candidate was used before, breaks ties between
+    /// otherwise equal results
+    pub usage_count: u32,
+    /// Position of the candidate in the input, the final tie breaker
+    pub index: usize,
     pub candidate: &'a Candidate<'a>,
     pub query: &'b Word<'b>,
 }
 
+/// Relative importance of each ranking criterion. Criteria are compared from
+/// the highest weight down, the ones of equal weight by their weighted sum, and
+/// a weight of 0 leaves a criterion out. The defaults keep the original ycmd
+/// ordering
+#[derive(serde::Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(default)]
+pub struct ScoringWeights {
+    pub first_char_is_same: f64,
+    /// See `QueryResult::query_is_acronym`
+    pub query_is_acronym: f64,
+    /// Every query character matched a word boundary, the fewer word boundaries
+    /// in the candidate the better
+    pub word_boundary_match: f64,
+    pub query_is_prefix: f64,
+    pub num_wb_matches: f64,
+    /// Penalty per word boundary character in the candidate
+    pub word_boundary_chars: f64,
+    /// Penalty per unit of char_match_index_sum
+    pub char_match_index_sum: f64,
+    /// Penalty per character in the candidate
+    pub candidate_length: f64,
+    pub text_is_lowercase: f64,
+}
+
+impl Default for ScoringWeights {
+    fn default() -> Self {
+        Self {
+            first_char_is_same: 128.0,
+            query_is_acronym: 64.0,
+            word_boundary_match: 32.0,
+            query_is_prefix: 16.0,
+            num_wb_matches: 8.0,
+            word_boundary_chars: 4.0,
+            char_match_index_sum: 2.0,
+            candidate_length: 1.0,
+            text_is_lowercase: 0.5,
+        }
+    }
+}
+
+/// How candidates are ordered when the query is empty, so that none is ranked
+/// above another by matching
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum EmptyOrder {
+    /// By usage count, then by text with its case swapped, so lowercase first
+    Lexicographic,
+    /// In the order they were given in, e.g. as a language server ranked them
+    AsGiven,
+}
+
+// `#[default]` on variants needs a newer compiler
+#[allow(clippy::derivable_impls)]
+impl Default for EmptyOrder {
+    fn default() -> Self {
+        EmptyOrder::Lexicographic
+    }
+}
+
+/// What matched where, for clients doing their own filtering or highlighting
+#[derive(serde::Serialize, Clone, PartialEq, Debug)]
+pub struct MatchMetadata {
+    pub query_is_prefix: bool,
+    pub num_wb_matches: usize,
+    /// Byte offsets of the matched characters in the candidate text
+    pub match_positions: Vec<usize>,
+}
+
 #[derive(PartialEq, Debug)]
 pub struct Word<'a> {
     pub characters: Vec<Character>,
-    pub text: &'a str,
+    pub text: Cow<'a, str>,
+    /// NFD form of `text`, if normalization changed it
+    pub normalized: Option<String>,
+    /// How `text` was split into `characters`, candidates are split the same way
+    pub segmentation: Segmentation,
 }
 
 impl<'a> Word<'a> {
     pub fn new(text: &'a str) -> Self {
+        Self::with_segmentation(text, Segmentation::default())
+    }
+
+    pub fn with_segmentation(text: impl Into<Cow<'a, str>>, segmentation: Segmentation) -> Self {
+        let text = text.into();
         Self {
-            characters: text.graphemes(true).map(Character::new).collect(),
+            characters: segmentation.characters(&text),
+            normalized: normalize(&text),
             text,
+            segmentation,
+        }
+    }
+
+    /// `text` in the same normal form characters are compared in
+    pub fn normalized_text(&self) -> &str {
+        self.normalized.as_deref().unwrap_or(&self.text)
+    }
+}
+
+impl Word<'static> {
+    pub fn new_owned(text: String) -> Self {
+        Self::with_segmentation(text, Segmentation::default())
+    }
+}
+
+/// Keeps the last parsed query around, as consecutive requests
+/// usually come with the same query
+#[derive(Default)]
+pub struct WordCache {
+    last: Mutex<Option<Arc<Word<'static>>>>,
+}
+
+impl WordCache {
+    pub fn get(&self, query: &str, segmentation: Segmentation) -> Arc<Word<'static>> {
+        let mut last = self.last.lock().unwrap();
+        match &*last {
+            Some(word) if word.text == query && word.segmentation == segmentation => word.clone(),
+            _ => {
+                let word = Arc::new(Word::with_segmentation(query.to_owned(), segmentation));
+                *last = Some(word.clone());
+                word
+            }
         }
     }
 }
@@ -42,15 +166,36 @@ impl Default for QueryResult<'_, '_> {
         Self {
             is_subsequence: false,
             query_is_prefix: false,
+            is_exact_prefix: false,
             first_char_is_same: false,
+            query_is_acronym: false,
             char_match_index_sum: 0,
             num_wb_matches: 0,
+            usage_count: 0,
+            index: 0,
             candidate: &EMPTY_CANDIDATE,
             query: &EMPTY_WORD,
         }
     }
 }
 
+/// Whether `query` spells the uppercase word boundary initials of `candidate`,
+/// ignoring case. A single initial is just the first character, so it takes at
+/// least two
+fn is_acronym(candidate: &Candidate, query: &Word) -> bool {
+    let initials = candidate
+        .word_boundary_chars
+        .iter()
+        .filter(|c| c.is_uppercase)
+        .collect::<Vec<_>>();
+    initials.len() > 1
+        && initials.len() == query.characters.len()
+        && initials
+            .iter()
+            .zip(&query.characters)
+            .all(|(c, q)| c.folded_case == q.folded_case)
+}
+
 impl<'a, 'b> QueryResult<'a, 'b> {
     pub fn new(
         is_subsequence: bool,
@@ -70,149 +215,276 @@ impl<'a, 'b> QueryResult<'a, 'b> {
                         .len();
                 (num_wb_matches, first_char_is_same)
             };
+        let query_is_acronym = is_acronym(candidate, query);
 
         Self {
             is_subsequence,
             query_is_prefix,
+            is_exact_prefix: query_is_prefix
+                && candidate
+                    .normalized_text()
+                    .starts_with(query.normalized_text()),
             first_char_is_same,
+            query_is_acronym,
             char_match_index_sum,
             num_wb_matches,
+            usage_count: 0,
+            index: 0,
             candidate,
             query,
         }
     }
 }
 
-impl PartialOrd for QueryResult<'_, '_> {
-    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        if !self.query.text.is_empty() {
-            match self.first_char_is_same.cmp(&other.first_char_is_same) {
-                Ordering::Less => return Some(Ordering::Greater),
-                Ordering::Greater => return Some(Ordering::Less),
-                Ordering::Equal => {}
-            }
-
-            if self.num_wb_matches == self.query.characters.len()
-                || other.num_wb_matches == other.query.characters.len()
-            {
-                match self.num_wb_matches.cmp(&other.num_wb_matches) {
-                    Ordering::Less => return Some(Ordering::Greater),
-                    Ordering::Greater => return Some(Ordering::Less),
-                    Ordering::Equal => {}
-                };
-                match self
-                    .candidate
-                    .word_boundary_chars
-                    .len()
-                    .cmp(&other.candidate.word_boundary_chars.len())
-                {
-                    o @ (Ordering::Less | Ordering::Greater) => return Some(o),
-                    Ordering::Equal => {}
-                };
-            }
-
-            match self.query_is_prefix.cmp(&other.query_is_prefix) {
-                Ordering::Less => return Some(Ordering::Greater),
-                Ordering::Greater => return Some(Ordering::Less),
-                Ordering::Equal => {}
-            }
+impl QueryResult<'_, '_> {
+    pub fn metadata(&self) -> MatchMetadata {
+        MatchMetadata {
+            query_is_prefix: self.query_is_prefix,
+            num_wb_matches: self.num_wb_matches,
+            match_positions: self.candidate.match_positions(self.query),
+        }
+    }
 
-            match self.num_wb_matches.cmp(&other.num_wb_matches) {
-                Ordering::Less => return Some(Ordering::Greater),
-                Ordering::Greater => return Some(Ordering::Less),
-                Ordering::Equal => {}
-            };
+    /// Each ranking criterion as its weight and how well the result does by it,
+    /// higher being better
+    fn criteria(&self, weights: &ScoringWeights) -> [(f64, f64); 9] {
+        let flag = |b: bool| if b { 1.0 } else { 0.0 };
+        let word_boundary_chars = self.candidate.word_boundary_chars.len() as f64;
+        let word_boundary_match = if self.num_wb_matches == self.query.characters.len() {
+            1.0 / (1.0 + word_boundary_chars)
+        } else {
+            0.0
+        };
+        [
+            (weights.first_char_is_same, flag(self.first_char_is_same)),
+            (weights.query_is_acronym, flag(self.query_is_acronym)),
+            (weights.word_boundary_match, word_boundary_match),
+            (weights.query_is_prefix, flag(self.query_is_prefix)),
+            (weights.num_wb_matches, self.num_wb_matches as f64),
+            (weights.word_boundary_chars, -word_boundary_chars),
+            (
+                weights.char_match_index_sum,
+                -(self.char_match_index_sum as f64),
+            ),
+            (
+                weights.candidate_length,
+                -(self.candidate.characters.len() as f64),
+            ),
+            (
+                weights.text_is_lowercase,
+                flag(self.candidate.text_is_lowercase),
+            ),
+        ]
+    }
 
-            match self
-                .candidate
-                .word_boundary_chars
-                .len()
-                .cmp(&other.candidate.word_boundary_chars.len())
-            {
-                o @ (Ordering::Less | Ordering::Greater) => return Some(o),
-                Ordering::Equal => {}
-            };
+    /// Weighted sum of every criterion
+    pub fn score(&self, weights: &ScoringWeights) -> f64 {
+        weighed(&self.criteria(weights), |_| true)
+    }
 
-            match self.char_match_index_sum.cmp(&other.char_match_index_sum) {
-                o @ (Ordering::Less | Ordering::Greater) => return Some(o),
-                Ordering::Equal => {}
-            };
+    /// Like `cmp_with_weights`, with `empty_order` deciding between results of an empty query
+    pub fn cmp_with_order(
+        &self,
+        other: &Self,
+        weights: &ScoringWeights,
+        empty_order: EmptyOrder,
+    ) -> Ordering {
+        if self.query.text.is_empty() && empty_order == EmptyOrder::AsGiven {
+            self.index.cmp(&other.index)
+        } else {
+            self.cmp_with_weights(other, weights)
+        }
+    }
 
-            match self
-                .candidate
-                .characters
-                .len()
-                .cmp(&other.candidate.characters.len())
-            {
-                o @ (Ordering::Less | Ordering::Greater) => return Some(o),
+    /// Better results compare as `Less`, same as `partial_cmp` with default
+    /// weights. See `ScoringWeights` for how criteria are weighed
+    pub fn cmp_with_weights(&self, other: &Self, weights: &ScoringWeights) -> Ordering {
+        if !self.query.text.is_empty() {
+            match self.is_exact_prefix.cmp(&other.is_exact_prefix) {
+                Ordering::Less => return Ordering::Greater,
+                Ordering::Greater => return Ordering::Less,
                 Ordering::Equal => {}
             }
 
-            match self
-                .candidate
-                .text_is_lowercase
-                .cmp(&other.candidate.text_is_lowercase)
-            {
-                Ordering::Less => return Some(Ordering::Greater),
-                Ordering::Greater => return Some(Ordering::Less),
-                Ordering::Equal => {}
-            };
+            let (ours, theirs) = (self.criteria(weights), other.criteria(weights));
+            let mut tiers = ours;
+            tiers.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(Ordering::Equal));
+            let mut last = None;
+            for &(weight, _) in tiers.iter().filter(|(w, _)| *w != 0.0) {
+                if last.replace(weight) == Some(weight) {
+                    continue;
+                }
+                let tier = |w: f64| w == weight;
+                if let Some(o @ (Ordering::Less | Ordering::Greater)) =
+                    weighed(&theirs, tier).partial_cmp(&weighed(&ours, tier))
+                {
+                    return o;
+                }
+            }
         }
-        Some(
-            self.candidate
-                .case_swapped
-                .cmp(&other.candidate.case_swapped),
-        )
+        other
+            .usage_count
+            .cmp(&self.usage_count)
+            .then_with(|| {
+                self.candidate
+                    .case_swapped
+                    .cmp(&other.candidate.case_swapped)
+            })
+            .then_with(|| self.index.cmp(&other.index))
+    }
+}
+
+impl PartialOrd for QueryResult<'_, '_> {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp_with_weights(other, &ScoringWeights::default()))
     }
 }
 
+/// Weighted sum of the `criteria` whose weight passes `filter`
+fn weighed(criteria: &[(f64, f64)], filter: impl Fn(f64) -> bool) -> f64 {
+    criteria
+        .iter()
+        .filter(|(w, _)| filter(*w))
+        .map(|(w, value)| w * value)
+        .sum()
+}
+
+/// Exact prefix matches sort above everything else, so once there are enough of
+/// them there is no point in scoring the rest
+fn only_exact_prefixes<'c>(
+    candidates: impl Iterator<Item = &'c Candidate<'c>>,
+    query: &Word,
+    max_candidates: usize,
+) -> bool {
+    !query.text.is_empty()
+        && candidates
+            .filter(|c| c.starts_with(query))
+            .take(max_candidates)
+            .count()
+            == max_candidates
+}
+
+/// Only the first `max_candidates` results are guaranteed to be sorted
+/// or even present. `candidates` should be segmented like `query`
 pub fn filter_and_sort_candidates<'a, 'b>(
     candidates: &'a [Candidate],
     query: &'b Word,
     max_candidates: usize,
+    weights: &ScoringWeights,
+    usage: Option<&HashMap<String, u32>>,
+    empty_order: EmptyOrder,
 ) -> Vec<QueryResult<'a, 'b>> {
+    let prefixes_only = only_exact_prefixes(candidates.iter(), query, max_candidates);
     let mut results = candidates
         .iter()
-        .map(|c| c.matches_query(query))
+        .enumerate()
+        .filter(|(_, c)| !prefixes_only || c.starts_with(query))
+        .map(|(i, c)| QueryResult {
+            index: i,
+            ..c.matches_query(query)
+        })
         .filter(|r| r.is_subsequence)
+        .map(|mut r| {
+            if let Some(&count) = usage.and_then(|u| u.get(r.candidate.text)) {
+                r.usage_count = count;
+            }
+            r
+        })
         .collect::<Vec<_>>();
 
     let max_candidates = max_candidates.min(results.len());
-    results.partial_sort(max_candidates, |a, b| a.partial_cmp(b).unwrap());
+    results.partial_sort(max_candidates, |a, b| {
+        a.cmp_with_order(b, weights, empty_order)
+    });
     results
 }
 
-// This impl is a little ugly, need to revisit later
+/// How `filter_and_sort_generic_candidates_with_metadata` filters and ranks
+/// candidates
+#[derive(Clone, Copy, Default)]
+pub struct FilterOptions {
+    pub max_candidates: usize,
+    pub weights: ScoringWeights,
+    /// Match metadata is returned along with each candidate
+    pub include_metadata: bool,
+    pub empty_order: EmptyOrder,
+}
+
 pub fn filter_and_sort_generic_candidates<T, F>(
     candidates: Vec<T>,
-    query: &str,
+    query: &Word,
     max_candidates: usize,
+    weights: &ScoringWeights,
     f: F,
 ) -> Vec<T>
 where
     F: for<'b> Fn(&'b T) -> &'b str,
 {
-    let query = Word::new(query);
+    let options = FilterOptions {
+        max_candidates,
+        weights: *weights,
+        ..FilterOptions::default()
+    };
+    filter_and_sort_generic_candidates_with_metadata(candidates, query, &options, f)
+        .into_iter()
+        .map(|(c, _)| c)
+        .collect()
+}
+
+/// Same as `filter_and_sort_generic_candidates`, optionally returning
+/// match metadata for each candidate. Candidates are segmented like `query`
+// This impl is a little ugly, need to revisit later
+pub fn filter_and_sort_generic_candidates_with_metadata<T, F>(
+    candidates: Vec<T>,
+    query: &Word,
+    options: &FilterOptions,
+    f: F,
+) -> Vec<(T, Option<MatchMetadata>)>
+where
+    F: for<'b> Fn(&'b T) -> &'b str,
+{
+    let FilterOptions {
+        max_candidates,
+        ref weights,
+        include_metadata,
+        empty_order,
+    } = *options;
     let parsed_candidates = candidates
         .iter()
         .enumerate()
-        .map(|(i, c)| (i, Candidate::new(f(c))))
+        .map(|(i, c)| (i, Candidate::with_segmentation(f(c), query.segmentation)))
         .collect::<Vec<_>>();
 
+    let prefixes_only = only_exact_prefixes(
+        parsed_candidates.iter().map(|(_, parsed)| parsed),
+        query,
+        max_candidates,
+    );
     let mut results = parsed_candidates
         .iter()
-        .map(|(i, parsed)| (i, parsed.matches_query(&query)))
+        .filter(|(_, parsed)| !prefixes_only || parsed.starts_with(query))
+        .map(|(i, parsed)| {
+            (
+                i,
+                QueryResult {
+                    index: *i,
+                    ..parsed.matches_query(query)
+                },
+            )
+        })
         .filter(|(_, q)| q.is_subsequence)
         .collect::<Vec<_>>();
 
     let max_candidates = max_candidates.min(results.len());
-    results.partial_sort(max_candidates, |a, b| a.1.partial_cmp(&b.1).unwrap());
+    results.partial_sort(max_candidates, |a, b| {
+        a.1.cmp_with_order(&b.1, weights, empty_order)
+    });
 
     #[allow(clippy::needless_collect)]
     let results = results
         .into_iter()
         .take(max_candidates)
-        .map(|(i, _)| *i)
+        .map(|(i, r)| (*i, include_metadata.then(|| r.metadata())))
         .collect::<Vec<_>>();
 
     //drop references to candidates
@@ -222,7 +494,72 @@ where
 
     results
         .into_iter()
-        .map(|i| unsafe { candidates.get_unchecked_mut(i) }.take().unwrap())
+        .map(|(i, metadata)| {
+            (
+                unsafe { candidates.get_unchecked_mut(i) }.take().unwrap(),
+                metadata,
+            )
+        })
+        .collect()
+}
+
+/// Merge `lists`, each already filtered and ranked by `query`, into a single
+/// ranked list. Nothing is left out and every list keeps its own order, only
+/// their heads being compared to pick the next candidate
+pub fn merge_ranked_generic_candidates<T, F>(
+    lists: Vec<Vec<T>>,
+    query: &Word,
+    weights: &ScoringWeights,
+    empty_order: EmptyOrder,
+    f: F,
+) -> Vec<T>
+where
+    F: for<'b> Fn(&'b T) -> &'b str,
+{
+    let order = {
+        let parsed = lists
+            .iter()
+            .map(|list| {
+                list.iter()
+                    .map(|c| Candidate::with_segmentation(f(c), query.segmentation))
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        // Ties go to the earlier list
+        let mut index = 0;
+        let results = parsed
+            .iter()
+            .map(|list| {
+                list.iter()
+                    .map(|c| {
+                        index += 1;
+                        QueryResult {
+                            index,
+                            ..c.matches_query(query)
+                        }
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+
+        let mut heads = vec![0; results.len()];
+        let mut order = Vec::with_capacity(index);
+        while let Some(list) = (0..results.len())
+            .filter(|&l| heads[l] < results[l].len())
+            .min_by(|&a, &b| {
+                results[a][heads[a]].cmp_with_order(&results[b][heads[b]], weights, empty_order)
+            })
+        {
+            heads[list] += 1;
+            order.push(list);
+        }
+        order
+    };
+
+    let mut lists = lists.into_iter().map(Vec::into_iter).collect::<Vec<_>>();
+    order
+        .into_iter()
+        .filter_map(|list| lists[list].next())
         .collect()
 }
 
@@ -252,8 +589,15 @@ mod tests {
             .collect::<Vec<_>>();
         let q = Word::new("ab");
 
-        let results = filter_and_sort_candidates(&candidates, &q, usize::MAX);
-        let expected_candidates = vec!["A , B", "ab", "Ab", "acb", "bab"];
+        let results = filter_and_sort_candidates(
+            &candidates,
+            &q,
+            usize::MAX,
+            &ScoringWeights::default(),
+            None,
+            EmptyOrder::Lexicographic,
+        );
+        let expected_candidates = vec!["ab", "A , B", "Ab", "acb", "bab"];
         let result_strings = results
             .into_iter()
             .map(|r| r.candidate.text)
@@ -270,10 +614,16 @@ mod tests {
         let candidates = std::array::IntoIter::new(["acb", "ab", "Ab", "bab", "A , B", "BA"])
             .map(|c| C { c: String::from(c) })
             .collect::<Vec<_>>();
-        let q = "ab";
+        let q = Word::new("ab");
 
-        let results = filter_and_sort_generic_candidates(candidates, &q, 3, |c| &c.c);
-        let expected_candidates = std::array::IntoIter::new(["A , B", "ab", "Ab"])
+        let results = filter_and_sort_generic_candidates(
+            candidates,
+            &q,
+            3,
+            &ScoringWeights::default(),
+            |c| &c.c,
+        );
+        let expected_candidates = std::array::IntoIter::new(["ab", "A , B", "Ab"])
             .map(|c| C { c: String::from(c) })
             .collect::<Vec<_>>();
         assert_eq!(expected_candidates, results);
@@ -287,7 +637,14 @@ mod tests {
                 .collect::<Vec<_>>();
         let q = Word::new("");
 
-        let results = filter_and_sort_candidates(&candidates, &q, usize::MAX);
+        let results = filter_and_sort_candidates(
+            &candidates,
+            &q,
+            usize::MAX,
+            &ScoringWeights::default(),
+            None,
+            EmptyOrder::Lexicographic,
+        );
         let expected_candidates = vec!["Ähnlich", "Äpfel", "Bären", "Käfer", "Küssen"];
         let result_strings = results
             .into_iter()
@@ -295,4 +652,294 @@ mod tests {
             .collect::<Vec<_>>();
         assert_eq!(expected_candidates, result_strings);
     }
+
+    #[test]
+    fn test_custom_weights_flip_order() {
+        let candidates = ["a_b", "Abc"]
+            .iter()
+            .map(|c| Candidate::new(c))
+            .collect::<Vec<_>>();
+        let q = Word::new("ab");
+        let texts = |weights: &ScoringWeights| {
+            filter_and_sort_candidates(
+                &candidates,
+                &q,
+                usize::MAX,
+                weights,
+                None,
+                EmptyOrder::Lexicographic,
+            )
+            .into_iter()
+            .map(|r| r.candidate.text)
+            .collect::<Vec<_>>()
+        };
+
+        assert_eq!(vec!["a_b", "Abc"], texts(&ScoringWeights::default()));
+        let prefer_prefix = ScoringWeights {
+            query_is_prefix: 100.0,
+            ..ScoringWeights::default()
+        };
+        assert_eq!(vec!["Abc", "a_b"], texts(&prefer_prefix));
+        // Equal weights only compare the sum of all criteria
+        let equal = ScoringWeights {
+            first_char_is_same: 1.0,
+            query_is_acronym: 1.0,
+            word_boundary_match: 1.0,
+            query_is_prefix: 1.0,
+            num_wb_matches: 1.0,
+            word_boundary_chars: 1.0,
+            char_match_index_sum: 1.0,
+            candidate_length: 1.0,
+            text_is_lowercase: 1.0,
+        };
+        assert_eq!(vec!["Abc", "a_b"], texts(&equal));
+    }
+
+    #[test]
+    fn test_acronym_match() {
+        let candidates = ["srv_state", "ServerState", "sessions"]
+            .iter()
+            .map(|c| Candidate::new(c))
+            .collect::<Vec<_>>();
+        let texts = |query: &str| {
+            let q = Word::new(query);
+            filter_and_sort_candidates(
+                &candidates,
+                &q,
+                usize::MAX,
+                &ScoringWeights::default(),
+                None,
+                EmptyOrder::Lexicographic,
+            )
+            .into_iter()
+            .map(|r| r.candidate.text)
+            .collect::<Vec<_>>()
+        };
+
+        assert_eq!(vec!["ServerState", "srv_state", "sessions"], texts("ss"));
+        assert_eq!(vec!["ServerState"], texts("SS"));
+        // Not every initial, so no boost over the shorter match
+        assert_eq!(vec!["srv_state", "ServerState"], texts("st"));
+    }
+
+    #[test]
+    fn test_acronym_weight() {
+        let candidates = ["srv_state", "ServerState", "sessions"]
+            .iter()
+            .map(|c| Candidate::new(c))
+            .collect::<Vec<_>>();
+        let q = Word::new("ss");
+        let texts = |query_is_acronym| {
+            let weights = ScoringWeights {
+                query_is_acronym,
+                ..ScoringWeights::default()
+            };
+            filter_and_sort_candidates(
+                &candidates,
+                &q,
+                usize::MAX,
+                &weights,
+                None,
+                EmptyOrder::Lexicographic,
+            )
+            .into_iter()
+            .map(|r| r.candidate.text)
+            .collect::<Vec<_>>()
+        };
+
+        assert_eq!(vec!["ServerState", "srv_state", "sessions"], texts(200.0));
+        assert_eq!(vec!["srv_state", "ServerState", "sessions"], texts(0.0));
+    }
+
+    #[test]
+    fn test_generic_candidates_segmented_like_query() {
+        let candidates = vec!["a\u{1f1fa}\u{1f1f8}b", "ab"];
+        let texts = |segmentation| {
+            let q = Word::with_segmentation("a\u{1f1fa}b", segmentation);
+            filter_and_sort_generic_candidates(
+                candidates.clone(),
+                &q,
+                usize::MAX,
+                &ScoringWeights::default(),
+                |c| c,
+            )
+        };
+
+        assert!(texts(Segmentation::Grapheme).is_empty());
+        assert_eq!(texts(Segmentation::Codepoint), vec!["a\u{1f1fa}\u{1f1f8}b"]);
+    }
+
+    #[test]
+    fn test_word_cache() {
+        let cache = WordCache::default();
+        let word = cache.get("ab", Segmentation::Grapheme);
+        assert_eq!(word.text, "ab");
+        assert!(Arc::ptr_eq(&word, &cache.get("ab", Segmentation::Grapheme)));
+        assert!(!Arc::ptr_eq(
+            &word,
+            &cache.get("abc", Segmentation::Grapheme)
+        ));
+        let codepoints = cache.get("abc", Segmentation::Codepoint);
+        assert_eq!(codepoints.segmentation, Segmentation::Codepoint);
+    }
+
+    #[test]
+    fn test_exact_prefix_fast_path() {
+        let candidates = ["xab", "abXy", "aXb", "ab_x", "abc"]
+            .iter()
+            .map(|c| Candidate::new(c))
+            .collect::<Vec<_>>();
+        let q = Word::new("ab");
+        let texts = |max_candidates| {
+            filter_and_sort_candidates(
+                &candidates,
+                &q,
+                max_candidates,
+                &ScoringWeights::default(),
+                None,
+                EmptyOrder::Lexicographic,
+            )
+            .into_iter()
+            .take(max_candidates)
+            .map(|r| r.candidate.text)
+            .collect::<Vec<_>>()
+        };
+
+        let all = texts(usize::MAX);
+        assert_eq!(all[..3], texts(3)[..]);
+        assert!(all[..3].iter().all(|c| c.starts_with("ab")));
+        assert_eq!(
+            filter_and_sort_candidates(
+                &candidates,
+                &q,
+                3,
+                &ScoringWeights::default(),
+                None,
+                EmptyOrder::Lexicographic
+            )
+            .len(),
+            3
+        );
+    }
+
+    #[test]
+    fn test_empty_query_order() {
+        let candidates = ["foo", "Bar", "baz", "abc"]
+            .iter()
+            .map(|c| Candidate::new(c))
+            .collect::<Vec<_>>();
+        let q = Word::new("");
+        let texts = |empty_order| {
+            filter_and_sort_candidates(
+                &candidates,
+                &q,
+                usize::MAX,
+                &ScoringWeights::default(),
+                None,
+                empty_order,
+            )
+            .into_iter()
+            .map(|r| r.candidate.text)
+            .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            vec!["abc", "baz", "foo", "Bar"],
+            texts(EmptyOrder::Lexicographic)
+        );
+        assert_eq!(vec!["foo", "Bar", "baz", "abc"], texts(EmptyOrder::AsGiven));
+
+        // Only empty queries keep the given order
+        let q = Word::new("ba");
+        let results = filter_and_sort_candidates(
+            &candidates,
+            &q,
+            usize::MAX,
+            &ScoringWeights::default(),
+            None,
+            EmptyOrder::AsGiven,
+        );
+        assert_eq!(
+            vec!["baz", "Bar"],
+            results
+                .into_iter()
+                .map(|r| r.candidate.text)
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_usage_breaks_ties() {
+        let candidates = ["foo_b", "foo_a"]
+            .iter()
+            .map(|c| Candidate::new(c))
+            .collect::<Vec<_>>();
+        let q = Word::new("fo");
+        let texts = |usage| {
+            filter_and_sort_candidates(
+                &candidates,
+                &q,
+                usize::MAX,
+                &ScoringWeights::default(),
+                usage,
+                EmptyOrder::Lexicographic,
+            )
+            .into_iter()
+            .map(|r| r.candidate.text)
+            .collect::<Vec<_>>()
+        };
+
+        assert_eq!(vec!["foo_a", "foo_b"], texts(None));
+        let mut usage = HashMap::new();
+        usage.insert(String::from("foo_b"), 2);
+        usage.insert(String::from("foo_a"), 1);
+        assert_eq!(vec!["foo_b", "foo_a"], texts(Some(&usage)));
+    }
+
+    #[test]
+    fn test_equal_candidates_keep_input_order() {
+        #[derive(Debug)]
+        struct C {
+            text: &'static str,
+            id: usize,
+        }
+        let candidates = (0..32)
+            .map(|id| C {
+                text: if id % 2 == 0 { "foo" } else { "f_o_o" },
+                id,
+            })
+            .collect::<Vec<_>>();
+
+        let results = filter_and_sort_generic_candidates(
+            candidates,
+            &Word::new("foo"),
+            10,
+            &ScoringWeights::default(),
+            |c| c.text,
+        );
+        let ids = results.into_iter().map(|c| c.id).collect::<Vec<_>>();
+        assert_eq!(ids, vec![0, 2, 4, 6, 8, 10, 12, 14, 16, 18]);
+    }
+
+    #[test]
+    fn test_merge_ranked_keeps_list_order() {
+        let texts = |lists: Vec<Vec<&'static str>>| {
+            merge_ranked_generic_candidates(
+                lists,
+                &Word::new("ab"),
+                &ScoringWeights::default(),
+                EmptyOrder::default(),
+                |c| c,
+            )
+        };
+
+        assert_eq!(
+            texts(vec![vec!["ab", "acb"], vec!["Ab", "bab"]]),
+            vec!["ab", "Ab", "acb", "bab"]
+        );
+        // Neither reordered nor filtered within a list
+        assert_eq!(
+            texts(vec![vec!["bab", "xyz"], vec!["ab"]]),
+            vec!["ab", "bab", "xyz"]
+        );
+    }
 }
@@ -1,7 +1,8 @@
 use std::collections::HashMap;
-use std::ops::Deref;
+use std::ops::{Deref, Range};
 
 use regex::{Captures, Regex, RegexBuilder};
+use unicode_segmentation::GraphemeCursor;
 
 const C_STYLE_COMMENT: &str = "(/\\*(?:\n|.)*?\\*/)";
 const CPP_STYLE_COMMENT: &str = "(//.*?$)";
@@ -26,6 +27,15 @@ const BACK_QUOTE_STRING: &str = r#"(?:[^\\])(`(?:\\\\|\\`|.)*?`)"#;
 const MULTILINE_SINGLE_QUOTE_STRING: &str = "('''(?:\n|.)*?''')";
 // Python-style multiline double-quote string
 const MULTILINE_DOUBLE_QUOTE_STRING: &str = r#"("""(?:\n|.)*?""")"#;
+// SQL line comment
+const SQL_STYLE_COMMENT: &str = "(--.*?$)";
+// SQL string, '...', in which a single quote is escaped by doubling it ('')
+// rather than with a backslash
+const SQL_SINGLE_QUOTE_STRING: &str = "('(?:''|[^'])*')";
+// YAML comment, which has to be separated from any preceding token by white space
+const YAML_STYLE_COMMENT: &str = r"(?:^|\s)(#.*?$)";
+// Markdown fenced code block, from its opening fence line through the closing one
+const FENCED_CODE_BLOCK: &str = r"^(?:```|~~~).*$(?:\n|.)*?^(?:```|~~~).*$";
 
 type RE = &'static (dyn Deref<Target = Regex> + Sync);
 
@@ -83,6 +93,29 @@ static ref RUST_COMMENT_AND_STRING_REGEX: Regex = RegexBuilder::new(&[ CPP_STYLE
                                                             ].join("|"))
     .multi_line(true).build().unwrap();
 
+// Spec:
+// https://www.postgresql.org/docs/current/sql-syntax-lexical.html
+// Double quoted strings are identifiers, so they are kept.
+static ref SQL_COMMENT_AND_STRING_REGEX: Regex = RegexBuilder::new(&[ C_STYLE_COMMENT,
+                                                               SQL_STYLE_COMMENT,
+                                                               SQL_SINGLE_QUOTE_STRING
+                                                            ].join("|"))
+    .multi_line(true).build().unwrap();
+
+// Spec:
+// https://yaml.org/spec/1.2.2/#66-comments
+// Quoted scalars are mostly keys and values worth completing, so they are kept.
+static ref YAML_COMMENT_AND_STRING_REGEX: Regex = RegexBuilder::new(YAML_STYLE_COMMENT)
+    .multi_line(true).build().unwrap();
+
+// Strict JSON has no comments and its keys are strings, so nothing is removed.
+static ref JSON_COMMENT_AND_STRING_REGEX: Regex = Regex::new(r"\z.").unwrap();
+
+// Prose has no comments or strings, and apostrophes are part of words. Only
+// code blocks are left out, their contents are not words.
+static ref MARKDOWN_COMMENT_AND_STRING_REGEX: Regex = RegexBuilder::new(FENCED_CODE_BLOCK)
+    .multi_line(true).build().unwrap();
+
 static ref FILETYPE_TO_COMMENT_AND_STRING_REGEX: HashMap<&'static str, RE> = {
 
     let mut map = HashMap::new();
@@ -93,7 +126,7 @@ static ref FILETYPE_TO_COMMENT_AND_STRING_REGEX: HashMap<&'static str, RE> = {
     map.insert("objc", &CPP_COMMENT_AND_STRING_REGEX);
     map.insert("objcpp", &CPP_COMMENT_AND_STRING_REGEX);
     map.insert("javascript", &CPP_COMMENT_AND_STRING_REGEX);
-    map.insert("typesript", &CPP_COMMENT_AND_STRING_REGEX);
+    map.insert("typescript", &CPP_COMMENT_AND_STRING_REGEX);
 
     map.insert("go", &GO_COMMENT_AND_STRING_REGEX);
 
@@ -101,6 +134,15 @@ static ref FILETYPE_TO_COMMENT_AND_STRING_REGEX: HashMap<&'static str, RE> = {
 
     map.insert("rust", &RUST_COMMENT_AND_STRING_REGEX);
 
+    map.insert("sql", &SQL_COMMENT_AND_STRING_REGEX);
+
+    map.insert("yaml", &YAML_COMMENT_AND_STRING_REGEX);
+
+    map.insert("json", &JSON_COMMENT_AND_STRING_REGEX);
+
+    map.insert("markdown", &MARKDOWN_COMMENT_AND_STRING_REGEX);
+    map.insert("text", &MARKDOWN_COMMENT_AND_STRING_REGEX);
+
     map
 };
 
@@ -156,6 +198,22 @@ static ref TEX_IDENTIFIER_REFEX: Regex = Regex::new( r"[^\W\d](?:[\w:-]*\w)?").u
 static ref PERL6_IDENTIFIER_REGEX: Regex = Regex::new( r"[_a-zA-Z](?:\w|[-'](?:[_a-zA-Z]))*",).unwrap();
 
 
+// Spec:
+// https://www.postgresql.org/docs/current/sql-syntax-lexical.html#SQL-SYNTAX-IDENTIFIERS
+// Quoted identifiers may contain anything but a double quote. Some dialects
+// (e.g. Oracle, PostgreSQL) accept dollar signs after the first character,
+// others (e.g. SQL Server variables, positional parameters) prefix names with one.
+static ref SQL_IDENTIFIER_REGEX: Regex = Regex::new( r#""[^"\n]+"|\$?[^\W\d][\w$]*"#).unwrap();
+
+// Keys of configuration files are often dashed (max-age) or refer to nested
+// keys with dots (spec.template.metadata). Both are accepted in the middle of an
+// identifier but not at its extremities.
+static ref CONFIG_IDENTIFIER_REGEX: Regex = Regex::new( r"[^\W\d](?:[\w.-]*\w)?").unwrap();
+
+// Words of prose: apostrophes and dashes are accepted between letters, for
+// contractions (don't) and compound words (well-known).
+static ref MARKDOWN_IDENTIFIER_REGEX: Regex = Regex::new(r"[^\W\d]\w*(?:['-]\w+)*").unwrap();
+
 // https://www.scheme.com/tspl4/grammar.html#grammar:symbols
 static ref SCHEME_IDENTIFIER_REGEX: Regex = Regex::new( r"\+|\-|\.\.\.|(?:->|(?:\\x[0-9A-Fa-f]+;|[!$%&*/:<=>?~^]|[^\W\d]))(?:\\x[0-9A-Fa-f]+;|[-+.@!$%&*/:<=>?~^\w])*").unwrap();
 
@@ -188,6 +246,69 @@ static ref FILETYPE_TO_IDENTIFIER_REGEX: HashMap<&'static str, RE> = {
 
     map.insert("scheme", &SCHEME_IDENTIFIER_REGEX);
 
+    map.insert("sql", &SQL_IDENTIFIER_REGEX);
+
+    map.insert("yaml", &CONFIG_IDENTIFIER_REGEX);
+    map.insert("json", &CONFIG_IDENTIFIER_REGEX);
+
+    map.insert("markdown", &MARKDOWN_IDENTIFIER_REGEX);
+    map.insert("text", &MARKDOWN_IDENTIFIER_REGEX);
+
+    map
+};
+
+// Runs of characters that may appear anywhere in an identifier, anchored at
+// the end so they are found by scanning backward from it. Every identifier
+// regex match must be made of these characters only.
+static ref DEFAULT_IDENTIFIER_RUN_REGEX: Regex = Regex::new(r"\w+$").unwrap();
+static ref JS_IDENTIFIER_RUN_REGEX: Regex = Regex::new(r"[\w$]+$").unwrap();
+static ref CSS_IDENTIFIER_RUN_REGEX: Regex = Regex::new(r"[\w-]+$").unwrap();
+static ref HTML_IDENTIFIER_RUN_REGEX: Regex = Regex::new(r#"[^\s/>='\\"}{\.]+$"#).unwrap();
+static ref CLOJURE_IDENTIFIER_RUN_REGEX: Regex = Regex::new(r"[-\*\+!_\?:\./\w]+$").unwrap();
+static ref HASKELL_IDENTIFIER_RUN_REGEX: Regex = Regex::new(r"[\w']+$").unwrap();
+static ref TEX_IDENTIFIER_RUN_REGEX: Regex = Regex::new(r"[\w:-]+$").unwrap();
+static ref PERL6_IDENTIFIER_RUN_REGEX: Regex = Regex::new(r"[\w'-]+$").unwrap();
+static ref CONFIG_IDENTIFIER_RUN_REGEX: Regex = Regex::new(r"[\w.-]+$").unwrap();
+static ref MARKDOWN_IDENTIFIER_RUN_REGEX: Regex = Regex::new(r"[\w'-]+$").unwrap();
+
+// R, scheme and quoted SQL identifiers are not simple enough to be covered by a run, they
+// are scanned the slow way.
+static ref FILETYPE_TO_IDENTIFIER_RUN_REGEX: HashMap<&'static str, Option<RE>> = {
+
+    let mut map = HashMap::new();
+
+    map.insert("javascript", Some(&JS_IDENTIFIER_RUN_REGEX as RE));
+    map.insert("typescript", Some(&JS_IDENTIFIER_RUN_REGEX as RE));
+
+    map.insert("css", Some(&CSS_IDENTIFIER_RUN_REGEX as RE));
+    map.insert("scss", Some(&CSS_IDENTIFIER_RUN_REGEX as RE));
+    map.insert("sass", Some(&CSS_IDENTIFIER_RUN_REGEX as RE));
+    map.insert("less", Some(&CSS_IDENTIFIER_RUN_REGEX as RE));
+
+    map.insert("html", Some(&HTML_IDENTIFIER_RUN_REGEX as RE));
+
+    map.insert("r", None);
+
+    map.insert("clojure", Some(&CLOJURE_IDENTIFIER_RUN_REGEX as RE));
+    map.insert("elisp", Some(&CLOJURE_IDENTIFIER_RUN_REGEX as RE));
+    map.insert("lisp", Some(&CLOJURE_IDENTIFIER_RUN_REGEX as RE));
+
+    map.insert("haskell", Some(&HASKELL_IDENTIFIER_RUN_REGEX as RE));
+
+    map.insert("tex", Some(&TEX_IDENTIFIER_RUN_REGEX as RE));
+
+    map.insert("perl6", Some(&PERL6_IDENTIFIER_RUN_REGEX as RE));
+
+    map.insert("scheme", None);
+
+    map.insert("sql", None);
+
+    map.insert("yaml", Some(&CONFIG_IDENTIFIER_RUN_REGEX as RE));
+    map.insert("json", Some(&CONFIG_IDENTIFIER_RUN_REGEX as RE));
+
+    map.insert("markdown", Some(&MARKDOWN_IDENTIFIER_RUN_REGEX as RE));
+    map.insert("text", Some(&MARKDOWN_IDENTIFIER_RUN_REGEX as RE));
+
     map
 };
 }
@@ -210,6 +331,15 @@ fn get_identifier_re_for_ftype(filetype: Option<&str>) -> RE {
     }
 }
 
+fn get_identifier_run_re_for_ftype(filetype: Option<&str>) -> Option<RE> {
+    match filetype {
+        None => Some(&DEFAULT_IDENTIFIER_RUN_REGEX),
+        Some(t) => *FILETYPE_TO_IDENTIFIER_RUN_REGEX
+            .get(t)
+            .unwrap_or(&Some(&DEFAULT_IDENTIFIER_RUN_REGEX as RE)),
+    }
+}
+
 fn replace_with_empty_lines(caps: &Captures) -> String {
     if caps.len() == 1 {
         "\n".repeat(caps[0].lines().count() - 1)
@@ -235,12 +365,49 @@ fn replace_with_empty_lines(caps: &Captures) -> String {
     }
 }
 
+/// `text` with every byte but line breaks replaced by a space
+fn blank(text: &str) -> String {
+    text.bytes()
+        .map(|b| if b == b'\n' { '\n' } else { ' ' })
+        .collect()
+}
+
+fn replace_with_spaces(caps: &Captures) -> String {
+    if caps.len() == 1 {
+        blank(&caps[0])
+    } else {
+        let off = caps.get(0).unwrap().start();
+        let mut prev = off;
+        let whole = &caps[0];
+        let mut replaced: String = caps
+            .iter()
+            .skip(1)
+            .flatten()
+            .map(|c| {
+                let kept = &whole[prev - off..c.start() - off];
+                prev = c.end();
+                String::from(kept) + &blank(c.as_str())
+            })
+            .collect();
+        replaced.push_str(&whole[prev - off..]);
+        replaced
+    }
+}
+
 pub fn remove_identifier_free_text(text: &str, filetype: Option<&str>) -> String {
     get_comments_and_strings_re_for_ftype(filetype)
         .replace_all(text, replace_with_empty_lines)
         .to_string()
 }
 
+/// Like `remove_identifier_free_text`, but comments and strings are replaced with
+/// spaces, keeping line breaks, so that byte offsets match the ones in `text`
+pub fn blank_identifier_free_text(text: &str, filetype: Option<&str>) -> String {
+    get_comments_and_strings_re_for_ftype(filetype)
+        .replace_all(text, replace_with_spaces)
+        .to_string()
+}
+
 pub fn is_identifier(text: &str, filetype: Option<&str>) -> bool {
     if text.is_empty() {
         return false;
@@ -258,6 +425,53 @@ pub fn is_identifier(text: &str, filetype: Option<&str>) -> bool {
     }
 }
 
+/// Byte ranges in `text` of the identifiers outside of comments and strings,
+/// in order of appearance
+pub fn identifier_ranges_in_text(text: &str, filetype: Option<&str>) -> Vec<Range<usize>> {
+    identifier_ranges_in_blanked_text(&blank_identifier_free_text(text, filetype), filetype)
+}
+
+/// Like `identifier_ranges_in_text`, for text whose comments and strings were
+/// already blanked out by `blank_identifier_free_text`
+pub fn identifier_ranges_in_blanked_text(text: &str, filetype: Option<&str>) -> Vec<Range<usize>> {
+    get_identifier_re_for_ftype(filetype)
+        .captures_iter(text)
+        .filter_map(|c| c.name("id").or_else(|| c.get(0)))
+        .map(|m| m.range())
+        .filter(|r| !r.is_empty())
+        .collect()
+}
+
+/// Identifiers outside of comments and strings along with their byte ranges in
+/// `text`, in order of appearance. Meant for clients highlighting occurrences of
+/// an identifier, the ranges being into `text` itself rather than a stripped copy
+pub fn identifiers_with_spans<'a>(
+    text: &'a str,
+    filetype: Option<&str>,
+) -> Vec<(Range<usize>, &'a str)> {
+    identifier_ranges_in_text(text, filetype)
+        .into_iter()
+        .map(|r| (r.clone(), &text[r]))
+        .collect()
+}
+
+/// All identifiers in `text` that are outside of comments and strings, in order of appearance
+pub fn identifiers_in_text(text: &str, filetype: Option<&str>) -> Vec<String> {
+    identifiers_with_spans(text, filetype)
+        .into_iter()
+        .map(|(_, identifier)| String::from(identifier))
+        .collect()
+}
+
+/// Whether `index` is not inside a grapheme cluster of `text`, e.g. between a
+/// letter and the combining mark following it
+fn is_grapheme_boundary(text: &str, index: usize) -> bool {
+    text.is_char_boundary(index)
+        && GraphemeCursor::new(index, text.len(), true)
+            .is_boundary(text, 0)
+            .unwrap_or(false)
+}
+
 // index is 0-based and EXCLUSIVE, so ("foo.", 3) -> 0
 // Returns the index on bad input.
 // Note: its different from python ycmd as its both expects and returns byte position
@@ -270,14 +484,236 @@ pub fn start_of_longest_identifier_ending_at_index(
         return index;
     }
 
-    for i in 0..index {
-        if text.is_char_boundary(i) && is_identifier(&text[i..=index - 1], filetype) {
+    // An identifier can't extend past the run of identifier characters ending
+    // at `index`, so only look for its start within that run. The run is found
+    // by a reverse scan, which keeps this linear in the identifier length
+    // rather than quadratic in `index` on long lines.
+    let run_start = match get_identifier_run_re_for_ftype(filetype) {
+        Some(re) => match re.find(&text[..index]) {
+            Some(m) => m.start(),
+            None => return index,
+        },
+        None => 0,
+    };
+
+    // Combining marks are word characters, an identifier may seem to start at
+    // one whose base character is not part of it
+    for i in run_start..index {
+        if is_grapheme_boundary(text, i) && is_identifier(&text[i..=index - 1], filetype) {
             return i;
         }
     }
     index
 }
 
+/// Known identifiers and non identifiers of some filetypes, covering every
+/// identifier regex
+struct IdentifierSamples {
+    filetypes: &'static [&'static str],
+    identifiers: &'static [&'static str],
+    non_identifiers: &'static [&'static str],
+}
+
+const IDENTIFIER_SAMPLES: &[IdentifierSamples] = &[
+    IdentifierSamples {
+        filetypes: &["rust"],
+        identifiers: &["foo_bar", "_x", "ålpha"],
+        non_identifiers: &["1foo", "foo-bar"],
+    },
+    IdentifierSamples {
+        filetypes: &["javascript", "typescript"],
+        identifiers: &["$foo", "_bar", "fooBar2"],
+        non_identifiers: &["2foo", "foo-bar"],
+    },
+    IdentifierSamples {
+        filetypes: &["css", "scss", "sass", "less"],
+        identifiers: &["font-size", "-moz-border", "_x"],
+        non_identifiers: &["2px", "foo.bar"],
+    },
+    IdentifierSamples {
+        filetypes: &["html"],
+        identifiers: &["div", "data-foo", "ng:if"],
+        non_identifiers: &["1div", "foo.bar", "foo=bar"],
+    },
+    IdentifierSamples {
+        filetypes: &["r"],
+        identifiers: &["foo.bar", ".foo", "foo_1"],
+        non_identifiers: &["_foo", "1foo", ".1foo"],
+    },
+    IdentifierSamples {
+        filetypes: &["clojure", "elisp", "lisp"],
+        identifiers: &["foo-bar?", "*ns*", "clojure.core/map", ":keyword"],
+        non_identifiers: &["1foo", "foo/bar/baz"],
+    },
+    IdentifierSamples {
+        filetypes: &["haskell"],
+        identifiers: &["foo'", "_bar", "fooBar"],
+        non_identifiers: &["'foo", "1x"],
+    },
+    IdentifierSamples {
+        filetypes: &["tex"],
+        identifiers: &["fig:foo", "foo-bar"],
+        non_identifiers: &["foo:", "1foo"],
+    },
+    IdentifierSamples {
+        filetypes: &["perl6"],
+        identifiers: &["foo-bar", "isn't", "_x"],
+        non_identifiers: &["foo-", "foo-1"],
+    },
+    IdentifierSamples {
+        filetypes: &["scheme"],
+        identifiers: &["+", "...", "list->vector", "set!"],
+        non_identifiers: &["1+", "foo bar"],
+    },
+    IdentifierSamples {
+        filetypes: &["sql"],
+        identifiers: &["\"My Table\"", "$foo", "foo$bar"],
+        non_identifiers: &["1foo", "foo bar"],
+    },
+    IdentifierSamples {
+        filetypes: &["yaml", "json"],
+        identifiers: &["max-age", "spec.template", "foo"],
+        non_identifiers: &["foo-", ".foo", "1foo"],
+    },
+    IdentifierSamples {
+        filetypes: &["markdown", "text"],
+        identifiers: &["don't", "well-known"],
+        non_identifiers: &["don'", "-foo"],
+    },
+];
+
+/// Text of some filetypes and the identifiers left once comments and strings are
+/// removed, covering every comment and string regex
+struct CommentSample {
+    filetypes: &'static [&'static str],
+    text: &'static str,
+    identifiers: &'static [&'static str],
+}
+
+const COMMENT_SAMPLES: &[CommentSample] = &[
+    CommentSample {
+        filetypes: &[
+            "cpp",
+            "c",
+            "cuda",
+            "objc",
+            "objcpp",
+            "javascript",
+            "typescript",
+        ],
+        // `#` starts no comment here, unlike in the default regex a misspelled
+        // filetype falls back to
+        text: "foo /* bar */ baz // qux\nquux # corge",
+        identifiers: &["foo", "baz", "quux", "corge"],
+    },
+    CommentSample {
+        filetypes: &["go"],
+        text: "foo // bar\nx := `baz`",
+        identifiers: &["foo", "x"],
+    },
+    CommentSample {
+        filetypes: &["python"],
+        text: "'''baz'''\nfoo # bar\nx = 'qux'",
+        identifiers: &["foo", "x"],
+    },
+    CommentSample {
+        filetypes: &["rust"],
+        text: "foo // bar\nx = \"baz\"",
+        identifiers: &["foo", "x"],
+    },
+    CommentSample {
+        filetypes: &["sql"],
+        text: "foo -- bar\nx = 'baz'",
+        identifiers: &["foo", "x"],
+    },
+    CommentSample {
+        filetypes: &["yaml"],
+        text: "foo: 1 # bar",
+        identifiers: &["foo"],
+    },
+    CommentSample {
+        filetypes: &["json"],
+        text: "{\"foo\": \"bar\"}",
+        identifiers: &["foo", "bar"],
+    },
+    CommentSample {
+        filetypes: &["markdown", "text"],
+        text: "foo\n```\nbar\n```\nbaz",
+        identifiers: &["foo", "baz"],
+    },
+];
+
+/// Checks the regexes of every filetype against the built-in samples, returning a
+/// description of each failure. Filetypes without samples count as failures, so
+/// that a newly added regex does not go unchecked.
+pub fn validate_identifier_regexes() -> Vec<String> {
+    let mut failures = vec![];
+    for samples in IDENTIFIER_SAMPLES {
+        for &filetype in samples.filetypes {
+            for &identifier in samples.identifiers {
+                if !is_identifier(identifier, Some(filetype)) {
+                    failures.push(format!(
+                        "{}: {:?} should be an identifier",
+                        filetype, identifier
+                    ));
+                    continue;
+                }
+                let start = start_of_longest_identifier_ending_at_index(
+                    identifier,
+                    identifier.len(),
+                    Some(filetype),
+                );
+                if start != 0 {
+                    failures.push(format!(
+                        "{}: identifier {:?} should start at 0, not {}",
+                        filetype, identifier, start
+                    ));
+                }
+            }
+            for &text in samples.non_identifiers {
+                if is_identifier(text, Some(filetype)) {
+                    failures.push(format!(
+                        "{}: {:?} should not be an identifier",
+                        filetype, text
+                    ));
+                }
+            }
+        }
+    }
+    for sample in COMMENT_SAMPLES {
+        for &filetype in sample.filetypes {
+            let identifiers = identifiers_in_text(sample.text, Some(filetype));
+            if identifiers != sample.identifiers {
+                failures.push(format!(
+                    "{}: identifiers of {:?} are {:?}, expected {:?}",
+                    filetype, sample.text, identifiers, sample.identifiers
+                ));
+            }
+        }
+    }
+
+    // Filetypes are only looked up by name above, go through the maps to
+    // compile every regex and find the ones without samples
+    let mut missing = FILETYPE_TO_IDENTIFIER_REGEX
+        .iter()
+        .filter(|(_, re)| !re.as_str().is_empty())
+        .map(|(filetype, _)| filetype)
+        .filter(|f| !IDENTIFIER_SAMPLES.iter().any(|s| s.filetypes.contains(f)))
+        .map(|f| format!("{}: no identifier samples", f))
+        .chain(
+            FILETYPE_TO_COMMENT_AND_STRING_REGEX
+                .iter()
+                .filter(|(_, re)| !re.as_str().is_empty())
+                .map(|(filetype, _)| filetype)
+                .filter(|f| !COMMENT_SAMPLES.iter().any(|s| s.filetypes.contains(f)))
+                .map(|f| format!("{}: no comment and string samples", f)),
+        )
+        .collect::<Vec<_>>();
+    missing.sort();
+    failures.extend(missing);
+    failures
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -305,6 +741,98 @@ mod tests {
         );
     }
 
+    #[test]
+    fn remove_identifier_free_text_yaml_comments() {
+        assert_eq!(
+            "\nkey: value \nurl: http://a#b\nname: \"#1\"",
+            &remove_identifier_free_text(
+                "# header\nkey: value # trailing\nurl: http://a#b\nname: \"#1\"",
+                Some("yaml")
+            )
+        );
+    }
+
+    #[test]
+    fn identifiers_in_text_json() {
+        assert_eq!(
+            vec!["max-age", "spec.template", "value"],
+            identifiers_in_text(r#"{"max-age": 1, "spec.template": "value"}"#, Some("json"))
+        );
+    }
+
+    #[test]
+    fn remove_identifier_free_text_sql_comments() {
+        assert_eq!(
+            "select a \nfrom t ",
+            &remove_identifier_free_text("select a -- the a\nfrom t /* all */", Some("sql"))
+        );
+    }
+
+    #[test]
+    fn remove_identifier_free_text_sql_strings() {
+        assert_eq!(
+            "where name =  and \"b\" = \n",
+            &remove_identifier_free_text("where name = 'it''s' and \"b\" = 'x\ny'", Some("sql"))
+        );
+        // Backslashes don't escape quotes
+        assert_eq!(
+            " foo",
+            &remove_identifier_free_text(r"'C:\' foo", Some("sql"))
+        );
+    }
+
+    #[test]
+    fn blank_identifier_free_text_keeps_offsets() {
+        let text = "foo /* ä\nb */ bar 'x' // qux";
+        let blanked = blank_identifier_free_text(text, None);
+        assert_eq!(blanked.len(), text.len());
+        assert_eq!(blanked.lines().count(), text.lines().count());
+        assert_eq!(blanked.find("bar"), text.find("bar"));
+        assert_eq!(blanked.trim_end(), "foo      \n     bar");
+    }
+
+    #[test]
+    fn identifier_ranges_after_comment() {
+        let text = "a /* ünïcode */ foo";
+        let ranges = identifier_ranges_in_text(text, Some("cpp"));
+        assert_eq!(ranges, vec![0..1, text.find("foo").unwrap()..text.len()]);
+        assert_eq!(&text[ranges[1].clone()], "foo");
+
+        // Identifiers around a removed comment are not joined together
+        assert_eq!(
+            vec!["foo", "bar"],
+            identifiers_in_text("foo/**/bar", Some("cpp"))
+        );
+    }
+
+    #[test]
+    fn identifier_spans_match_text() {
+        let text = "fn main() {\n    // let ignored = 1;\n    let ünï = \"str\"; ünï\n}";
+        let spans = identifiers_with_spans(text, Some("rust"));
+        for (range, identifier) in &spans {
+            assert_eq!(&text[range.clone()], *identifier);
+        }
+        let identifiers = spans.iter().map(|(_, i)| *i).collect::<Vec<_>>();
+        assert_eq!(identifiers, vec!["fn", "main", "let", "ünï", "ünï"]);
+        assert_eq!(spans[4].0.end, text.rfind('\n').unwrap());
+    }
+
+    #[test]
+    fn identifiers_in_text_simple() {
+        assert_eq!(
+            vec!["foo", "bar", "_qux1"],
+            identifiers_in_text("foo = bar + _qux1 // comment", Some("cpp"))
+        );
+        assert_eq!(
+            vec!["foo", "bar"],
+            identifiers_in_text("foo \"string\" 12 bar", None)
+        );
+        assert_eq!(
+            vec!["a.b", "c_d"],
+            identifiers_in_text("a.b <- c_d", Some("r"))
+        );
+    }
+
     #[test]
     fn is_identifier_generic() {
         assert!(is_identifier("foo", None));
@@ -526,6 +1054,73 @@ mod tests {
         assert!(!is_identifier(r"aa\x123;cc\x", Some("scheme")));
     }
 
+    #[test]
+    fn is_identifier_config() {
+        for filetype in [Some("yaml"), Some("json")] {
+            assert!(is_identifier("foo", filetype));
+            assert!(is_identifier("foo-bar", filetype));
+            assert!(is_identifier("foo.bar.baz", filetype));
+            assert!(is_identifier("x86_64", filetype));
+
+            assert!(!is_identifier("-foo", filetype));
+            assert!(!is_identifier("foo.", filetype));
+            assert!(!is_identifier("foo-", filetype));
+            assert!(!is_identifier("1foo", filetype));
+        }
+    }
+
+    #[test]
+    fn is_identifier_sql() {
+        assert!(is_identifier("foo", Some("sql")));
+        assert!(is_identifier("_foo1", Some("sql")));
+        assert!(is_identifier("foo$bar", Some("sql")));
+        assert!(is_identifier("$foo", Some("sql")));
+        assert!(is_identifier("\"Mixed Case\"", Some("sql")));
+        assert!(is_identifier("\"1st\"", Some("sql")));
+
+        assert!(!is_identifier("1st", Some("sql")));
+        assert!(!is_identifier("$1", Some("sql")));
+        assert!(!is_identifier("\"\"", Some("sql")));
+        assert!(!is_identifier("\"foo", Some("sql")));
+        assert!(!is_identifier("'foo'", Some("sql")));
+    }
+
+    #[test]
+    fn is_identifier_markdown() {
+        assert!(is_identifier("don't", Some("markdown")));
+        assert!(is_identifier("it's", Some("markdown")));
+        assert!(is_identifier("well-known", Some("markdown")));
+        assert!(is_identifier("state-of-the-art", Some("markdown")));
+        assert!(is_identifier("naïve", Some("markdown")));
+        assert!(is_identifier("don't", Some("text")));
+
+        assert!(!is_identifier("'quoted'", Some("markdown")));
+        assert!(!is_identifier("dash-", Some("markdown")));
+        assert!(!is_identifier("don''t", Some("markdown")));
+        assert!(!is_identifier("3rd", Some("markdown")));
+        assert!(!is_identifier("```", Some("markdown")));
+    }
+
+    #[test]
+    fn identifiers_in_text_markdown() {
+        let text = "Don't use `it`, it's well-known\n\
+                    ```rust\n\
+                    let code_only = 1;\n\
+                    ```\n\
+                    after";
+        assert_eq!(
+            vec!["Don't", "use", "it", "it's", "well-known", "after"],
+            identifiers_in_text(text, Some("markdown"))
+        );
+        // Code blocks keep the line count
+        assert_eq!(
+            remove_identifier_free_text(text, Some("markdown"))
+                .lines()
+                .count(),
+            text.lines().count()
+        );
+    }
+
     #[test]
     fn start_of_longest_identifier_ending_at_index_simple() {
         assert_eq!(
@@ -588,5 +1183,134 @@ mod tests {
         );
     }
 
+    #[test]
+    fn start_of_longest_identifier_ending_at_index_graphemes() {
+        // Combining acute accent on a dot, and café spelled with one
+        let text = "a.\u{301}bc";
+        assert_eq!(
+            2,
+            start_of_longest_identifier_ending_at_index_naive(text, 6, None)
+        );
+        assert_eq!(
+            4,
+            start_of_longest_identifier_ending_at_index(text, 6, None)
+        );
+
+        let text = "(cafe\u{301} -\u{301}x";
+        assert_eq!(
+            1,
+            start_of_longest_identifier_ending_at_index(text, 7, None)
+        );
+        assert_eq!(
+            9,
+            start_of_longest_identifier_ending_at_index_naive(text, 12, None)
+        );
+        assert_eq!(
+            11,
+            start_of_longest_identifier_ending_at_index(text, 12, None)
+        );
+        // Dashes are part of css identifiers, so the accented one is
+        assert_eq!(
+            8,
+            start_of_longest_identifier_ending_at_index(text, 12, Some("css"))
+        );
+
+        // Nothing but the mark itself is left of an identifier
+        let text = "x.\u{301}";
+        assert_eq!(
+            2,
+            start_of_longest_identifier_ending_at_index_naive(text, 4, None)
+        );
+        assert_eq!(
+            4,
+            start_of_longest_identifier_ending_at_index(text, 4, None)
+        );
+    }
+
+    /// Scan of every start position, kept to check the run based one against
+    fn start_of_longest_identifier_ending_at_index_naive(
+        text: &str,
+        index: usize,
+        filetype: Option<&str>,
+    ) -> usize {
+        if text.len() < index || !text.is_char_boundary(index) {
+            return index;
+        }
+        (0..index)
+            .find(|&i| text.is_char_boundary(i) && is_identifier(&text[i..index], filetype))
+            .unwrap_or(index)
+    }
+
+    #[test]
+    fn start_of_longest_identifier_ending_at_index_matches_naive() {
+        let lines = [
+            "foo",
+            "f12",
+            "(foo",
+            "      foo",
+            "gar;foo",
+            "...",
+            "(fäö",
+            "fäö(fäö",
+            "  fäö",
+            "1foo129 uniçode",
+            "font-face -ms-foo -3b",
+            "$føo1 = fø$o1",
+            "a.b <- c_d .1a _a",
+            "a/b/c (a) a?b",
+            "foo' 'x _x'",
+            "\\section{fig:foo-bar}",
+            "x-y'a x--a x-'a",
+            r"h\x65;lle call/cc -<abc",
+            "<div class=\"a\">",
+            "don't 'a' well-known- x''y",
+            "select \"a b\", $c, d$e from",
+            "a.b-c: d. -e",
+        ];
+        let filetypes = [
+            None,
+            Some("javascript"),
+            Some("css"),
+            Some("html"),
+            Some("r"),
+            Some("clojure"),
+            Some("haskell"),
+            Some("tex"),
+            Some("perl6"),
+            Some("scheme"),
+            Some("sql"),
+            Some("yaml"),
+            Some("markdown"),
+        ];
+        for text in lines {
+            for filetype in filetypes {
+                for index in 0..=text.len() + 1 {
+                    assert_eq!(
+                        start_of_longest_identifier_ending_at_index_naive(text, index, filetype),
+                        start_of_longest_identifier_ending_at_index(text, index, filetype),
+                        "{:?} at {} for {:?}",
+                        text,
+                        index,
+                        filetype
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn start_of_longest_identifier_ending_at_index_long_line() {
+        let text = "a.".repeat(50_000) + "foo";
+        assert_eq!(
+            text.len() - 3,
+            start_of_longest_identifier_ending_at_index(&text, text.len(), None)
+        );
+    }
+
+    #[test]
+    fn builtin_regexes_pass_validation() {
+        assert_eq!(validate_identifier_regexes(), Vec::<String>::new());
+    }
+
     //TODO: port all other tests
 }
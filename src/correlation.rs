@@ -0,0 +1,33 @@
+//! Correlation ids tying log lines to the request that caused them.
+//! Routes run their handlers in [`scope`], anything they call can then
+//! tag its logs with [`current`].
+
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(1);
+
+/// Fresh id for a request to `path`, e.g. `completions-42`
+pub fn next_id(path: &str) -> String {
+    format!(
+        "{}-{}",
+        path.trim_matches('/'),
+        NEXT_ID.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+/// Run `f` as part of the request identified by `id`
+pub async fn scope<F: Future>(id: String, f: F) -> F::Output {
+    REQUEST_ID.scope(id, f).await
+}
+
+/// Id of the request the current task is working on, `-` outside of any
+pub fn current() -> String {
+    REQUEST_ID
+        .try_with(|id| id.clone())
+        .unwrap_or_else(|_| String::from("-"))
+}
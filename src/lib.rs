@@ -1,5 +1,6 @@
 pub mod completer;
 pub mod core;
+pub mod correlation;
 pub mod routes;
 pub mod server;
 pub mod ycmd_types;
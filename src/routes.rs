@@ -1,11 +1,20 @@
 use std::convert::Infallible;
-use std::sync::Arc;
+use std::future::Future;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
+use anyhow::Context;
 use futures::future;
 
-use log::error;
+use log::{debug, error, warn};
 use ring::hmac;
 
+use flate2::write::{DeflateEncoder, GzEncoder};
+use flate2::Compression;
+use warp::http::header::{
+    HeaderMap, HeaderValue, ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_LENGTH, VARY,
+};
 use warp::hyper::Method;
 use warp::path::FullPath;
 use warp::reply::Response;
@@ -16,12 +25,70 @@ use warp::{
 
 use tokio::sync::mpsc;
 
+use super::correlation;
 use super::server::{Options, ServerState};
 use super::ycmd_types;
 const HMAC_HEADER: &str = "x-ycm-hmac";
+/// Rejected hmac attempts logged per second, the rest are only counted
+const HMAC_REJECTIONS_LOGGED_PER_SEC: usize = 5;
+/// Replies smaller than this aren't worth compressing
+const COMPRESSION_MIN_SIZE: usize = 1024;
+
+lazy_static::lazy_static! {
+    static ref HMAC_REJECTIONS: Mutex<LogRateLimit> =
+        Mutex::new(LogRateLimit::new(HMAC_REJECTIONS_LOGGED_PER_SEC));
+}
+
+/// Bounds how many events of some kind get logged per second
+struct LogRateLimit {
+    limit: usize,
+    window_start: Option<Instant>,
+    logged: usize,
+    suppressed: usize,
+}
+
+impl LogRateLimit {
+    fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            window_start: None,
+            logged: 0,
+            suppressed: 0,
+        }
+    }
+
+    /// Whether to log an event happening at `now`. The first event of a new second
+    /// also gets how many events went unlogged before it
+    fn check(&mut self, now: Instant) -> (bool, usize) {
+        let mut suppressed = 0;
+        match self.window_start {
+            Some(start) if now.duration_since(start) < Duration::from_secs(1) => {}
+            _ => {
+                self.window_start = Some(now);
+                self.logged = 0;
+                suppressed = std::mem::take(&mut self.suppressed);
+            }
+        }
+        if self.logged < self.limit {
+            self.logged += 1;
+            (true, suppressed)
+        } else {
+            self.suppressed += 1;
+            (false, suppressed)
+        }
+    }
+}
 
 fn hmac_filter(
     key: Arc<hmac::Key>,
+) -> impl warp::Filter<Extract = (Bytes,), Error = Rejection> + Send + Sync + 'static + Clone {
+    hmac_filter_limited(key, &HMAC_REJECTIONS)
+}
+
+/// `hmac_filter` logging the rejected requests `rejections` lets through
+fn hmac_filter_limited(
+    key: Arc<hmac::Key>,
+    rejections: &'static Mutex<LogRateLimit>,
 ) -> impl warp::Filter<Extract = (Bytes,), Error = Rejection> + Send + Sync + 'static + Clone {
     warp::header::<String>(HMAC_HEADER)
         .and(warp::body::bytes())
@@ -30,7 +97,7 @@ fn hmac_filter(
         .and_then(
             move |hmac_value, body: Bytes, path: FullPath, method: Method| {
                 let hmac_secret = key.clone();
-                let hmac_value = base64::decode(&hmac_value).unwrap();
+                let hmac_value = base64::decode(&hmac_value).unwrap_or_default();
                 let body_hmac = hmac::sign(&hmac_secret, &body);
                 let method_hmac = hmac::sign(&hmac_secret, method.as_str().as_bytes());
                 let path_hmac = hmac::sign(&hmac_secret, path.as_str().as_bytes());
@@ -42,7 +109,18 @@ fn hmac_filter(
                 let expected = ctx.sign();
 
                 if !expected.as_ref().eq(&hmac_value) {
-                    error!("Non matching hmac: {:?}, {:?}", hmac_value, body.as_ref());
+                    let (log, suppressed) = rejections.lock().unwrap().check(Instant::now());
+                    if suppressed > 0 {
+                        warn!("Suppressed {} more rejected hmac attempts", suppressed);
+                    }
+                    if log {
+                        warn!(
+                            "Non matching hmac for {} {} with {} byte body",
+                            method,
+                            path.as_str(),
+                            body.len()
+                        );
+                    }
                     future::err(warp::reject::not_found())
                 } else {
                     future::ok(body)
@@ -66,16 +144,47 @@ fn hmac_filter_discard_body(
     hmac_filter(key).map(move |_: Bytes| ()).untuple_one()
 }
 
+/// Environment variable the HMAC secret is taken from when the options leave it
+/// empty, so that it is never written to disk. It is removed once read, for the
+/// processes started later not to inherit it. Without either, the server refuses
+/// to start rather than accept requests signed with an empty key
+pub const HMAC_SECRET_ENV: &str = "YCMD_HMAC_SECRET";
+
+/// Value of the environment variable `name`, which is removed
+fn take_env_var(name: &str) -> Option<String> {
+    let value = std::env::var(name).ok();
+    std::env::remove_var(name);
+    value
+}
+
+/// Key requests are signed with, decoded from the base64 `options_secret` or,
+/// if that is empty, from `env_secret`
+fn hmac_key(options_secret: &str, env_secret: Option<String>) -> Result<hmac::Key, anyhow::Error> {
+    let secret = if options_secret.is_empty() {
+        let secret = env_secret.ok_or_else(|| {
+            anyhow::anyhow!("No hmac_secret in options nor in {}", HMAC_SECRET_ENV)
+        })?;
+        base64::decode(secret.trim())
+            .with_context(|| format!("{} is not valid base64", HMAC_SECRET_ENV))?
+    } else {
+        base64::decode(options_secret).context("hmac_secret is not valid base64")?
+    };
+    Ok(hmac::Key::new(hmac::HMAC_SHA256, &secret))
+}
+
 pub fn get_routes(
     options: Options,
-) -> (
-    impl warp::Filter<Extract = impl Reply, Error = Infallible> + Send + Sync + 'static + Clone,
-    mpsc::Receiver<()>,
-) {
-    let hmac_secret = Arc::from(hmac::Key::new(
-        hmac::HMAC_SHA256,
-        &base64::decode(&options.hmac_secret).unwrap()[..],
-    ));
+) -> Result<
+    (
+        impl warp::Filter<Extract = impl Reply, Error = Infallible> + Send + Sync + 'static + Clone,
+        mpsc::Receiver<()>,
+    ),
+    anyhow::Error,
+> {
+    let hmac_secret = Arc::from(hmac_key(
+        &options.hmac_secret,
+        take_env_var(HMAC_SECRET_ENV),
+    )?);
 
     let server_state = Arc::from(ServerState::new(options));
     let state_filter = warp::any().map(move || server_state.clone());
@@ -96,9 +205,16 @@ pub fn get_routes(
         .and(warp::path("completions"))
         .and(hmac_filter_json_body(hmac_secret.clone()))
         .and(state_filter.clone())
-        .map(
+        .and_then(
             |request: ycmd_types::SimpleRequest, state: Arc<ServerState>| {
-                warp::reply::json(&state.completions(request))
+                correlated("completions", async move {
+                    let reply = if request.dry_run {
+                        warp::reply::json(&state.completion_decisions(request))
+                    } else {
+                        warp::reply::json(&state.completions(request).await)
+                    };
+                    Ok::<_, warp::Rejection>(reply)
+                })
             },
         );
 
@@ -122,6 +238,32 @@ pub fn get_routes(
             },
         );
 
+    let resolve_completion = warp::filters::method::post()
+        .and(warp::path("resolve_completion"))
+        .and(state_filter.clone())
+        .and(hmac_filter_json_body(hmac_secret.clone()))
+        .and_then(
+            |state: Arc<ServerState>, request: ycmd_types::ResolveCompletionRequest| {
+                correlated("resolve_completion", async move {
+                    Ok::<_, warp::Rejection>(error_reply(state.resolve_completion(request).await))
+                })
+            },
+        );
+
+    let run_completer_command = warp::filters::method::post()
+        .and(warp::path("run_completer_command"))
+        .and(state_filter.clone())
+        .and(hmac_filter_json_body(hmac_secret.clone()))
+        .and_then(
+            |state: Arc<ServerState>, request: ycmd_types::CommandRequest| {
+                correlated("run_completer_command", async move {
+                    Ok::<_, warp::Rejection>(error_reply(
+                        state.run_completer_command(request).await,
+                    ))
+                })
+            },
+        );
+
     let semantic_completer_available = warp::filters::method::post()
         .and(warp::path("semantic_completion_available"))
         .and(state_filter.clone())
@@ -141,13 +283,21 @@ pub fn get_routes(
             warp::reply::json(&state.signature_help_available(request))
         });
 
+    let filetype_completer_available = warp::filters::method::get()
+        .and(warp::path("filetype_completer_available"))
+        .and(hmac_filter_discard_body(hmac_secret.clone()))
+        .and(state_filter.clone())
+        .map(|state: Arc<ServerState>| warp::reply::json(&state.filetype_completer_available()));
+
     let event_notification = warp::filters::method::post()
         .and(warp::path("event_notification"))
         .and(state_filter.clone())
         .and(hmac_filter_json_body(hmac_secret.clone()))
-        .map(
+        .and_then(
             |state: Arc<ServerState>, request: ycmd_types::EventNotification| {
-                warp::reply::json(&state.event_notification(request))
+                correlated("event_notification", async move {
+                    Ok::<_, warp::Rejection>(warp::reply::json(&state.event_notification(request)))
+                })
             },
         );
 
@@ -161,8 +311,9 @@ pub fn get_routes(
                 let sort_property = request.sort_property.clone();
                 let candidates = crate::core::query::filter_and_sort_generic_candidates(
                     request.candidates,
-                    &request.query,
+                    &state.query_word(&request.query),
                     max_candidates,
+                    &state.options.scoring_weights,
                     |c| match c {
                         serde_json::Value::String(s) => s,
                         serde_json::Value::Object(o) => {
@@ -177,7 +328,7 @@ pub fn get_routes(
 
     let receive_messages = warp::filters::method::post()
         .and(warp::path("receive_messages"))
-        .and(state_filter)
+        .and(state_filter.clone())
         .and(hmac_filter_json_body(hmac_secret.clone()))
         .and_then(
             |state: Arc<ServerState>, request: ycmd_types::SimpleRequest| async move {
@@ -190,9 +341,11 @@ pub fn get_routes(
     let shutdown = warp::filters::method::post()
         .and(warp::path("shutdown"))
         .and(hmac_filter_discard_body(hmac_secret.clone()))
-        .and_then(move || {
+        .and(state_filter)
+        .and_then(move |state: Arc<ServerState>| {
             let shutdown_tx = shutdown_tx.clone();
             async move {
+                state.shutdown().await;
                 shutdown_tx.send(()).await.unwrap();
                 Ok::<_, warp::Rejection>(warp::reply())
             }
@@ -205,34 +358,123 @@ pub fn get_routes(
         .or(event_notification)
         .or(debug_info)
         .or(defined_subcommands)
+        .or(resolve_completion)
+        .or(run_completer_command)
         .or(semantic_completer_available)
         .or(signature_help_available)
+        .or(filetype_completer_available)
         .or(filter_and_sort)
         .or(shutdown);
 
-    (
-        ycmd_paths
-            .recover(rejection_handler)
-            .and_then(move |r| {
+    Ok((
+        warp::header::headers_cloned()
+            .and(ycmd_paths.recover(rejection_handler))
+            .and_then(move |headers: HeaderMap, r| {
                 let hmac_secret = hmac_secret.clone();
-                sign_body(r, hmac_secret)
+                sign_body(r, hmac_secret, headers)
             })
             .with(warp::log("ycmd")),
         shutdown_rx,
-    )
+    ))
+}
+
+/// Handle a request to `path` under a fresh correlation id
+pub async fn correlated<F: Future>(path: &str, handler: F) -> F::Output {
+    let id = correlation::next_id(path);
+    debug!("[{}] Handling request", id);
+    correlation::scope(id, handler).await
+}
+
+/// `result` as JSON, errors being sent as an exception with a 500 status
+fn error_reply<T: serde::Serialize, E: std::fmt::Display>(result: Result<T, E>) -> Response {
+    match result {
+        Ok(response) => warp::reply::json(&response).into_response(),
+        Err(e) => warp::reply::with_status(
+            warp::reply::json(&ycmd_types::ExceptionResponse::new(e.to_string())),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        )
+        .into_response(),
+    }
+}
+
+/// Preferred compression among the ones listed in `Accept-Encoding`
+fn accepted_encoding(headers: &HeaderMap) -> Option<&'static str> {
+    let accepted = headers
+        .get_all(ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(|coding| {
+            let mut params = coding.split(';').map(str::trim);
+            let name = params.next()?.to_ascii_lowercase();
+            let refused =
+                params.any(|p| p.strip_prefix("q=").and_then(|q| q.parse().ok()) == Some(0.0));
+            if refused {
+                None
+            } else {
+                Some(name)
+            }
+        })
+        .collect::<Vec<_>>();
+    ["gzip", "deflate"]
+        .iter()
+        .copied()
+        .find(|e| accepted.iter().any(|a| a == e))
+}
+
+fn compress(body: &[u8], encoding: &str) -> std::io::Result<Vec<u8>> {
+    if encoding == "gzip" {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(body)?;
+        encoder.finish()
+    } else {
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(body)?;
+        encoder.finish()
+    }
 }
 
-/// Sign reply with hmac
+/// Sign reply with hmac, compressing large replies if the request accepts it.
+/// The signature covers the uncompressed body: HTTP clients undo `Content-Encoding`
+/// before handing the body over, so that is what gets verified.
 async fn sign_body(
     reply: impl Reply,
     hmac_secret: Arc<hmac::Key>,
+    request_headers: HeaderMap,
 ) -> Result<impl Reply, Infallible> {
-    let (parts, body) = reply.into_response().into_parts();
+    let (mut parts, body) = reply.into_response().into_parts();
     let (sig, body) = if let Ok(body) = warp::hyper::body::to_bytes(body).await {
-        (
-            base64::encode(hmac::sign(&hmac_secret, &body).as_ref()),
-            warp::hyper::body::Body::from(body),
-        )
+        let sig = base64::encode(hmac::sign(&hmac_secret, &body).as_ref());
+        if body.len() >= COMPRESSION_MIN_SIZE {
+            // Caches in between must not hand a compressed reply to a client
+            // that did not ask for it, nor the other way around
+            parts
+                .headers
+                .append(VARY, HeaderValue::from_static("accept-encoding"));
+        }
+        let compressed = match accepted_encoding(&request_headers) {
+            Some(encoding) if body.len() >= COMPRESSION_MIN_SIZE => {
+                match compress(&body, encoding) {
+                    Ok(compressed) => Some((encoding, compressed)),
+                    Err(e) => {
+                        error!("Failed to {} compress reply: {}", encoding, e);
+                        None
+                    }
+                }
+            }
+            _ => None,
+        };
+        let body = match compressed {
+            Some((encoding, compressed)) => {
+                parts.headers.remove(CONTENT_LENGTH);
+                parts
+                    .headers
+                    .insert(CONTENT_ENCODING, HeaderValue::from_static(encoding));
+                warp::hyper::body::Body::from(compressed)
+            }
+            None => warp::hyper::body::Body::from(body),
+        };
+        (sig, body)
     } else {
         (
             String::from(""),
@@ -279,3 +521,139 @@ async fn rejection_handler(r: Rejection) -> Result<impl Reply, Infallible> {
     Ok(warp::reply::with_status(json, code))
 }
 
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use super::*;
+
+    const OPTIONS_JSON: &str = r#"{
+        "hmac_secret": "c2VjcmV0",
+        "max_num_candidates": 1000,
+        "min_num_of_chars_for_completion": 1,
+        "max_num_candidates_to_detail": 10,
+        "max_diagnostics_to_display": 10,
+        "filepath_blacklist": {},
+        "filepath_completion_use_working_dir": 0,
+        "rust_toolchain_root": ""
+    }"#;
+
+    #[test]
+    fn log_rate_limit() {
+        let mut limit = LogRateLimit::new(3);
+        let start = Instant::now();
+        let logged = (0..100)
+            .filter(|i| limit.check(start + Duration::from_millis(*i)).0)
+            .count();
+        assert_eq!(logged, 3);
+
+        assert_eq!(limit.check(start + Duration::from_secs(1)), (true, 97));
+        assert_eq!(limit.check(start + Duration::from_secs(3)), (true, 0));
+    }
+
+    #[tokio::test]
+    async fn hmac_rejections_rate_limited() {
+        let rejections: &'static Mutex<_> = Box::leak(Box::new(Mutex::new(LogRateLimit::new(3))));
+        let key = hmac::Key::new(hmac::HMAC_SHA256, b"secret");
+        let mut ctx = hmac::Context::with_key(&key);
+        for part in [&b"GET"[..], b"/", b""] {
+            ctx.update(hmac::sign(&key, part).as_ref());
+        }
+        let signature = base64::encode(ctx.sign().as_ref());
+        let filter = hmac_filter_limited(Arc::new(key), rejections);
+        let request = |signature: &str| warp::test::request().header(HMAC_HEADER, signature);
+
+        for _ in 0..5 {
+            assert!(request("bm90IGl0").filter(&filter).await.is_err());
+        }
+        {
+            let limit = rejections.lock().unwrap();
+            assert_eq!((limit.logged, limit.suppressed), (3, 2));
+        }
+        // Only rejected requests count
+        assert!(request(&signature).filter(&filter).await.is_ok());
+        let limit = rejections.lock().unwrap();
+        assert_eq!((limit.logged, limit.suppressed), (3, 2));
+    }
+
+    #[test]
+    fn hmac_key_from_env() {
+        let expected = hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, b"secret"), b"body");
+        let key = hmac_key("", Some(String::from("c2VjcmV0\n"))).unwrap();
+        assert!(hmac::verify(&key, b"body", expected.as_ref()).is_ok());
+
+        // The options take precedence
+        let key = hmac_key("b3RoZXI=", Some(String::from("c2VjcmV0"))).unwrap();
+        assert!(hmac::verify(&key, b"body", expected.as_ref()).is_err());
+
+        let err = hmac_key("", Some(String::from("not base64!")))
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains(HMAC_SECRET_ENV), "{:#}", err);
+        let err = hmac_key("", None).err().unwrap();
+        assert!(err.to_string().contains(HMAC_SECRET_ENV), "{:#}", err);
+    }
+
+    #[test]
+    fn env_var_taken() {
+        // Not HMAC_SECRET_ENV, which servers started by other tests take
+        let name = "YCMD_ROUTES_TEST_TAKEN";
+        std::env::set_var(name, "c2VjcmV0");
+        assert_eq!(take_env_var(name).as_deref(), Some("c2VjcmV0"));
+        assert!(std::env::var_os(name).is_none());
+        assert_eq!(take_env_var(name), None);
+    }
+
+    #[tokio::test]
+    async fn compressed_signed_response() {
+        let options = serde_json::from_str(OPTIONS_JSON).unwrap();
+        let (routes, _shutdown) = get_routes(options).unwrap();
+
+        let candidates = (0..500)
+            .map(|i| format!("candidate_{}", i))
+            .collect::<Vec<_>>();
+        let body = serde_json::to_vec(&serde_json::json!({
+            "candidates": candidates,
+            "sort_property": "",
+            "query": "",
+        }))
+        .unwrap();
+        let key = hmac::Key::new(hmac::HMAC_SHA256, b"secret");
+        let mut ctx = hmac::Context::with_key(&key);
+        for part in [&b"POST"[..], b"/filter_and_sort_candidates", &body] {
+            ctx.update(hmac::sign(&key, part).as_ref());
+        }
+        let signature = base64::encode(ctx.sign().as_ref());
+        let request = || {
+            warp::test::request()
+                .method("POST")
+                .path("/filter_and_sort_candidates")
+                .header(HMAC_HEADER, &signature)
+                .body(&body)
+        };
+
+        let response = request()
+            .header("accept-encoding", "deflate, gzip;q=0.8")
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.headers()["content-encoding"], "gzip");
+        assert_eq!(response.headers()["vary"], "accept-encoding");
+        let mut json = Vec::new();
+        flate2::read::GzDecoder::new(&response.body()[..])
+            .read_to_end(&mut json)
+            .unwrap();
+        assert!(json.len() > response.body().len());
+
+        let signature = base64::decode(&response.headers()[HMAC_HEADER]).unwrap();
+        hmac::verify(&key, &json, &signature).unwrap();
+        let json: Vec<String> = serde_json::from_slice(&json).unwrap();
+        assert_eq!(json.len(), 500);
+
+        // Could have been compressed, caches still need to tell both apart
+        let response = request().reply(&routes).await;
+        assert_eq!(response.status(), 200);
+        assert!(response.headers().get("content-encoding").is_none());
+        assert_eq!(response.headers()["vary"], "accept-encoding");
+    }
+}
@@ -1,16 +1,37 @@
-use std::{collections::HashMap, time::Duration};
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::watch;
 
-use std::sync::Mutex;
+use log::{debug, error};
 
 use crate::completer::{
-    filename::FilenameCompleter, ultisnips::UltisnipsCompleter, Completer, CompletionConfig,
-    GenericCompleters,
+    filename::{default_lone_separator_blacklist, FilenameCompleter},
+    filter_and_sort_completions,
+    lsp::registry::{LspRegistry, LspServerConfig},
+    lsp::root::RootResolution,
+    registry::{default_completers, CompleterRegistry},
+    timing::CompleterTimings,
+    trigger::parse_triggers,
+    truncate_diagnostics, Completer, CompleterBlacklist, CompleterError, CompletionConfig,
+    Completions, GenericCompleters,
 };
+use crate::core::character::Segmentation;
+use crate::core::query::{EmptyOrder, ScoringWeights, Word, WordCache};
+use crate::core::utils::identifier::validate_identifier_regexes;
 
 use super::ycmd_types::*;
 
 #[derive(serde::Deserialize)]
 pub struct Options {
+    /// Base64 encoded, see `routes::HMAC_SECRET_ENV` for leaving it out. The
+    /// server doesn't start without a secret from either
+    #[serde(default)]
     pub hmac_secret: String,
     pub max_num_candidates: usize,
     pub min_num_of_chars_for_completion: usize,
@@ -18,69 +39,295 @@ pub struct Options {
     pub max_diagnostics_to_display: usize,
     pub filepath_blacklist: HashMap<String, String>,
     pub filepath_completion_use_working_dir: u8,
+    /// Whether paths are completed at all
+    #[serde(default = "default_filepath_completion_enabled")]
+    pub filepath_completion_enabled: bool,
+    #[serde(default = "default_lone_separator_blacklist")]
+    pub filepath_lone_separator_blacklist: HashMap<String, String>,
+    /// Directories of a path that don't exist complete as the closest existing
+    /// ones, e.g. `src/cpm/` as `src/cmp/`
+    #[serde(default)]
+    pub filepath_completion_fuzzy_components: bool,
     pub rust_toolchain_root: String,
+    #[serde(default)]
+    pub language_server: Vec<LspServerConfig>,
+    #[serde(default)]
+    pub scoring_weights: ScoringWeights,
+    #[serde(default)]
+    pub semantic_triggers: Vec<HashMap<String, Vec<String>>>,
+    #[serde(default)]
+    pub signature_triggers: Vec<HashMap<String, Vec<String>>>,
+    /// Names of the completers to use, see [`CompleterRegistry`]
+    #[serde(default = "default_completers")]
+    pub completers: Vec<String>,
+    /// Filetypes each completer is disabled for, keyed by completer name
+    #[serde(default)]
+    pub filetype_completer_blacklist: HashMap<String, Vec<String>>,
+    /// Insertion texts longer than this are truncated, 0 disables the limit
+    #[serde(default)]
+    pub max_candidate_length: usize,
+    /// File to keep identifiers in across restarts
+    #[serde(default)]
+    pub identifier_cache_file: Option<PathBuf>,
+    /// `grapheme` or `codepoint`, the units queries are matched in
+    #[serde(default)]
+    pub match_segmentation: Segmentation,
+    /// How the root directory language servers are started in is chosen
+    #[serde(default)]
+    pub lsp_root_resolution: RootResolution,
+    /// Identifiers come from every file in an event's `file_data`, not only the
+    /// current one. Off by default as editors may send many buffers
+    #[serde(default)]
+    pub collect_identifiers_from_all_files: bool,
+    /// Rank candidates language servers marked as deprecated after all others
+    #[serde(default)]
+    pub sink_deprecated_completions: bool,
+    /// Identifiers shorter than this are left out of the identifier completer's
+    /// database, queries still match the longer ones
+    #[serde(default = "default_min_identifier_length")]
+    pub min_identifier_length: usize,
+    /// Candidates that are exactly the query typed so far aren't offered
+    #[serde(default)]
+    pub drop_exact_query_matches: bool,
+    /// Bytes of JSON a completion response may take, candidates lose their details
+    /// and then the last ones are dropped to fit. 0 disables the limit
+    #[serde(default)]
+    pub max_completion_response_size: usize,
+    /// Absolute paths of the files stdout/stderr are redirected to, set by `main`
+    #[serde(skip)]
+    pub logfiles: Vec<PathBuf>,
+}
+
+fn default_filepath_completion_enabled() -> bool {
+    true
 }
 
+fn default_min_identifier_length() -> usize {
+    1
+}
+
+/// Latest completion generation of a file, with a receiver keeping the channel open
+type GenerationChannel = (watch::Sender<u64>, watch::Receiver<u64>);
+
 pub struct ServerState {
     generic_completers: Mutex<GenericCompleters>,
+    word_cache: WordCache,
+    /// Number of completion requests seen per file, a newer request
+    /// supersedes the ones still waiting for a language server
+    completion_generations: Mutex<HashMap<PathBuf, GenerationChannel>>,
     pub options: Options,
 }
 
 impl ServerState {
     pub fn new(options: Options) -> Self {
+        Self::with_registry(options, &CompleterRegistry::default())
+    }
+
+    /// State whose completers are built by `registry`
+    pub fn with_registry(options: Options, registry: &CompleterRegistry) -> Self {
+        // Triggers only matter for filetypes something can complete semantically
+        let filetypes = options
+            .language_server
+            .iter()
+            .flat_map(|s| s.filetypes.iter().cloned())
+            .collect::<HashSet<_>>();
         let config = CompletionConfig {
             min_num_chars: options.min_num_of_chars_for_completion,
-            max_diagnostics_to_display: options.max_num_candidates,
-            completion_triggers: HashMap::default(),
-            signature_triggers: HashMap::default(),
+            max_diagnostics_to_display: options.max_diagnostics_to_display,
+            completion_triggers: parse_triggers(options.semantic_triggers.clone(), &filetypes),
+            signature_triggers: parse_triggers(options.signature_triggers.clone(), &filetypes),
             max_candidates: options.max_num_candidates,
             max_candidates_to_detail: options.max_num_candidates_to_detail,
+            scoring_weights: options.scoring_weights,
+            completer_blacklist: CompleterBlacklist::new(
+                options.filetype_completer_blacklist.clone(),
+            ),
+            identifier_cache: options.identifier_cache_file.clone(),
+            empty_query_order: EmptyOrder::Lexicographic,
+            segmentation: options.match_segmentation,
+            collect_identifiers_from_all_files: options.collect_identifiers_from_all_files,
+            sink_deprecated: options.sink_deprecated_completions,
+            min_identifier_length: options.min_identifier_length,
+            drop_exact_matches: options.drop_exact_query_matches,
+            max_candidate_length: options.max_candidate_length,
         };
 
-        let fname_bl = options
-            .filepath_blacklist
-            .iter()
-            .filter(|(_k, v)| v.as_str().eq("1"))
-            .map(|(k, _v)| k.clone())
-            .collect();
-        let filename_use_working_dir = options.filepath_completion_use_working_dir == 1;
+        let fname_completer = if options.filepath_completion_enabled {
+            let fname_bl = options
+                .filepath_blacklist
+                .iter()
+                .filter(|(_k, v)| v.as_str().eq("1"))
+                .map(|(k, _v)| k.clone())
+                .collect();
+            Some(
+                FilenameCompleter::new(
+                    config.clone(),
+                    fname_bl,
+                    options.filepath_completion_use_working_dir == 1,
+                    options.filepath_lone_separator_blacklist.clone(),
+                )
+                .with_fuzzy_components(options.filepath_completion_fuzzy_components),
+            )
+        } else {
+            None
+        };
+        let lsp = LspRegistry::new(options.language_server.clone(), config.clone())
+            .with_root_resolution(options.lsp_root_resolution);
+        let completers = registry.build(&options.completers, &config);
 
         Self {
             options,
             generic_completers: Mutex::new(GenericCompleters {
-                completers: vec![Box::new(UltisnipsCompleter::new(config.clone()))],
-                fname_completer: FilenameCompleter::new(
-                    config.clone(),
-                    fname_bl,
-                    filename_use_working_dir,
-                ),
+                completers,
+                fname_completer,
+                filetype_completers: HashMap::default(),
+                lsp,
                 config,
+                timings: CompleterTimings::default(),
             }),
+            word_cache: WordCache::default(),
+            completion_generations: Mutex::default(),
         }
     }
 
+    /// Parsed `query`, shared with the previous request if the query did not change
+    pub fn query_word(&self, query: &str) -> Arc<Word<'static>> {
+        self.word_cache.get(query, self.options.match_segmentation)
+    }
+
+    /// Completers are built along with the state, so this only fails
+    /// if one of them panicked while holding the lock
     pub fn is_ready(&self) -> bool {
-        true
+        self.generic_completers.lock().is_ok()
     }
 
     pub fn is_healthy(&self) -> bool {
-        true
+        match self.generic_completers.lock() {
+            Ok(mut completers) => completers.is_healthy(),
+            Err(_) => false,
+        }
     }
 
-    pub fn completions(&self, mut request: SimpleRequest) -> CompletionResponse {
-        let candidates = self
-            .generic_completers
-            .lock()
-            .unwrap()
-            .compute_candidates(&mut request);
-        CompletionResponse {
+    /// Generation of a new completion request for `filepath`, along with a receiver
+    /// seeing any request coming after it
+    fn next_completion_generation(&self, filepath: &Path) -> (u64, watch::Receiver<u64>) {
+        let mut generations = self.completion_generations.lock().unwrap();
+        let (sender, receiver) = generations
+            .entry(filepath.to_path_buf())
+            .or_insert_with(|| watch::channel(0));
+        let generation = *receiver.borrow() + 1;
+        // Can't fail, the map keeps a receiver alive
+        let _ = sender.send(generation);
+        (generation, sender.subscribe())
+    }
+
+    /// Language server requests are awaited after the completers are unlocked,
+    /// dropping the returned future cancels them. So does a newer request for
+    /// the same file, the superseded one returns no candidates
+    pub async fn completions(&self, mut request: SimpleRequest) -> CompletionResponse {
+        if !request.position_in_bounds() {
+            return CompletionResponse {
+                completions: vec![],
+                completion_start_column: request.column_num,
+                errors: vec![],
+                truncated: false,
+            };
+        }
+        let (generation, mut newer) = self.next_completion_generation(&request.filepath);
+        request.query_word = Some(self.query_word(request.query()));
+        let completions = {
+            let completers = self.generic_completers.lock().unwrap();
+            match request.completer_target.clone() {
+                // Language servers are neither queried nor started for the identifier target
+                Some(CompleterTarget::identifier) => {
+                    let (candidates, errors) = completers.generic_candidates(&mut request);
+                    Completions::Ready(candidates, errors)
+                }
+                Some(CompleterTarget::filetype(filetype)) => {
+                    completers.filetype_completions(&filetype, &mut request)
+                }
+                Some(CompleterTarget::filetype_default) | None => {
+                    completers.default_completions(&mut request)
+                }
+            }
+        };
+        let (mut candidates, errors) = match completions {
+            Completions::Ready(candidates, errors) => (candidates, errors),
+            Completions::Pending(pending, config) => tokio::select! {
+                result = pending => match result {
+                    Ok(candidates) => {
+                        (filter_and_sort_completions(candidates, &request, &config), vec![])
+                    }
+                    Err(e) => (vec![], vec![e]),
+                },
+                _ = newer.changed() => {
+                    debug!("Completion request for {:?} superseded", request.filepath);
+                    (vec![], vec![])
+                }
+            },
+        };
+        // Failing completers are reported along with the candidates of the others
+        for e in &errors {
+            error!("Completion failed: {}", e);
+        }
+        if *newer.borrow() != generation {
+            candidates.clear();
+        }
+        candidates.truncate(self.options.max_num_candidates);
+        let mut response = CompletionResponse {
             completions: candidates,
             completion_start_column: request.start_column() + 1,
-            errors: vec![],
+            errors: errors.iter().map(ExceptionResponse::from).collect(),
+            truncated: false,
+        };
+        response.fit_to_size(self.options.max_completion_response_size);
+        response
+    }
+
+    /// Which completers a completion request would use, for `dry_run` requests
+    pub fn completion_decisions(&self, request: SimpleRequest) -> DryRunResponse {
+        let completers = self.generic_completers.lock().unwrap();
+        DryRunResponse {
+            completers: completers.completion_decisions(&request),
         }
     }
 
     pub fn debug_info(&self, _request: SimpleRequest) -> DebugInfo {
+        let logfiles = self
+            .options
+            .logfiles
+            .iter()
+            .map(|p| p.display().to_string())
+            .collect::<Vec<_>>();
+        let mut servers = vec![];
+        let mut items = vec![];
+        if !logfiles.is_empty() {
+            items.push(ItemData {
+                key: "Server logfiles".into(),
+                value: logfiles.join(", "),
+            });
+            servers.push(ServerData {
+                name: "Rust YCMD".into(),
+                is_running: true,
+                executable: std::env::current_exe()
+                    .map(|p| p.display().to_string())
+                    .unwrap_or_default(),
+                address: String::new(),
+                port: 0,
+                pid: std::process::id() as usize,
+                logfiles,
+                extras: vec![],
+            });
+        }
+        let timings = self.generic_completers.lock().unwrap().timings.all();
+        items.extend(timings.into_iter().map(|(name, timing)| ItemData {
+            key: format!("{} completion time", name),
+            value: format!(
+                "last: {:.1}ms, average: {:.1}ms",
+                timing.last().as_secs_f64() * 1000.0,
+                timing.average().as_secs_f64() * 1000.0
+            ),
+        }));
         DebugInfo {
             python: PythonInfo {
                 executable: "/dev/null".into(),
@@ -96,27 +343,175 @@ impl ServerState {
             },
             completer: DebugInfoResponse {
                 name: "Rust YCMD".into(),
-                servers: vec![],
-                items: vec![],
+                servers,
+                items,
             },
         }
     }
 
-    pub fn defined_subcommands(&self, _request: SimpleRequest) -> Vec<String> {
-        vec![]
+    pub fn defined_subcommands(&self, request: SimpleRequest) -> Vec<String> {
+        let completers = self.generic_completers.lock().unwrap();
+        if request
+            .filetypes()
+            .iter()
+            .any(|f| completers.lsp.completer(f).is_some())
+        {
+            vec![
+                String::from("GoToDocumentOutline"),
+                String::from("GoToSymbol"),
+                String::from("RefactorRename"),
+            ]
+        } else {
+            vec![]
+        }
     }
 
-    pub fn semantic_completer_available(&self, _request: SimpleRequest) -> bool {
-        false
+    /// Fetch the details a language server left out of a completion candidate
+    pub async fn resolve_completion(
+        &self,
+        request: ResolveCompletionRequest,
+    ) -> Result<ResolveCompletionResponse, anyhow::Error> {
+        let pending = self
+            .generic_completers
+            .lock()
+            .unwrap()
+            .resolve_completion(&request.request, request.resolve)
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "No semantic completer for {:?}",
+                    request.request.filetypes()
+                )
+            })?;
+        Ok(ResolveCompletionResponse {
+            completion: pending.await?,
+            errors: vec![],
+        })
+    }
+
+    /// Run a subcommand of the semantic completer for the request's filetype.
+    /// `GoToSymbol` searches the whole workspace when given a query, the current
+    /// file otherwise
+    pub async fn run_completer_command(
+        &self,
+        request: CommandRequest,
+    ) -> Result<CommandResponse, CompleterError> {
+        let command = request
+            .command_arguments
+            .first()
+            .map(String::as_str)
+            .unwrap_or_default();
+        let no_completer = || CompleterError::NoCompleter(request.request.filetypes().to_vec());
+        match (command, request.command_arguments.get(1)) {
+            // Every symbol matches an empty query, that is no search at all
+            ("GoToSymbol", Some(query)) if query.trim().is_empty() => {
+                Ok(CommandResponse::Locations(vec![]))
+            }
+            ("GoToSymbol", Some(query)) => {
+                let pending = self
+                    .generic_completers
+                    .lock()
+                    .unwrap()
+                    .workspace_symbols(&request.request, query)
+                    .ok_or_else(no_completer)?;
+                Ok(CommandResponse::Locations(pending.await?))
+            }
+            ("GoToSymbol", None) | ("GoToDocumentOutline", _) => {
+                let pending = self
+                    .generic_completers
+                    .lock()
+                    .unwrap()
+                    .document_symbols(&request.request)
+                    .ok_or_else(no_completer)?;
+                Ok(CommandResponse::Locations(pending.await?))
+            }
+            ("RefactorRename", Some(new_name)) => {
+                let pending = self
+                    .generic_completers
+                    .lock()
+                    .unwrap()
+                    .rename(&request.request, new_name)
+                    .ok_or_else(no_completer)?;
+                Ok(CommandResponse::FixIts {
+                    fixits: vec![pending.await?],
+                })
+            }
+            ("RefactorRename", None) => Err(CompleterError::InvalidCommand(String::from(
+                "RefactorRename requires a new name",
+            ))),
+            ("DebugValidateIdentifierRegexes", _) => {
+                let failures = validate_identifier_regexes();
+                let message = if failures.is_empty() {
+                    String::from("All identifier regexes passed")
+                } else {
+                    format!(
+                        "{} identifier regex checks failed:\n{}",
+                        failures.len(),
+                        failures.join("\n")
+                    )
+                };
+                Ok(CommandResponse::Message { message })
+            }
+            _ => Err(CompleterError::InvalidCommand(format!(
+                "Unknown command: {:?}",
+                command
+            ))),
+        }
+    }
+
+    pub fn semantic_completer_available(&self, request: SimpleRequest) -> bool {
+        self.generic_completers
+            .lock()
+            .unwrap()
+            .semantic_completer_available(request.filetypes())
     }
 
     pub fn signature_help_available(&self, _request: Subserver) -> Available {
         Available::NO
     }
 
+    /// Availability of semantic completion keyed by filetype
+    pub fn filetype_completer_available(&self) -> HashMap<String, Available> {
+        self.generic_completers
+            .lock()
+            .unwrap()
+            .filetype_availability()
+    }
+
+    /// Diagnostics are only returned for `FileReadyToParse`, being the latest
+    /// ones known for the file, e.g. as last published by its language server
     pub fn event_notification(&self, request: EventNotification) -> Vec<DiagnosticData> {
-        self.generic_completers.lock().unwrap().on_event(&request);
-        vec![]
+        if let Event::BufferUnload = request.event_name {
+            // Completion requests still pending for the file see the channel
+            // close and give up like superseded ones
+            self.completion_generations
+                .lock()
+                .unwrap()
+                .remove(Path::new(&request.filepath));
+        }
+        let mut completers = self.generic_completers.lock().unwrap();
+        completers.on_event(&request);
+        if !matches!(request.event_name, Event::FileReadyToParse) {
+            return vec![];
+        }
+        let mut diagnostics = completers.diagnostics(&request);
+        truncate_diagnostics(
+            &mut diagnostics,
+            completers.config.max_diagnostics_to_display,
+        );
+        diagnostics
+    }
+
+    /// Let completers persist their state and stop language servers before the
+    /// server exits
+    pub async fn shutdown(&self) {
+        let language_servers = match self.generic_completers.lock() {
+            Ok(mut completers) => {
+                completers.shutdown();
+                completers.lsp.shutdown()
+            }
+            Err(_) => return,
+        };
+        language_servers.await;
     }
 
     pub async fn get_messages(&self, _request: SimpleRequest) -> MessagePollResponse {
@@ -124,3 +519,1153 @@ impl ServerState {
         MessagePollResponse::MessagePollResponse(true)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use futures::future;
+    use tokio::io::AsyncWriteExt;
+
+    use super::*;
+    use crate::completer::lsp::{client::LspClient, transport::LspTransport, LspCompleter};
+    use crate::completer::CompleterInner;
+
+    fn test_options() -> Options {
+        Options {
+            hmac_secret: String::from("c2VjcmV0"),
+            max_num_candidates: 10,
+            min_num_of_chars_for_completion: 1,
+            max_num_candidates_to_detail: 10,
+            max_diagnostics_to_display: 10,
+            filepath_blacklist: HashMap::default(),
+            filepath_completion_use_working_dir: 0,
+            filepath_completion_enabled: true,
+            filepath_lone_separator_blacklist: default_lone_separator_blacklist(),
+            filepath_completion_fuzzy_components: false,
+            rust_toolchain_root: String::default(),
+            language_server: vec![],
+            scoring_weights: ScoringWeights::default(),
+            semantic_triggers: vec![],
+            signature_triggers: vec![],
+            completers: default_completers(),
+            filetype_completer_blacklist: HashMap::default(),
+            max_candidate_length: 0,
+            identifier_cache_file: None,
+            match_segmentation: Segmentation::Grapheme,
+            lsp_root_resolution: RootResolution::Markers,
+            collect_identifiers_from_all_files: false,
+            sink_deprecated_completions: false,
+            min_identifier_length: 1,
+            drop_exact_query_matches: false,
+            max_completion_response_size: 0,
+            logfiles: vec![],
+        }
+    }
+
+    fn test_request(
+        contents: &str,
+        filetype: &str,
+        column_num: usize,
+        completer_target: Option<CompleterTarget>,
+    ) -> SimpleRequest {
+        SimpleRequest {
+            column_num,
+            completer_target,
+            ..crate::completer::tests::test_request(contents, filetype)
+        }
+    }
+
+    /// Answers every request with what `answer` returns
+    struct StubCompleter {
+        config: CompletionConfig,
+        answer: Box<dyn Fn() -> Result<Vec<Candidate>, CompleterError> + Send>,
+        healthy: bool,
+    }
+
+    impl StubCompleter {
+        /// Offers a candidate for each of `texts`
+        fn new(config: CompletionConfig, texts: &[&str]) -> Self {
+            let candidates = texts.iter().map(|t| candidate(t)).collect::<Vec<_>>();
+            Self::answering(config, move || Ok(candidates.clone()))
+        }
+
+        fn answering(
+            config: CompletionConfig,
+            answer: impl Fn() -> Result<Vec<Candidate>, CompleterError> + Send + 'static,
+        ) -> Self {
+            Self {
+                config,
+                answer: Box::new(answer),
+                healthy: true,
+            }
+        }
+    }
+
+    impl CompleterInner for StubCompleter {
+        fn get_settings(&self) -> &CompletionConfig {
+            &self.config
+        }
+
+        fn get_settings_mut(&mut self) -> &mut CompletionConfig {
+            &mut self.config
+        }
+    }
+
+    impl Completer for StubCompleter {
+        fn name(&self) -> &str {
+            "stub"
+        }
+
+        fn should_use_now(&self, _request: &SimpleRequest) -> bool {
+            true
+        }
+
+        fn is_healthy(&mut self) -> bool {
+            self.healthy
+        }
+
+        fn compute_candidates_inner(
+            &self,
+            _request: &SimpleRequest,
+        ) -> Result<Vec<Candidate>, CompleterError> {
+            (self.answer)()
+        }
+    }
+
+    fn candidate(text: &str) -> Candidate {
+        Candidate {
+            insertion_text: String::from(text),
+            menu_text: None,
+            extra_menu_info: None,
+            detailed_info: None,
+            kind: None,
+            extra_data: None,
+            filter_text: None,
+        }
+    }
+
+    fn stubbed_state() -> ServerState {
+        stubbed_state_with(test_options())
+    }
+
+    fn stubbed_state_with(options: Options) -> ServerState {
+        let state = ServerState::new(options);
+        {
+            let mut completers = state.generic_completers.lock().unwrap();
+            let config = completers.config.clone();
+            completers.completers = vec![Box::new(StubCompleter::new(
+                config.clone(),
+                &["foo_identifier"],
+            ))];
+            completers.filetype_completers.insert(
+                String::from("rust"),
+                Box::new(StubCompleter::new(config, &["foo_semantic"])),
+            );
+        }
+        state
+    }
+
+    fn completion_texts(response: CompletionResponse) -> Vec<String> {
+        response
+            .completions
+            .into_iter()
+            .map(|c| c.insertion_text)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn request_id_reaches_completers() {
+        let state = stubbed_state();
+        {
+            let mut completers = state.generic_completers.lock().unwrap();
+            let config = completers.config.clone();
+            // Offers the id of the request it's working on
+            completers.completers = vec![Box::new(StubCompleter::answering(config, || {
+                Ok(vec![candidate(&crate::correlation::current())])
+            }))];
+        }
+        let id = crate::correlation::next_id("/completions");
+        let request = test_request("comp", "rust", 5, Some(CompleterTarget::identifier));
+        let response = crate::correlation::scope(id.clone(), state.completions(request)).await;
+        assert_eq!(completion_texts(response), vec![id]);
+    }
+
+    #[tokio::test]
+    async fn long_candidates_truncated() {
+        let mut options = test_options();
+        options.max_candidate_length = 5;
+        let state = stubbed_state_with(options);
+        let request = test_request("foo", "rust", 4, Some(CompleterTarget::identifier));
+        let response = state.completions(request).await;
+        assert_eq!(response.completions.len(), 1);
+        assert_eq!(response.completions[0].insertion_text, "foo_i");
+        assert_eq!(
+            response.completions[0].detailed_info.as_deref(),
+            Some("foo_identifier")
+        );
+    }
+
+    #[tokio::test]
+    async fn oversized_response_truncated() {
+        let mut options = test_options();
+        // Full texts are kept as details of the truncated candidates
+        options.max_candidate_length = 5;
+        let mut state = stubbed_state_with(options);
+        {
+            let mut completers = state.generic_completers.lock().unwrap();
+            let config = completers.config.clone();
+            completers.completers = vec![Box::new(StubCompleter::new(
+                config,
+                &[
+                    "foo_a_candidate_with_a_long_detailed_info",
+                    "foo_b_candidate_with_a_long_detailed_info",
+                    "foo_c_candidate_with_a_long_detailed_info",
+                ],
+            ))];
+        }
+        let request = || test_request("foo", "rust", 4, Some(CompleterTarget::identifier));
+        let full = state.completions(request()).await;
+        assert!(!full.truncated);
+        assert!(full.completions.iter().all(|c| c.detailed_info.is_some()));
+
+        // Details of the last candidates go first, enough for them to fit
+        state.options.max_completion_response_size = 250;
+        let stripped = state.completions(request()).await;
+        assert!(stripped.truncated);
+        assert!(serde_json::to_vec(&stripped).unwrap().len() <= 250);
+        let details = stripped
+            .completions
+            .iter()
+            .map(|c| c.detailed_info.is_some())
+            .collect::<Vec<_>>();
+        assert_eq!(details, vec![true, false, false]);
+
+        // Only then are candidates dropped
+        state.options.max_completion_response_size = 110;
+        let dropped = state.completions(request()).await;
+        assert!(dropped.truncated);
+        assert!(serde_json::to_vec(&dropped).unwrap().len() <= 110);
+        assert!(dropped.completions[0].detailed_info.is_none());
+        assert_eq!(completion_texts(dropped), vec!["foo_a"]);
+    }
+
+    #[tokio::test]
+    async fn merged_candidates_capped() {
+        let mut options = test_options();
+        options.max_num_candidates = 2;
+        let state = stubbed_state_with(options);
+        {
+            let mut completers = state.generic_completers.lock().unwrap();
+            let config = completers.config.clone();
+            completers.completers = vec![
+                Box::new(StubCompleter::new(config.clone(), &["foo_a", "foo_bb"])),
+                Box::new(StubCompleter::new(config, &["foo_ccc", "foo"])),
+            ];
+        }
+        let request = test_request("foo", "rust", 4, Some(CompleterTarget::identifier));
+        assert_eq!(
+            completion_texts(state.completions(request).await),
+            vec!["foo", "foo_a"]
+        );
+    }
+
+    #[tokio::test]
+    async fn candidate_length_unlimited_by_default() {
+        let state = stubbed_state();
+        let request = test_request("foo", "rust", 4, Some(CompleterTarget::identifier));
+        let response = state.completions(request).await;
+        assert_eq!(response.completions[0].insertion_text, "foo_identifier");
+        assert_eq!(response.completions[0].detailed_info, None);
+    }
+
+    #[tokio::test]
+    async fn completer_target_identifier() {
+        let state = stubbed_state();
+        let request = test_request("foo", "rust", 4, Some(CompleterTarget::identifier));
+        assert_eq!(
+            completion_texts(state.completions(request).await),
+            vec!["foo_identifier"]
+        );
+    }
+
+    #[tokio::test]
+    async fn match_metadata_only_when_requested() {
+        let state = stubbed_state();
+        let request = test_request("foo", "rust", 4, Some(CompleterTarget::identifier));
+        let completions = state.completions(request).await.completions;
+        assert_eq!(
+            serde_json::to_value(&completions[0]).unwrap(),
+            serde_json::json!({ "insertion_text": "foo_identifier" })
+        );
+
+        let mut request = test_request("foo", "rust", 4, Some(CompleterTarget::identifier));
+        request.include_match_metadata = true;
+        let completions = state.completions(request).await.completions;
+        assert_eq!(
+            serde_json::to_value(&completions[0]).unwrap()["extra_data"]["match_metadata"],
+            serde_json::json!({
+                "query_is_prefix": true,
+                "num_wb_matches": 1,
+                "match_positions": [0, 1, 2],
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn completer_target_filetype() {
+        let state = stubbed_state();
+        let request = test_request(
+            "foo",
+            "rust",
+            4,
+            Some(CompleterTarget::filetype(String::from("rust"))),
+        );
+        assert_eq!(
+            completion_texts(state.completions(request).await),
+            vec!["foo_semantic"]
+        );
+    }
+
+    #[tokio::test]
+    async fn completer_errors_reported() {
+        let state = stubbed_state();
+        {
+            let mut completers = state.generic_completers.lock().unwrap();
+            let config = completers.config.clone();
+            // Their servers weren't started
+            let failing = |config| {
+                Box::new(StubCompleter::answering(config, || {
+                    Err(CompleterError::NotInitialized)
+                }))
+            };
+            completers
+                .filetype_completers
+                .insert(String::from("rust"), failing(config.clone()));
+            completers.completers.push(failing(config));
+        }
+        let error = serde_json::json!({
+            "exception": { "message": "Completer is not initialized yet" },
+            "message": "Completer is not initialized yet",
+            "traceback": "",
+        });
+        // Other completers still answer
+        let request = test_request("foo", "rust", 4, Some(CompleterTarget::identifier));
+        let response = state.completions(request).await;
+        assert_eq!(
+            serde_json::to_value(&response.errors).unwrap(),
+            serde_json::json!([error])
+        );
+        assert_eq!(completion_texts(response), vec!["foo_identifier"]);
+
+        let request = test_request(
+            "foo",
+            "rust",
+            4,
+            Some(CompleterTarget::filetype(String::from("rust"))),
+        );
+        let response = state.completions(request).await;
+        assert!(response.completions.is_empty());
+        assert_eq!(
+            serde_json::to_value(&response.errors).unwrap(),
+            serde_json::json!([error])
+        );
+    }
+
+    #[tokio::test]
+    async fn unknown_command_fails() {
+        let state = stubbed_state();
+        let request = CommandRequest {
+            request: test_request("foo", "rust", 4, None),
+            command_arguments: vec![String::from("Frobnicate")],
+        };
+        match state.run_completer_command(request).await {
+            Err(CompleterError::InvalidCommand(message)) => {
+                assert_eq!(message, "Unknown command: \"Frobnicate\"")
+            }
+            _ => panic!("expected an invalid command error"),
+        }
+    }
+
+    #[tokio::test]
+    async fn completer_target_filetype_unsatisfiable() {
+        let state = stubbed_state();
+        let request = test_request(
+            "foo",
+            "rust",
+            4,
+            Some(CompleterTarget::filetype(String::from("cpp"))),
+        );
+        assert!(state.completions(request).await.completions.is_empty());
+    }
+
+    #[tokio::test]
+    async fn completer_target_filetype_default() {
+        let state = stubbed_state();
+        let request = test_request("foo", "rust", 4, Some(CompleterTarget::filetype_default));
+        assert_eq!(
+            completion_texts(state.completions(request).await),
+            vec!["foo_semantic"]
+        );
+
+        let request = test_request("foo", "text", 4, None);
+        assert_eq!(
+            completion_texts(state.completions(request).await),
+            vec!["foo_identifier"]
+        );
+    }
+
+    fn test_event(
+        event_name: Event,
+        contents: &str,
+        line_num: usize,
+        column_num: usize,
+    ) -> EventNotification {
+        let mut file_data = HashMap::default();
+        file_data.insert(
+            String::from("/foo.rs"),
+            FileData {
+                filetypes: vec![String::from("rust")],
+                contents: String::from(contents),
+            },
+        );
+        EventNotification {
+            line_num,
+            column_num,
+            filepath: String::from("/foo.rs"),
+            file_data,
+            completer_target: None,
+            working_dir: None,
+            extra_conf_data: None,
+            event_name,
+            ultisnips_snippets: None,
+        }
+    }
+
+    /// State whose ultisnips completer knows `snippets`, as `(trigger, description)`
+    fn state_with_snippets(snippets: &[(&str, &str)]) -> ServerState {
+        let state = ServerState::new(test_options());
+        let mut event = test_event(Event::BufferVisit, "", 1, 1);
+        event.ultisnips_snippets = Some(
+            snippets
+                .iter()
+                .map(|(trigger, description)| UltisnipSnippet {
+                    trigger: String::from(*trigger),
+                    description: String::from(*description),
+                    body: None,
+                })
+                .collect(),
+        );
+        state.event_notification(event);
+        state
+    }
+
+    #[tokio::test]
+    async fn snippets_matched_by_description() {
+        let state = state_with_snippets(&[("ife", "if else"), ("st", "struct")]);
+        let request = test_request("else", "rust", 5, None);
+        assert_eq!(
+            completion_texts(state.completions(request).await),
+            vec!["ife"]
+        );
+    }
+
+    #[tokio::test]
+    async fn snippet_word_boundary_matches_first() {
+        let state = state_with_snippets(&[
+            ("from", "from import"),
+            ("forr", "for range"),
+            ("for_range", "for loop"),
+        ]);
+        let request = test_request("fr", "rust", 3, None);
+        let texts = completion_texts(state.completions(request).await);
+        // A prefix match otherwise ranks first
+        assert_eq!(texts.len(), 3);
+        assert_eq!(texts[2], "from");
+    }
+
+    #[tokio::test]
+    async fn snippet_word_boundary_matches_ranked_with_identifiers() {
+        let state = state_with_snippets(&[("forr", "for range")]);
+        let contents = "let from_str = 1;\nfr";
+        state.event_notification(test_event(Event::FileReadyToParse, contents, 2, 3));
+
+        let mut request = test_request(contents, "rust", 3, None);
+        request.line_num = 2;
+        assert_eq!(
+            completion_texts(state.completions(request).await),
+            vec!["from_str", "forr"]
+        );
+    }
+
+    #[tokio::test]
+    async fn identifiers_from_file_ready_to_parse() {
+        let state = ServerState::new(test_options());
+        let contents = "let foo_bar = baz;\nfo";
+        state.event_notification(test_event(Event::FileReadyToParse, contents, 2, 3));
+
+        let mut request = test_request(contents, "rust", 3, None);
+        request.line_num = 2;
+        assert_eq!(
+            completion_texts(state.completions(request).await),
+            vec!["foo_bar"]
+        );
+    }
+
+    #[tokio::test]
+    async fn identifiers_from_other_files() {
+        let contents = "let foo_bar = baz;\nfo";
+        let event = || {
+            let mut event = test_event(Event::FileReadyToParse, contents, 2, 3);
+            event.file_data.insert(
+                String::from("/other.rs"),
+                FileData {
+                    filetypes: vec![String::from("rust")],
+                    contents: String::from("fn foo_other() {}"),
+                },
+            );
+            event
+        };
+        let texts = |state: ServerState| async move {
+            state.event_notification(event());
+            let mut request = test_request(contents, "rust", 3, None);
+            request.line_num = 2;
+            completion_texts(state.completions(request).await)
+        };
+
+        assert_eq!(
+            texts(ServerState::new(test_options())).await,
+            vec!["foo_bar"]
+        );
+        let mut options = test_options();
+        options.collect_identifiers_from_all_files = true;
+        assert_eq!(
+            texts(ServerState::new(options)).await,
+            vec!["foo_other", "foo_bar"]
+        );
+    }
+
+    #[tokio::test]
+    async fn short_identifiers_not_collected() {
+        let contents = "let x = xy + xyz;\nx";
+        let texts = |state: ServerState| async move {
+            state.event_notification(test_event(Event::FileReadyToParse, contents, 2, 2));
+            let mut request = test_request(contents, "rust", 2, None);
+            request.line_num = 2;
+            completion_texts(state.completions(request).await)
+        };
+
+        assert_eq!(
+            texts(ServerState::new(test_options())).await,
+            vec!["x", "xy", "xyz"]
+        );
+        let mut options = test_options();
+        options.min_identifier_length = 2;
+        assert_eq!(texts(ServerState::new(options)).await, vec!["xy", "xyz"]);
+    }
+
+    /// Replace the state's language servers with a rust one that never
+    /// finishes starting, returning how many times it was spawned
+    fn count_lsp_spawns(state: &ServerState) -> Arc<AtomicUsize> {
+        let spawned = Arc::new(AtomicUsize::new(0));
+        let counter = spawned.clone();
+        let mut completers = state.generic_completers.lock().unwrap();
+        completers.lsp = LspRegistry::with_spawner(
+            vec![LspServerConfig {
+                name: String::from("rust-analyzer"),
+                filetypes: vec![String::from("rust")],
+                cmdline: vec![String::from("rust-analyzer")],
+                port: None,
+                env: HashMap::default(),
+                clear_env: false,
+            }],
+            completers.config.clone(),
+            Arc::new(move |_, _, _| {
+                counter.fetch_add(1, Ordering::SeqCst);
+                Box::pin(future::pending())
+            }),
+        );
+        spawned
+    }
+
+    #[tokio::test]
+    async fn filename_completion_start_column() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::File::create(dir.path().join("foo_file")).unwrap();
+        let state = ServerState::new(test_options());
+
+        let contents = format!("let x = \"{}/fo", dir.path().display());
+        let request = test_request(&contents, "rust", contents.len() + 1, None);
+        let response = state.completions(request).await;
+        assert_eq!(
+            response.completion_start_column,
+            contents.rfind('/').unwrap() + 2
+        );
+        assert_eq!(completion_texts(response), vec!["foo_file"]);
+
+        // Nothing in the directory matches, identifiers start after the `-`
+        let contents = format!("let x = \"{}/nothing-ba", dir.path().display());
+        let request = test_request(&contents, "rust", contents.len() + 1, None);
+        let response = state.completions(request).await;
+        assert_eq!(
+            response.completion_start_column,
+            contents.rfind('-').unwrap() + 2
+        );
+    }
+
+    #[tokio::test]
+    async fn filename_completion_disabled() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::File::create(dir.path().join("foo_file")).unwrap();
+        let mut options = test_options();
+        options.filepath_completion_enabled = false;
+        let state = ServerState::new(options);
+
+        let contents = format!("let x = \"{}/fo", dir.path().display());
+        let request = test_request(&contents, "rust", contents.len() + 1, None);
+        assert!(state.completions(request).await.completions.is_empty());
+    }
+
+    #[test]
+    fn dry_run_lists_completer_decisions() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = ServerState::new(test_options());
+        let contents = format!("let x = \"{}/fo", dir.path().display());
+        let mut request = test_request(&contents, "rust", contents.len() + 1, None);
+        request.dry_run = true;
+
+        let decision =
+            |completer: &str, should_use_now, start_column, query: &str| CompleterDecision {
+                completer: String::from(completer),
+                should_use_now,
+                blacklisted: false,
+                start_column,
+                query: String::from(query),
+            };
+        let response = state.completion_decisions(request);
+        assert_eq!(
+            response.completers,
+            vec![
+                decision("filename", true, contents.len() - 1, "fo"),
+                decision("ultisnips", true, contents.len() - 1, "fo"),
+                decision("identifier", true, contents.len() - 1, "fo"),
+            ]
+        );
+
+        // Snippets count the character under the cursor as part of the query
+        let request = test_request("let x = 1", "rust", 10, None);
+        let response = state.completion_decisions(request);
+        assert_eq!(
+            response.completers,
+            vec![
+                decision("filename", false, 10, ""),
+                decision("ultisnips", true, 10, ""),
+                decision("identifier", false, 10, ""),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn candidates_filtered_by_kind() {
+        let state = ServerState::new(test_options());
+        {
+            let mut completers = state.generic_completers.lock().unwrap();
+            let config = completers.config.clone();
+            completers.completers = vec![Box::new(StubCompleter::answering(config, || {
+                let with_kind = |text, kind: Option<&str>| Candidate {
+                    kind: kind.map(String::from),
+                    ..candidate(text)
+                };
+                Ok(vec![
+                    with_kind("foo_fn", Some("Function")),
+                    with_kind("foo_keyword", Some("Keyword")),
+                    with_kind("foo_plain", None),
+                    with_kind("foo_struct", Some("Struct")),
+                ])
+            }))];
+        }
+        let texts = |kinds: Option<Vec<&str>>| {
+            let mut request = test_request("foo", "rust", 4, Some(CompleterTarget::identifier));
+            request.kind_filter = kinds.map(|k| k.into_iter().map(String::from).collect());
+            let completions = state.completions(request);
+            async { completion_texts(completions.await) }
+        };
+
+        assert_eq!(
+            texts(None).await,
+            vec!["foo_fn", "foo_plain", "foo_struct", "foo_keyword"]
+        );
+        assert_eq!(
+            texts(Some(vec!["Function"])).await,
+            vec!["foo_fn", "foo_plain"]
+        );
+        assert_eq!(
+            texts(Some(vec!["!Keyword"])).await,
+            vec!["foo_fn", "foo_struct"]
+        );
+        assert_eq!(
+            texts(Some(vec!["Function", "Struct", "!Struct"])).await,
+            vec!["foo_fn"]
+        );
+    }
+
+    #[tokio::test]
+    async fn completers_limited_by_name() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("foo_file"), "").unwrap();
+        let state = ServerState::new(test_options());
+        let contents = format!("let x = \"{}/fo", dir.path().display());
+        let request = |completers: Option<Vec<&str>>| {
+            let mut request = test_request(&contents, "rust", contents.len() + 1, None);
+            request.completers =
+                completers.map(|names| names.into_iter().map(String::from).collect());
+            request
+        };
+
+        assert_eq!(
+            completion_texts(state.completions(request(None)).await),
+            vec!["foo_file"]
+        );
+        assert_eq!(
+            completion_texts(state.completions(request(Some(vec![]))).await),
+            vec!["foo_file"]
+        );
+        assert_eq!(
+            completion_texts(state.completions(request(Some(vec!["filename"]))).await),
+            vec!["foo_file"]
+        );
+        assert!(state
+            .completions(request(Some(vec!["ultisnips"])))
+            .await
+            .completions
+            .is_empty());
+
+        // Language servers are all matched by the prefix of their names
+        let request = request(Some(vec!["lsp"]));
+        assert!(request.wants_completer("lsp:rust"));
+        assert!(!request.wants_completer("filename"));
+    }
+
+    #[test]
+    fn builtin_completer_names() {
+        let state = ServerState::new(test_options());
+        let completers = state.generic_completers.lock().unwrap();
+        let names = completers
+            .completers
+            .iter()
+            .map(|c| c.name())
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["ultisnips", "identifier"]);
+        assert_eq!(
+            completers.fname_completer.as_ref().unwrap().name(),
+            "filename"
+        );
+        assert_eq!(completers.name(), "generic");
+    }
+
+    #[tokio::test]
+    async fn diagnostics_truncated_by_priority() {
+        let mut options = test_options();
+        options.max_diagnostics_to_display = 3;
+        let state = ServerState::new(options);
+        let servers = start_silent_language_server(&state).await;
+        let mut server = servers.lock().unwrap().pop().unwrap();
+
+        // Hint, warning, information, error, warning, error
+        let diagnostics = [4, 2, 3, 1, 2, 1]
+            .iter()
+            .enumerate()
+            .map(|(i, severity)| {
+                serde_json::json!({
+                    "range": {
+                        "start": {"line": i, "character": 0},
+                        "end": {"line": i, "character": 1},
+                    },
+                    "severity": severity,
+                    "message": format!("diagnostic {}", i),
+                })
+            })
+            .collect::<Vec<_>>();
+        let notification = serde_json::json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/publishDiagnostics",
+            "params": {"uri": "file:///foo.rs", "diagnostics": diagnostics},
+        })
+        .to_string();
+        server
+            .write_all(
+                format!(
+                    "Content-Length: {}\r\n\r\n{}",
+                    notification.len(),
+                    notification
+                )
+                .as_bytes(),
+            )
+            .await
+            .unwrap();
+
+        let parse = || state.event_notification(test_event(Event::FileReadyToParse, "", 1, 1));
+        let mut diagnostics = parse();
+        while diagnostics.is_empty() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            diagnostics = parse();
+        }
+        let kinds = diagnostics.iter().map(|d| d.kind()).collect::<Vec<_>>();
+        assert_eq!(
+            kinds,
+            vec![
+                DiagnosticKind::ERROR,
+                DiagnosticKind::ERROR,
+                DiagnosticKind::WARNING
+            ]
+        );
+        // Only parsing reports diagnostics
+        assert!(state
+            .event_notification(test_event(Event::BufferVisit, "", 1, 1))
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn buffer_visit_starts_language_server_once() {
+        let state = ServerState::new(test_options());
+        let spawned = count_lsp_spawns(&state);
+
+        state.event_notification(test_event(Event::BufferVisit, "", 1, 1));
+        state.event_notification(test_event(Event::BufferVisit, "", 1, 1));
+        assert_eq!(spawned.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn identifier_target_never_starts_language_server() {
+        let state = ServerState::new(test_options());
+        let spawned = count_lsp_spawns(&state);
+        let contents = "let foo_bar = baz;\nfo";
+        state.event_notification(test_event(Event::FileReadyToParse, contents, 2, 3));
+
+        let mut request = test_request(contents, "rust", 3, Some(CompleterTarget::identifier));
+        request.line_num = 2;
+        assert_eq!(
+            completion_texts(state.completions(request).await),
+            vec!["foo_bar"]
+        );
+        tokio::task::yield_now().await;
+        assert_eq!(spawned.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn configured_language_servers_available() {
+        let mut options = test_options();
+        options.language_server = vec![
+            LspServerConfig {
+                name: String::from("rust-analyzer"),
+                filetypes: vec![String::from("rust")],
+                cmdline: vec![String::from("rust-analyzer")],
+                port: None,
+                env: HashMap::default(),
+                clear_env: false,
+            },
+            LspServerConfig {
+                name: String::from("pyls"),
+                filetypes: vec![String::from("python")],
+                cmdline: vec![String::from("pyls")],
+                port: None,
+                env: HashMap::default(),
+                clear_env: false,
+            },
+        ];
+        let state = ServerState::new(options);
+        let available = state.filetype_completer_available();
+        assert_eq!(available.len(), 2);
+        assert_eq!(available["rust"], Available::YES);
+        assert_eq!(available["python"], Available::YES);
+    }
+
+    #[tokio::test]
+    async fn starting_language_server_pending() {
+        let state = ServerState::new(test_options());
+        count_lsp_spawns(&state);
+        state.event_notification(test_event(Event::BufferVisit, "", 1, 1));
+        assert_eq!(
+            state.filetype_completer_available()["rust"],
+            Available::PENDING
+        );
+    }
+
+    /// Start a rust language server for the state that never answers, returning
+    /// the server ends of its connections
+    async fn start_silent_language_server(
+        state: &ServerState,
+    ) -> Arc<Mutex<Vec<tokio::io::DuplexStream>>> {
+        let servers = Arc::new(Mutex::new(vec![]));
+        {
+            let mut completers = state.generic_completers.lock().unwrap();
+            completers.lsp = LspRegistry::with_spawner(
+                vec![LspServerConfig {
+                    name: String::from("rust-analyzer"),
+                    filetypes: vec![String::from("rust")],
+                    cmdline: vec![String::from("rust-analyzer")],
+                    port: None,
+                    env: HashMap::default(),
+                    clear_env: false,
+                }],
+                completers.config.clone(),
+                Arc::new({
+                    let servers = servers.clone();
+                    move |_, config, _| {
+                        let (client, server) = tokio::io::duplex(4096);
+                        servers.lock().unwrap().push(server);
+                        let (client_r, client_w) = tokio::io::split(client);
+                        let client =
+                            LspClient::from_transport(LspTransport::new(client_r, client_w));
+                        Box::pin(future::ok(LspCompleter::with_client(client, config)))
+                    }
+                }),
+            );
+        }
+        state.event_notification(test_event(Event::BufferVisit, "", 1, 1));
+        tokio::task::yield_now().await;
+        servers
+    }
+
+    #[tokio::test]
+    async fn newer_request_supersedes_pending_one() {
+        let state = Arc::new(ServerState::new(test_options()));
+        start_silent_language_server(&state).await;
+        let target = || Some(CompleterTarget::filetype(String::from("rust")));
+
+        let mut first = tokio::spawn({
+            let state = state.clone();
+            let request = test_request("foo.", "rust", 5, target());
+            async move { state.completions(request).await }
+        });
+        assert!(tokio::time::timeout(Duration::from_millis(10), &mut first)
+            .await
+            .is_err());
+
+        let mut second = tokio::spawn({
+            let state = state.clone();
+            let request = test_request("foo.b", "rust", 6, target());
+            async move { state.completions(request).await }
+        });
+        let first = tokio::time::timeout(Duration::from_secs(5), first)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(first.completions.is_empty());
+        assert!(tokio::time::timeout(Duration::from_millis(10), &mut second)
+            .await
+            .is_err());
+        second.abort();
+    }
+
+    #[tokio::test]
+    async fn completion_generations_dropped_on_unload() {
+        let state = stubbed_state();
+        state
+            .completions(test_request("foo", "rust", 4, None))
+            .await;
+        assert!(state
+            .completion_generations
+            .lock()
+            .unwrap()
+            .contains_key(Path::new("/foo.rs")));
+
+        state.event_notification(test_event(Event::BufferUnload, "", 1, 1));
+        assert!(state.completion_generations.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn semantic_completer_available_once_initialized() {
+        let state = ServerState::new(test_options());
+        count_lsp_spawns(&state);
+        state.event_notification(test_event(Event::BufferVisit, "", 1, 1));
+        assert!(!state.semantic_completer_available(test_request("", "rust", 1, None)));
+
+        let state = ServerState::new(test_options());
+        start_silent_language_server(&state).await;
+        assert!(state.semantic_completer_available(test_request("", "rust", 1, None)));
+        assert!(!state.semantic_completer_available(test_request("", "python", 1, None)));
+    }
+
+    struct TriggeredCompleter {
+        config: CompletionConfig,
+    }
+
+    impl CompleterInner for TriggeredCompleter {
+        fn get_settings(&self) -> &CompletionConfig {
+            &self.config
+        }
+
+        fn get_settings_mut(&mut self) -> &mut CompletionConfig {
+            &mut self.config
+        }
+    }
+
+    impl Completer for TriggeredCompleter {
+        fn name(&self) -> &str {
+            "triggered"
+        }
+    }
+
+    #[test]
+    fn semantic_triggers_from_options() {
+        let mut options = test_options();
+        options.semantic_triggers = vec![vec![(
+            String::from("rust,c"),
+            vec![String::from("."), String::from(r"re!\w+::")],
+        )]
+        .into_iter()
+        .collect()];
+        let state = ServerState::new(options);
+        let completer = TriggeredCompleter {
+            config: state.generic_completers.lock().unwrap().config.clone(),
+        };
+
+        assert!(completer.should_use_now(&test_request("foo.", "rust", 5, None)));
+        assert!(completer.should_use_now(&test_request("foo::", "rust", 6, None)));
+        assert!(completer.should_use_now(&test_request("foo.", "c", 5, None)));
+        assert!(!completer.should_use_now(&test_request("foo", "rust", 4, None)));
+        assert!(!completer.should_use_now(&test_request("foo.", "python", 5, None)));
+    }
+
+    #[test]
+    fn health_reflects_completers() {
+        let state = stubbed_state();
+        assert!(state.is_ready());
+        assert!(state.is_healthy());
+
+        let config = state.generic_completers.lock().unwrap().config.clone();
+        state
+            .generic_completers
+            .lock()
+            .unwrap()
+            .filetype_completers
+            .insert(
+                String::from("python"),
+                Box::new(StubCompleter {
+                    healthy: false,
+                    ..StubCompleter::new(config, &[])
+                }),
+            );
+        assert!(state.is_ready());
+        assert!(!state.is_healthy());
+    }
+
+    #[tokio::test]
+    async fn completions_out_of_bounds() {
+        let state = stubbed_state();
+        let mut request = test_request("foo", "rust", 4, None);
+        request.line_num = 2;
+        assert!(state.completions(request).await.completions.is_empty());
+
+        let request = test_request("foo", "rust", 0, None);
+        assert!(state.completions(request).await.completions.is_empty());
+    }
+
+    #[test]
+    fn debug_info_lists_logfiles() {
+        let state = ServerState::new(test_options());
+        let request = test_request("", "rust", 1, None);
+        let debug_info = serde_json::to_value(state.debug_info(request)).unwrap();
+        assert_eq!(debug_info["completer"]["servers"], serde_json::json!([]));
+
+        let mut options = test_options();
+        options.logfiles = vec![
+            PathBuf::from("/tmp/ycmd_stdout.log"),
+            PathBuf::from("/tmp/ycmd_stderr.log"),
+        ];
+        let state = ServerState::new(options);
+        let request = test_request("", "rust", 1, None);
+        let debug_info = serde_json::to_value(state.debug_info(request)).unwrap();
+        assert_eq!(
+            debug_info["completer"]["servers"][0]["logfiles"],
+            serde_json::json!(["/tmp/ycmd_stdout.log", "/tmp/ycmd_stderr.log"])
+        );
+        assert_eq!(
+            debug_info["completer"]["items"],
+            serde_json::json!([{
+                "key": "Server logfiles",
+                "value": "/tmp/ycmd_stdout.log, /tmp/ycmd_stderr.log",
+            }])
+        );
+    }
+
+    #[tokio::test]
+    async fn completion_time_in_debug_info() {
+        let state = ServerState::new(test_options());
+        let contents = "let foo_bar = baz;\nfo";
+        state.event_notification(test_event(Event::FileReadyToParse, contents, 2, 3));
+        let mut request = test_request(contents, "rust", 3, None);
+        request.line_num = 2;
+        assert_eq!(
+            completion_texts(state.completions(request).await),
+            vec!["foo_bar"]
+        );
+
+        let timing = {
+            let completers = state.generic_completers.lock().unwrap();
+            completers.timings.get("identifier").unwrap()
+        };
+        assert!(timing.last() > Duration::from_nanos(0));
+        assert!(timing.average() > Duration::from_nanos(0));
+
+        let debug_info =
+            serde_json::to_value(state.debug_info(test_request("", "rust", 1, None))).unwrap();
+        let items = debug_info["completer"]["items"].as_array().unwrap();
+        assert!(items
+            .iter()
+            .any(|item| item["key"] == "identifier completion time"));
+    }
+
+    #[tokio::test]
+    async fn blacklisted_completer_is_skipped() {
+        let mut options = test_options();
+        options.filetype_completer_blacklist =
+            vec![(String::from("identifier"), vec![String::from("text")])]
+                .into_iter()
+                .collect();
+        let state = ServerState::new(options);
+        let contents = "let foo_bar = baz;\nfo";
+        for filetype in ["rust", "text"] {
+            let mut event = test_event(Event::FileReadyToParse, contents, 2, 3);
+            event.file_data.get_mut("/foo.rs").unwrap().filetypes = vec![String::from(filetype)];
+            state.event_notification(event);
+        }
+
+        let mut request = test_request(contents, "text", 3, None);
+        request.line_num = 2;
+        assert!(state.completions(request).await.completions.is_empty());
+
+        let mut request = test_request(contents, "rust", 3, None);
+        request.line_num = 2;
+        assert_eq!(
+            completion_texts(state.completions(request).await),
+            vec!["foo_bar"]
+        );
+
+        let blacklist = CompleterBlacklist::new(
+            vec![(String::from("identifier"), vec![String::from("*")])]
+                .into_iter()
+                .collect(),
+        );
+        assert!(blacklist.disables("identifier", &[String::from("rust")]));
+        assert!(!blacklist.disables("filename", &[String::from("rust")]));
+    }
+
+    #[tokio::test]
+    async fn completers_from_registry() {
+        let mut registry = CompleterRegistry::empty();
+        registry.register("fake", |config: &CompletionConfig| {
+            Box::new(StubCompleter::new(config.clone(), &["foo_fake"])) as Box<dyn Completer + Send>
+        });
+        let mut options = test_options();
+        options.completers = vec![String::from("fake"), String::from("missing")];
+        let state = ServerState::with_registry(options, &registry);
+
+        let request = test_request("foo", "rust", 4, Some(CompleterTarget::identifier));
+        assert_eq!(
+            completion_texts(state.completions(request).await),
+            vec!["foo_fake"]
+        );
+    }
+}
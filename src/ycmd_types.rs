@@ -1,18 +1,40 @@
 #![allow(dead_code)]
 
-use std::{collections::HashMap, path::PathBuf, str::Lines};
+use std::{collections::HashMap, path::PathBuf, sync::Arc};
 
 use serde::{Deserialize, Serialize};
 
+use crate::completer::ultisnips::SnippetElement;
+use crate::core::character::Segmentation;
+use crate::core::query::{MatchMetadata, Word};
 use crate::core::utils::identifier::start_of_longest_identifier_ending_at_index;
 
-#[derive(Serialize, Clone, Debug)]
+#[derive(Serialize, Clone, Debug, PartialEq)]
 pub struct Location {
     line_num: usize,
     column_num: usize,
     filepath: String,
 }
 
+impl Location {
+    /// `line_num` and `column_num` are 1-based, the column being a byte offset
+    pub fn new(filepath: String, line_num: usize, column_num: usize) -> Self {
+        Self {
+            line_num,
+            column_num,
+            filepath,
+        }
+    }
+}
+
+/// Where a GoTo command may jump to
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct GoToLocation {
+    #[serde(flatten)]
+    pub location: Location,
+    pub description: String,
+}
+
 #[derive(Deserialize, Debug)]
 pub struct FileData {
     pub filetypes: Vec<String>,
@@ -26,12 +48,17 @@ pub enum Event {
     BufferVisit,
     InsertLeave,
     CurrentIdentifierFinished,
+    /// The buffer was written to disk
+    FileSave,
 }
 
 #[derive(Deserialize, Debug)]
 pub struct UltisnipSnippet {
     pub trigger: String,
     pub description: String,
+    /// Not sent by older clients
+    #[serde(default)]
+    pub body: Option<String>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -61,11 +88,85 @@ pub struct SimpleRequest {
     /// Override that can be set by completer. Although this is a bit ugly
     #[serde(skip)]
     pub start_column: Option<usize>,
+    /// Parsed query shared between requests, see `query_word`
+    #[serde(skip)]
+    pub query_word: Option<Arc<Word<'static>>>,
+    /// Attach `MatchMetadata` to returned candidates
+    #[serde(default)]
+    pub include_match_metadata: bool,
+    /// Answer `/completions` with a `DryRunResponse` instead of candidates
+    #[serde(default)]
+    pub dry_run: bool,
+    /// Names of the completers allowed to run, every one of them when missing
+    /// or empty. Narrows `completer_target` further
+    #[serde(default)]
+    pub completers: Option<Vec<String>>,
+    /// Kinds of the candidates to return, `!` in front of a kind excludes it
+    /// instead. See `KindFilter`
+    #[serde(default)]
+    pub kind_filter: Option<Vec<String>>,
+    /// 1-based byte offset to complete at instead of `column_num`, which stays
+    /// the cursor column. See `completion_column`
+    #[serde(default)]
+    pub completion_column_num: Option<usize>,
+}
+
+/// Kinds a request lets through, see `SimpleRequest::kind_filter`
+pub struct KindFilter<'a> {
+    allowed: Vec<&'a str>,
+    excluded: Vec<&'a str>,
+}
+
+impl KindFilter<'_> {
+    /// Whether a candidate of `kind` passes. Once a kind is listed without `!`,
+    /// only listed kinds pass. Candidates without a kind pass unless some kind
+    /// is excluded
+    pub fn allows(&self, kind: Option<&str>) -> bool {
+        match kind {
+            None => self.excluded.is_empty(),
+            Some(kind) => {
+                !self.excluded.contains(&kind)
+                    && (self.allowed.is_empty() || self.allowed.contains(&kind))
+            }
+        }
+    }
 }
 
 impl SimpleRequest {
-    pub fn lines(&self) -> Lines {
-        self.file_data.get(&self.filepath).unwrap().contents.lines()
+    /// Lines of the current file without terminators. Both `\n` and `\r\n` are
+    /// accepted so byte columns within a line are the same for either style
+    pub fn lines(&self) -> impl Iterator<Item = &str> {
+        self.file_data
+            .get(&self.filepath)
+            .map(|f| f.contents.as_str())
+            .unwrap_or_default()
+            .split('\n')
+            .map(|l| l.strip_suffix('\r').unwrap_or(l))
+    }
+
+    /// Whether completer `name` may run, see `completers`. `lsp` stands for
+    /// every language server, named `lsp:<filetype>`
+    pub fn wants_completer(&self, name: &str) -> bool {
+        match &self.completers {
+            Some(names) if !names.is_empty() => names
+                .iter()
+                .any(|n| n == name || name.split(':').next() == Some(n.as_str())),
+            _ => true,
+        }
+    }
+
+    /// `kind_filter` parsed, to check every candidate against
+    pub fn kinds(&self) -> KindFilter<'_> {
+        let (excluded, allowed) = self
+            .kind_filter
+            .iter()
+            .flatten()
+            .map(String::as_str)
+            .partition(|k| k.starts_with('!'));
+        KindFilter {
+            allowed,
+            excluded: excluded.into_iter().map(|k: &str| &k[1..]).collect(),
+        }
     }
 
     pub fn filetypes(&self) -> &[String] {
@@ -79,9 +180,27 @@ impl SimpleRequest {
         self.filetypes().get(0).map(String::as_str)
     }
 
-    /// current line
+    /// current line, empty if `line_num` is past the end of the file
     pub fn line_value(&self) -> &str {
-        self.lines().nth(self.line_num - 1).unwrap()
+        self.line_num
+            .checked_sub(1)
+            .and_then(|n| self.lines().nth(n))
+            .unwrap_or_default()
+    }
+
+    /// Whether `line_num`/`column_num`, and `completion_column_num` if given,
+    /// point inside of the file
+    pub fn position_in_bounds(&self) -> bool {
+        let columns = 1..=self.line_value().len() + 1;
+        (1..=self.lines().count()).contains(&self.line_num)
+            && columns.contains(&self.column_num)
+            && columns.contains(&self.completion_column())
+    }
+
+    /// 1-based column completions are computed at, `completion_column_num` if
+    /// given and the cursor column otherwise
+    pub fn completion_column(&self) -> usize {
+        self.completion_column_num.unwrap_or(self.column_num)
     }
 
     /// The calculated start column, as a byte offset into the UTF-8 encoded
@@ -90,7 +209,7 @@ impl SimpleRequest {
         self.start_column.unwrap_or_else(|| {
             start_of_longest_identifier_ending_at_index(
                 self.line_value(),
-                self.column_num - 1,
+                self.completion_column().saturating_sub(1),
                 self.first_filetype(),
             )
         })
@@ -99,33 +218,56 @@ impl SimpleRequest {
     /// 'query' after the beginning
     /// of the identifier to be completed
     pub fn query(&self) -> &str {
-        &dbg!(self.line_value())[dbg!(self.start_column())..=dbg!(self.column_num - 2)]
+        let line = self.line_value();
+        let end = self.completion_column().saturating_sub(1).min(line.len());
+        line.get(self.start_column()..end).unwrap_or_default()
+    }
+
+    /// Parsed 'query', reusing `query_word` if it is still up to date
+    pub fn query_word(&self, segmentation: Segmentation) -> Arc<Word<'static>> {
+        let query = self.query();
+        match &self.query_word {
+            Some(word) if word.text == query && word.segmentation == segmentation => word.clone(),
+            _ => Arc::new(Word::with_segmentation(query.to_owned(), segmentation)),
+        }
     }
 
     /// line value up to the character
     /// before the start of 'query'
     pub fn prefix(&self) -> &str {
-        let start = self.start_column();
-        if start == 0 {
-            ""
-        } else {
-            &self.line_value()[..=self.start_column() - 1]
-        }
+        self.line_value()
+            .get(..self.start_column())
+            .unwrap_or_default()
     }
 }
 
-#[derive(Serialize, Clone, Debug)]
+#[derive(Serialize, Clone, Debug, PartialEq)]
 pub struct Range {
     start: Location,
     end: Location,
 }
 
-#[derive(Serialize, Clone, Debug)]
+impl Range {
+    pub fn new(start: Location, end: Location) -> Self {
+        Self { start, end }
+    }
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
 pub struct FixitChunk {
     replacement_string: String,
     range: Range,
 }
 
+impl FixitChunk {
+    pub fn new(replacement_string: String, range: Range) -> Self {
+        Self {
+            replacement_string,
+            range,
+        }
+    }
+}
+
 #[derive(Serialize, Clone, Debug)]
 pub struct Fixit {
     text: String,
@@ -135,11 +277,37 @@ pub struct Fixit {
     chunks: Vec<FixitChunk>,
 }
 
-#[derive(Serialize, Clone, Debug)]
+impl Fixit {
+    /// Fix applied at `location` by replacing `chunks`, which may span several files
+    pub fn new(text: String, location: Location, kind: String, chunks: Vec<FixitChunk>) -> Self {
+        Self {
+            text,
+            location,
+            resolve: false,
+            kind,
+            chunks,
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Debug, Default)]
 pub struct CandidateExtraData {
     doc_string: String,
     fixits: Vec<Fixit>,
     resolve: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    match_metadata: Option<MatchMetadata>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    snippet: Option<Snippet>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    deprecated: bool,
+}
+
+/// Snippet body along with its tab stops, for clients to expand
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct Snippet {
+    pub body: String,
+    pub elements: Vec<SnippetElement>,
 }
 
 #[derive(Deserialize, Clone, Debug)]
@@ -162,10 +330,55 @@ pub struct Candidate {
     pub kind: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub extra_data: Option<CandidateExtraData>,
+    /// What the query is matched against, if not the insertion text
+    #[serde(skip)]
+    pub filter_text: Option<String>,
+}
+
+impl Candidate {
+    pub fn filter_text(&self) -> &str {
+        self.filter_text.as_deref().unwrap_or(&self.insertion_text)
+    }
+
+    pub fn set_match_metadata(&mut self, metadata: MatchMetadata) {
+        self.extra_data
+            .get_or_insert_with(CandidateExtraData::default)
+            .match_metadata = Some(metadata);
+    }
+
+    /// Mark the candidate as having more details to fetch with `resolve_completion`
+    pub fn set_resolve(&mut self, id: usize) {
+        self.extra_data
+            .get_or_insert_with(CandidateExtraData::default)
+            .resolve = Some(id);
+    }
+
+    pub fn set_snippet(&mut self, snippet: Snippet) {
+        self.extra_data
+            .get_or_insert_with(CandidateExtraData::default)
+            .snippet = Some(snippet);
+    }
+
+    /// Mark the candidate as deprecated, for clients to render struck-through
+    pub fn set_deprecated(&mut self) {
+        self.extra_data
+            .get_or_insert_with(CandidateExtraData::default)
+            .deprecated = true;
+    }
+
+    pub fn is_deprecated(&self) -> bool {
+        matches!(
+            self.extra_data,
+            Some(CandidateExtraData {
+                deprecated: true,
+                ..
+            })
+        )
+    }
 }
 
 #[allow(non_camel_case_types)]
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, Clone)]
 pub enum CompleterTarget {
     filetype_default,
     identifier,
@@ -184,29 +397,129 @@ pub struct ExceptionResponse {
     traceback: String,
 }
 
+impl ExceptionResponse {
+    pub fn new(message: String) -> Self {
+        Self {
+            exception: Exception {
+                message: message.clone(),
+            },
+            message,
+            traceback: String::new(),
+        }
+    }
+}
+
+/// Subcommand to run, along with the request it applies to
+#[derive(Deserialize, Debug)]
+pub struct CommandRequest {
+    #[serde(flatten)]
+    pub request: SimpleRequest,
+    pub command_arguments: Vec<String>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(untagged)]
+pub enum CommandResponse {
+    Locations(Vec<GoToLocation>),
+    FixIts { fixits: Vec<Fixit> },
+    Message { message: String },
+}
+
 #[derive(Serialize)]
 pub struct CompletionResponse {
     pub completions: Vec<Candidate>,
     pub completion_start_column: usize,
     pub errors: Vec<ExceptionResponse>,
+    /// Candidates were stripped of details or dropped to fit the size limit
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub truncated: bool,
+}
+
+fn serialized_len<T: Serialize>(value: &T) -> usize {
+    serde_json::to_vec(value).map(|v| v.len()).unwrap_or(0)
+}
+
+impl CompletionResponse {
+    /// Shrink the response to at most `max_size` bytes of JSON, 0 for no limit.
+    /// Details and extra data go first, from the last candidate up, then the
+    /// last candidates themselves
+    pub fn fit_to_size(&mut self, max_size: usize) {
+        if max_size == 0 || serialized_len(self) <= max_size {
+            return;
+        }
+        self.truncated = true;
+        let mut size = serialized_len(self);
+        for candidate in self.completions.iter_mut().rev() {
+            if size <= max_size {
+                return;
+            }
+            let before = serialized_len(candidate);
+            candidate.detailed_info = None;
+            candidate.extra_data = None;
+            size -= before - serialized_len(candidate);
+        }
+        while size > max_size {
+            let candidate = match self.completions.pop() {
+                Some(candidate) => candidate,
+                None => return,
+            };
+            // Along with the comma separating it from the one before
+            let separator = if self.completions.is_empty() { 0 } else { 1 };
+            size -= serialized_len(&candidate) + separator;
+        }
+    }
+}
+
+/// Whether a completer would be used for a request, and where its candidates
+/// would start
+#[derive(Serialize, Debug, PartialEq)]
+pub struct CompleterDecision {
+    pub completer: String,
+    pub should_use_now: bool,
+    /// Disabled for the filetype by `filetype_completer_blacklist`
+    pub blacklisted: bool,
+    /// 1-based byte offset, like `completion_start_column`
+    pub start_column: usize,
+    pub query: String,
+}
+
+/// Answer to a `dry_run` completion request
+#[derive(Serialize, Debug)]
+pub struct DryRunResponse {
+    pub completers: Vec<CompleterDecision>,
+}
+
+/// Candidate of the last completion request to fetch the details of,
+/// by the `resolve` id from its extra data
+#[derive(Deserialize, Debug)]
+pub struct ResolveCompletionRequest {
+    #[serde(flatten)]
+    pub request: SimpleRequest,
+    pub resolve: usize,
+}
+
+#[derive(Serialize)]
+pub struct ResolveCompletionResponse {
+    pub completion: Candidate,
+    pub errors: Vec<ExceptionResponse>,
 }
 
 #[derive(Serialize)]
 pub struct ItemData {
-    key: String,
-    value: String,
+    pub key: String,
+    pub value: String,
 }
 
 #[derive(Serialize)]
 pub struct ServerData {
-    name: String,
-    is_running: bool,
-    executable: String,
-    address: String,
-    port: usize,
-    pid: usize,
-    logfiles: Vec<String>,
-    extras: Vec<ItemData>,
+    pub name: String,
+    pub is_running: bool,
+    pub executable: String,
+    pub address: String,
+    pub port: usize,
+    pub pid: usize,
+    pub logfiles: Vec<String>,
+    pub extras: Vec<ItemData>,
 }
 
 #[derive(Serialize)]
@@ -239,7 +552,7 @@ pub struct DebugInfo {
     pub completer: DebugInfoResponse,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
 pub enum DiagnosticKind {
     WARNING,
     ERROR,
@@ -247,6 +560,19 @@ pub enum DiagnosticKind {
     HINT,
 }
 
+impl DiagnosticKind {
+    /// Lower is more important, the order diagnostics are kept in when there
+    /// are too many to display
+    pub fn priority(self) -> u8 {
+        match self {
+            DiagnosticKind::ERROR => 0,
+            DiagnosticKind::WARNING => 1,
+            DiagnosticKind::INFORMATION => 2,
+            DiagnosticKind::HINT => 3,
+        }
+    }
+}
+
 #[derive(Serialize)]
 pub struct DiagnosticData {
     ranges: Vec<Range>,
@@ -257,13 +583,31 @@ pub struct DiagnosticData {
     fixit_available: bool,
 }
 
+impl DiagnosticData {
+    /// Diagnostic at a single location, without fixits
+    pub fn new(location: Location, kind: DiagnosticKind, text: String) -> Self {
+        Self {
+            ranges: vec![],
+            location_extent: Range::new(location.clone(), location.clone()),
+            location,
+            test: text,
+            kind,
+            fixit_available: false,
+        }
+    }
+
+    pub fn kind(&self) -> DiagnosticKind {
+        self.kind
+    }
+}
+
 #[derive(Serialize)]
 pub struct DiagnosticMessage {
     filepath: String,
     diagnostics: Vec<DiagnosticData>,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
 pub enum Available {
     YES,
     NO,
@@ -325,6 +669,12 @@ mod tests {
             working_dir: None,
             extra_conf_data: None,
             start_column: None,
+            query_word: None,
+            include_match_metadata: false,
+            dry_run: false,
+            completers: None,
+            kind_filter: None,
+            completion_column_num: None,
         }
     }
 
@@ -383,4 +733,74 @@ mod tests {
         let request = get_simple_request("unim", "aa", 1, 5);
         assert_eq!(request.prefix(), "");
     }
+
+    #[test]
+    fn simple_request_completion_column() {
+        let mut request = get_simple_request("foo.bar baz", "aa", 1, 12);
+        request.completion_column_num = Some(7);
+        assert_eq!(request.start_column(), 4);
+        assert_eq!(request.query(), "ba");
+        assert_eq!(request.prefix(), "foo.");
+        // The cursor stays where it is
+        assert_eq!(request.column_num, 12);
+        assert!(request.position_in_bounds());
+
+        request.completion_column_num = Some(13);
+        assert!(!request.position_in_bounds());
+        request.completion_column_num = Some(0);
+        assert!(!request.position_in_bounds());
+    }
+
+    #[test]
+    fn simple_request_crlf() {
+        for (lf, crlf) in [
+            ("foo\n12345 a8\nbar", "foo\r\n12345 a8\r\nbar"),
+            ("foo\n12345 a8", "foo\r\n12345 a8\r"),
+        ] {
+            let lf = get_simple_request(lf, "aa", 2, 9);
+            let crlf = get_simple_request(crlf, "aa", 2, 9);
+            assert_eq!(crlf.line_value(), lf.line_value());
+            assert_eq!(crlf.start_column(), lf.start_column());
+            assert_eq!(crlf.query(), lf.query());
+            assert_eq!(crlf.query(), "a8");
+            assert_eq!(crlf.prefix(), lf.prefix());
+            assert_eq!(
+                crlf.lines().collect::<Vec<_>>(),
+                lf.lines().collect::<Vec<_>>()
+            );
+        }
+    }
+
+    #[test]
+    fn simple_request_line_past_eof() {
+        let request = get_simple_request("foo\nbar", "aa", 3, 2);
+        assert_eq!(request.line_value(), "");
+        assert_eq!(request.query(), "");
+        assert_eq!(request.prefix(), "");
+        assert!(!request.position_in_bounds());
+
+        let request = get_simple_request("foo\nbar", "aa", 0, 2);
+        assert_eq!(request.line_value(), "");
+        assert!(!request.position_in_bounds());
+    }
+
+    #[test]
+    fn simple_request_column_at_line_start() {
+        let request = get_simple_request("foo", "aa", 1, 1);
+        assert_eq!(request.start_column(), 0);
+        assert_eq!(request.query(), "");
+        assert_eq!(request.prefix(), "");
+        assert!(request.position_in_bounds());
+
+        let request = get_simple_request("foo", "aa", 1, 0);
+        assert_eq!(request.query(), "");
+        assert!(!request.position_in_bounds());
+    }
+
+    #[test]
+    fn simple_request_column_past_line_end() {
+        let request = get_simple_request("foo", "aa", 1, 10);
+        assert_eq!(request.query(), "");
+        assert!(!request.position_in_bounds());
+    }
 }